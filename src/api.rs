@@ -1,27 +1,205 @@
 use crate::{
-    model::{Block, Blockchain, Transaction, TransactionPool},
-    util::{execution::Runnable, Context},
+    miner::{MinerStats, MinerStatus},
+    model::{
+        Address, Block, BlockHash, Blockchain, ChainInfo, ConfirmedTransaction, MerkleProof, Nonce,
+        PendingTransaction, Transaction, TransactionHash, TransactionPool,
+    },
+    peer::{AddressBook, Gossip, Handshake, KnownPeer, PeerAddress, RELAY_HEADER},
+    util::{
+        execution::Runnable,
+        metrics::{SOURCE_API, SOURCE_PEER},
+        Context, Metrics,
+    },
+};
+use actix_web::{
+    dev::Service, http::header, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use anyhow::Result;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+// Amount of blocks or transactions returned in each page, by default and at most
+const DEFAULT_PAGE_LIMIT: usize = 20;
+const MAX_PAGE_LIMIT: usize = 100;
 
 struct ApiState {
     blockchain: Blockchain,
     pool: TransactionPool,
+    miner_status: MinerStatus,
+    address_book: AddressBook,
+    metrics: Metrics,
+    gossip: Gossip,
+    network_id: String,
+    admin_token: String,
+}
+
+// What the node is doing, gathered from all its parts
+#[derive(Serialize)]
+struct NodeStatus {
+    version: &'static str,
+    chain: ChainInfo,
+    mempool: MempoolStatus,
+    peers: Vec<KnownPeer>,
+    miner: MinerStats,
+}
+
+#[derive(Serialize)]
+struct MempoolStatus {
+    transactions: usize,
+    bytes: usize,
+}
+
+// Proof that a transaction is included in a block, which anyone can verify only with the block header
+#[derive(Serialize, Deserialize)]
+struct TransactionProof {
+    block_hash: BlockHash,
+    merkle_root: BlockHash,
+    transaction_hash: TransactionHash,
+    proof: MerkleProof,
+}
+
+#[derive(Serialize)]
+struct ProofVerification {
+    valid: bool,
+}
+
+#[derive(Serialize)]
+struct TransactionId {
+    hash: TransactionHash,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum TransactionStatus {
+    // waiting in the pool to be included in a block
+    Pending,
+    // included in a block of the main chain
+    Confirmed,
+}
+
+// A transaction along with where it is, the block fields are only present for confirmed transactions
+#[derive(Serialize)]
+struct TransactionDetails {
+    hash: TransactionHash,
+    status: TransactionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_index: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_hash: Option<BlockHash>,
+    confirmations: u64,
+    transaction: Transaction,
+}
+
+impl TransactionDetails {
+    fn confirmed(confirmed: ConfirmedTransaction) -> TransactionDetails {
+        TransactionDetails {
+            hash: confirmed.transaction.hash(),
+            status: TransactionStatus::Confirmed,
+            block_index: Some(confirmed.block_index),
+            block_hash: Some(confirmed.block_hash),
+            confirmations: confirmed.confirmations,
+            transaction: confirmed.transaction,
+        }
+    }
+
+    fn pending(transaction: Transaction) -> TransactionDetails {
+        TransactionDetails {
+            hash: transaction.hash(),
+            status: TransactionStatus::Pending,
+            block_index: None,
+            block_hash: None,
+            confirmations: 0,
+            transaction,
+        }
+    }
+}
+
+// A transaction waiting in the pool, along with when it arrived
+#[derive(Serialize)]
+struct PendingTransactionDetails {
+    hash: TransactionHash,
+    fee: u64,
+    size: usize,
+    received_at: i64,
+    transaction: Transaction,
+}
+
+impl From<PendingTransaction> for PendingTransactionDetails {
+    fn from(pending: PendingTransaction) -> PendingTransactionDetails {
+        PendingTransactionDetails {
+            hash: pending.hash,
+            fee: pending.transaction.fee,
+            size: pending.size,
+            received_at: pending.received_at,
+            transaction: pending.transaction,
+        }
+    }
+}
+
+// State of an account in the main chain, and the balance it will have once its pending transactions are confirmed
+#[derive(Serialize)]
+struct AccountDetails {
+    address: Address,
+    balance: u64,
+    nonce: Nonce,
+    pending_balance: u64,
+}
+
+// Range of blocks of the main chain, the whole chain is returned if none of the fields are present
+#[derive(Deserialize)]
+struct BlockRange {
+    from: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Pagination {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_page_limit")]
+    limit: usize,
+}
+
+fn default_page_limit() -> usize {
+    DEFAULT_PAGE_LIMIT
+}
+
+#[derive(Serialize)]
+struct AddressTransactionsPage {
+    address: Address,
+    total: usize,
+    offset: usize,
+    limit: usize,
+    transactions: Vec<TransactionDetails>,
 }
 
 pub struct Api {
     port: u16,
+    network_id: String,
+    admin_token: String,
     blockchain: Blockchain,
     pool: TransactionPool,
+    miner_status: MinerStatus,
+    address_book: AddressBook,
+    metrics: Metrics,
+    gossip: Gossip,
 }
 
 impl Runnable for Api {
     fn run(&self) -> Result<()> {
-        let api_blockchain = self.blockchain.clone();
-        let api_pool = self.pool.clone();
+        let api_state = ApiState {
+            blockchain: self.blockchain.clone(),
+            pool: self.pool.clone(),
+            miner_status: self.miner_status.clone(),
+            address_book: self.address_book.clone(),
+            metrics: self.metrics.clone(),
+            gossip: self.gossip.clone(),
+            network_id: self.network_id.clone(),
+            admin_token: self.admin_token.clone(),
+        };
 
-        start_server(self.port, api_blockchain, api_pool)
+        start_server(self.port, api_state)
     }
 }
 
@@ -29,25 +207,82 @@ impl Api {
     pub fn new(context: &Context) -> Api {
         Api {
             port: context.config.port,
+            network_id: context.config.network_id.clone(),
+            admin_token: context.config.admin_token.clone(),
             blockchain: context.blockchain.clone(),
             pool: context.pool.clone(),
+            miner_status: context.miner_status.clone(),
+            address_book: context.address_book.clone(),
+            metrics: context.metrics.clone(),
+            gossip: context.gossip.clone(),
         }
     }
 }
 
 #[actix_web::main]
-async fn start_server(port: u16, blockchain: Blockchain, pool: TransactionPool) -> Result<()> {
+async fn start_server(port: u16, api_state: ApiState) -> Result<()> {
     let url = format!("localhost:{}", port);
-    // These variables are really "Arc" pointers to a shared memory value
+    // The values in the state are really "Arc" pointers to a shared memory value
     // So when we clone them, we are only cloning the pointers and not the actual data
-    let api_state = web::Data::new(ApiState { blockchain, pool });
+    let api_state = web::Data::new(api_state);
 
     HttpServer::new(move || {
+        let metrics = api_state.metrics.clone();
+
         App::new()
             .app_data(api_state.clone())
+            // measure how long it takes to respond each request, grouped by route to not have a metric per URL
+            .wrap_fn(move |request, service| {
+                let start = Instant::now();
+                let method = request.method().to_string();
+                let route = request
+                    .match_pattern()
+                    .unwrap_or_else(|| "unknown".to_string());
+                let metrics = metrics.clone();
+
+                service.call(request).map(move |result| {
+                    if let Ok(response) = &result {
+                        let status = response.status().as_u16().to_string();
+                        metrics
+                            .api_request_duration
+                            .with_label_values(&[&method, &route, &status])
+                            .observe(start.elapsed().as_secs_f64());
+                    }
+                    result
+                })
+            })
+            .route("/status", web::get().to(get_status))
+            .route("/metrics", web::get().to(get_metrics))
+            .route("/handshake", web::post().to(handshake))
+            .route("/peers", web::get().to(get_peers))
+            .route("/peers", web::post().to(add_peer))
             .route("/blocks", web::get().to(get_blocks))
             .route("/blocks", web::post().to(add_block))
+            // the fixed paths go first, so they are not taken as block indexes
+            .route("/blocks/latest", web::get().to(get_last_block))
+            .route("/blocks/hash/{hash}", web::get().to(get_block_by_hash))
+            .route("/blocks/{index}", web::get().to(get_block_by_index))
+            .route(
+                "/blocks/{hash}/transactions/{index}/proof",
+                web::get().to(get_transaction_proof),
+            )
+            .route("/proofs/verify", web::post().to(verify_transaction_proof))
             .route("/transactions", web::post().to(add_transaction))
+            // the fixed path goes first, so it's not taken as a transaction hash
+            .route(
+                "/transactions/pending",
+                web::get().to(get_pending_transactions),
+            )
+            .route("/transactions/{hash}", web::get().to(get_transaction))
+            .route("/accounts/{address}", web::get().to(get_account))
+            .route(
+                "/accounts/{address}/transactions",
+                web::get().to(get_address_transactions),
+            )
+            .route(
+                "/admin/transactions/{hash}",
+                web::delete().to(remove_pending_transaction),
+            )
     })
     .bind(url)
     .unwrap()
@@ -57,43 +292,315 @@ async fn start_server(port: u16, blockchain: Blockchain, pool: TransactionPool)
     Ok(())
 }
 
-// Returns a list of all the blocks in the blockchain
-async fn get_blocks(state: web::Data<ApiState>) -> impl Responder {
+// Returns what the node is doing: the state of the main chain, the pool, the peers and the miner
+async fn get_status(state: web::Data<ApiState>) -> impl Responder {
+    HttpResponse::Ok().json(NodeStatus {
+        version: env!("CARGO_PKG_VERSION"),
+        chain: state.blockchain.get_chain_info(),
+        mempool: MempoolStatus {
+            transactions: state.pool.count_transactions(),
+            bytes: state.pool.count_bytes(),
+        },
+        peers: state.address_book.get_peers(),
+        miner: state.miner_status.get_stats(),
+    })
+}
+
+// Returns the metrics of the node in the Prometheus text format
+// The gauges are read right before, so they are up to date on each scrape
+async fn get_metrics(state: web::Data<ApiState>) -> impl Responder {
+    let metrics = &state.metrics;
+    let chain_info = state.blockchain.get_chain_info();
+    metrics.chain_height.set(chain_info.height as i64);
+    metrics
+        .mempool_transactions
+        .set(state.pool.count_transactions() as i64);
+    metrics.mempool_bytes.set(state.pool.count_bytes() as i64);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.encode())
+}
+
+// Answers the handshake of a node with the one of this node, if both follow the same rules
+async fn handshake(
+    state: web::Data<ApiState>,
+    handshake_json: web::Json<Handshake>,
+) -> HttpResponse {
+    let peer_handshake = handshake_json.into_inner();
+    let handshake = Handshake::new(&state.network_id, &state.blockchain);
+
+    match handshake.check_compatibility(&peer_handshake) {
+        Ok(_) => HttpResponse::Ok().json(handshake),
+        Err(error) => HttpResponse::Conflict().body(error.to_string()),
+    }
+}
+
+// Returns the peers known by the node, so other nodes can learn about them
+async fn get_peers(state: web::Data<ApiState>) -> impl Responder {
+    HttpResponse::Ok().json(state.address_book.get_peers())
+}
+
+// Adds a node that announces itself as a peer, so blocks and transactions are also relayed to it
+async fn add_peer(state: web::Data<ApiState>, peer_json: web::Json<PeerAddress>) -> HttpResponse {
+    let peer = peer_json.into_inner();
+
+    if state.address_book.add_announced_peer(&peer.address) {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::BadRequest().body("Invalid peer address")
+    }
+}
+
+// Returns a list of the blocks in the main chain, starting by the oldest
+// Only the indicated range is returned if "from" or "limit" are present, otherwise it returns all the blocks
+async fn get_blocks(state: web::Data<ApiState>, query: web::Query<BlockRange>) -> impl Responder {
     let blockchain = &state.blockchain;
-    let blocks = blockchain.get_all_blocks();
+    let blocks = match query.into_inner() {
+        BlockRange {
+            from: None,
+            limit: None,
+        } => blockchain.get_all_blocks(),
+        BlockRange { from, limit } => {
+            let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+            blockchain.get_blocks(from.unwrap_or_default(), limit)
+        }
+    };
 
     HttpResponse::Ok().json(&blocks)
 }
 
-// Adds a new block to the blockchain
-async fn add_block(state: web::Data<ApiState>, block_json: web::Json<Block>) -> HttpResponse {
+// Returns the last block of the main chain
+async fn get_last_block(state: web::Data<ApiState>) -> impl Responder {
+    HttpResponse::Ok().json(state.blockchain.get_last_block())
+}
+
+// Returns the block of the main chain with the indicated index
+async fn get_block_by_index(state: web::Data<ApiState>, path: web::Path<u64>) -> HttpResponse {
+    match state.blockchain.get_block_by_index(path.into_inner()) {
+        Some(block) => HttpResponse::Ok().json(block),
+        None => HttpResponse::NotFound().body("Block not found"),
+    }
+}
+
+// Returns the block with the indicated hash, in the main chain or in any other branch
+async fn get_block_by_hash(state: web::Data<ApiState>, path: web::Path<BlockHash>) -> HttpResponse {
+    match state.blockchain.get_block(&path.into_inner()) {
+        Some(block) => HttpResponse::Ok().json(block),
+        None => HttpResponse::NotFound().body("Block not found"),
+    }
+}
+
+// Adds a new block to the blockchain, and relays it to the peers
+async fn add_block(
+    state: web::Data<ApiState>,
+    request: HttpRequest,
+    block_json: web::Json<Block>,
+) -> HttpResponse {
     let mut block = block_json.into_inner();
 
     // The hash of the block is mandatory and the blockchain checks if it's correct
     // That's a bit unconvenient for manual use of the API
-    // So we ignore the comming hash and recalculate it again before adding to the blockchain
+    // So we ignore the comming hash (and merkle root) and recalculate it again before adding to the blockchain
+    block.merkle_root = block.calculate_merkle_root();
     block.hash = block.calculate_hash();
 
     let blockchain = &state.blockchain;
     let result = blockchain.add_block(block.clone());
 
     match result {
-        Ok(_) => {
+        Ok(status) => {
             info!("Received new block {}", block.index);
+            // orphans are counted and relayed once they get connected, if ever
+            if status.is_connected() {
+                // the blocks relayed by peers come through the API too, but they are counted as coming from peers
+                let source = if request.headers().contains_key(RELAY_HEADER) {
+                    SOURCE_PEER
+                } else {
+                    SOURCE_API
+                };
+                state
+                    .metrics
+                    .blocks_added
+                    .with_label_values(&[source])
+                    .inc();
+                state.gossip.announce_block(&block);
+            }
             HttpResponse::Ok().finish()
         }
         Err(error) => HttpResponse::BadRequest().body(error.to_string()),
     }
 }
 
-// Adds a new transaction to the pool, to be included on the next block
+// Returns the proof that the transaction in the indicated position of a block is included in its merkle root
+async fn get_transaction_proof(
+    state: web::Data<ApiState>,
+    path: web::Path<(BlockHash, usize)>,
+) -> HttpResponse {
+    let (block_hash, index) = path.into_inner();
+    let block = match state.blockchain.get_block(&block_hash) {
+        Some(block) => block,
+        None => return HttpResponse::NotFound().body("Block not found"),
+    };
+
+    match block.create_merkle_proof(index) {
+        Some(proof) => HttpResponse::Ok().json(TransactionProof {
+            block_hash,
+            merkle_root: block.merkle_root,
+            transaction_hash: block.transactions[index].hash(),
+            proof,
+        }),
+        None => HttpResponse::NotFound().body("Transaction not found"),
+    }
+}
+
+// Checks that a proof is valid for a block of the blockchain
+async fn verify_transaction_proof(
+    state: web::Data<ApiState>,
+    proof_json: web::Json<TransactionProof>,
+) -> impl Responder {
+    let proof = proof_json.into_inner();
+
+    // the merkle root of the proof must be the one of the block, not any other value
+    // and the proof must be for a tree of as many leaves as transactions in the block
+    let valid = match state.blockchain.get_block(&proof.block_hash) {
+        Some(block)
+            if block.merkle_root == proof.merkle_root
+                && block.transactions.len() == proof.proof.leaf_count =>
+        {
+            proof
+                .proof
+                .verify(proof.transaction_hash, proof.merkle_root)
+        }
+        _ => false,
+    };
+
+    HttpResponse::Ok().json(ProofVerification { valid })
+}
+
+// Adds a new transaction to the pool, to be included on the next block, and relays it to the peers
+// The transaction is rejected if it's not valid according to the current account balances
 async fn add_transaction(
     state: web::Data<ApiState>,
     transaction_json: web::Json<Transaction>,
 ) -> impl Responder {
     let transaction = transaction_json.into_inner();
+    // only the account of the sender is needed to validate the transaction
+    let account_balances = state.blockchain.get_accounts([&transaction.sender]);
     let pool = &state.pool;
-    pool.add_transaction(transaction);
+    let result = pool.add_transaction(transaction.clone(), &account_balances);
 
-    HttpResponse::Ok()
+    match result {
+        Ok(hash) => {
+            info!("Received new transaction");
+            state.gossip.announce_transaction(&transaction);
+            HttpResponse::Ok().json(TransactionId { hash })
+        }
+        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+// Returns a transaction by its hash, either confirmed in the blockchain or pending in the pool
+async fn get_transaction(
+    state: web::Data<ApiState>,
+    path: web::Path<TransactionHash>,
+) -> HttpResponse {
+    let hash = path.into_inner();
+
+    if let Some(confirmed) = state.blockchain.get_transaction(&hash) {
+        return HttpResponse::Ok().json(TransactionDetails::confirmed(confirmed));
+    }
+
+    match state.pool.get_transaction(&hash) {
+        Some(transaction) => HttpResponse::Ok().json(TransactionDetails::pending(transaction)),
+        None => HttpResponse::NotFound().body("Transaction not found"),
+    }
+}
+
+// Returns all the transactions waiting in the pool, from the oldest to the newest
+async fn get_pending_transactions(state: web::Data<ApiState>) -> impl Responder {
+    let pending_transactions: Vec<PendingTransactionDetails> = state
+        .pool
+        .get_pending_transactions()
+        .into_iter()
+        .map(PendingTransactionDetails::from)
+        .collect();
+
+    HttpResponse::Ok().json(pending_transactions)
+}
+
+// Removes a transaction from the pool, so it's not included in any block (unless it's sent again)
+// Only allowed to admins, as anyone could remove the transactions of others otherwise
+async fn remove_pending_transaction(
+    state: web::Data<ApiState>,
+    request: HttpRequest,
+    path: web::Path<TransactionHash>,
+) -> HttpResponse {
+    if let Some(rejection) = reject_non_admin(&state, &request) {
+        return rejection;
+    }
+
+    match state.pool.remove_transaction(&path.into_inner()) {
+        Some(pending) => HttpResponse::Ok().json(PendingTransactionDetails::from(pending)),
+        None => HttpResponse::NotFound().body("Transaction not found"),
+    }
+}
+
+// Checks that the request has the admin token, returning the error response if it does not
+// The admin endpoints are disabled if no token is configured
+fn reject_non_admin(state: &ApiState, request: &HttpRequest) -> Option<HttpResponse> {
+    if state.admin_token.is_empty() {
+        return Some(HttpResponse::Forbidden().body("Admin API is disabled"));
+    }
+
+    let expected = format!("Bearer {}", state.admin_token);
+    let authorization = request.headers().get(header::AUTHORIZATION);
+    match authorization.and_then(|value| value.to_str().ok()) {
+        Some(value) if value == expected => None,
+        _ => Some(HttpResponse::Unauthorized().body("Invalid admin token")),
+    }
+}
+
+// Returns the balance and nonce of an address, which are zero for addresses that never received funds
+async fn get_account(state: web::Data<ApiState>, path: web::Path<Address>) -> impl Responder {
+    let address = path.into_inner();
+    let account = state.blockchain.get_account(&address);
+    let pending_balance = state
+        .pool
+        .get_pending_balance(&address, &account, |sender| {
+            state.blockchain.get_account(sender).nonce
+        });
+
+    HttpResponse::Ok().json(AccountDetails {
+        address,
+        balance: account.balance,
+        nonce: account.nonce,
+        pending_balance,
+    })
+}
+
+// Returns a page of the confirmed transactions sent or received by an address, from the newest to the oldest
+async fn get_address_transactions(
+    state: web::Data<ApiState>,
+    path: web::Path<Address>,
+    query: web::Query<Pagination>,
+) -> impl Responder {
+    let address = path.into_inner();
+    let offset = query.offset;
+    let limit = query.limit.min(MAX_PAGE_LIMIT);
+    let page = state
+        .blockchain
+        .get_address_transactions(&address, offset, limit);
+
+    HttpResponse::Ok().json(AddressTransactionsPage {
+        address,
+        total: page.total,
+        offset,
+        limit,
+        transactions: page
+            .transactions
+            .into_iter()
+            .map(TransactionDetails::confirmed)
+            .collect(),
+    })
 }
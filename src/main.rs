@@ -8,10 +8,14 @@ mod peer;
 mod util;
 
 use api::Api;
-use miner::Miner;
+use miner::{Miner, MinerStatus};
 use model::{Blockchain, TransactionPool};
-use peer::Peer;
-use util::{execution, initialize_logger, termination, Config, Context};
+use peer::{AddressBook, Gossip, Peer, Relay};
+use std::path::Path;
+use util::{execution, initialize_logger, termination, Config, Context, Metrics};
+
+// File in the data directory where the known peers are stored
+const PEERS_FILE_NAME: &str = "peers.json";
 
 fn main() {
     initialize_logger();
@@ -22,19 +26,57 @@ fn main() {
 
     // initialize shared data values
     let config = Config::read();
-    let difficulty = config.difficulty;
+    let metrics = Metrics::new();
+    let pool = TransactionPool::new(config.max_pool_transactions, config.max_pool_bytes);
+    // the transactions of the blocks removed from the main chain go back to the pool
+    let blockchain = Blockchain::new(config.difficulty)
+        .with_difficulty_adjustment(
+            config.difficulty_adjustment_interval,
+            config.target_block_time_ms,
+        )
+        .with_orphan_limits(config.max_orphan_blocks, config.orphan_expiry_ms)
+        .with_max_fork_depth(config.max_fork_depth)
+        .with_metrics(metrics.clone())
+        .with_block_store(Path::new(&config.data_dir), config.snapshot_interval)
+        .unwrap_or_else(|error| {
+            error!(
+                "could not load the blocks from {}: {}",
+                config.data_dir, error
+            );
+            std::process::exit(1);
+        })
+        .with_transaction_pool(pool.clone());
+    // the configured peers are added to the ones known before the last restart
+    let address_book = AddressBook::new(&config.public_address, config.max_peers)
+        .with_metrics(metrics.clone())
+        .with_file(&Path::new(&config.data_dir).join(PEERS_FILE_NAME))
+        .unwrap_or_else(|error| {
+            error!(
+                "could not load the peers from {}: {}",
+                config.data_dir, error
+            );
+            std::process::exit(1);
+        });
+    for address in config.peers.iter() {
+        address_book.add_seed_peer(address);
+    }
     let context = Context {
         config,
-        blockchain: Blockchain::new(difficulty),
-        pool: TransactionPool::new(),
+        blockchain,
+        pool,
+        miner_status: MinerStatus::default(),
+        address_book,
+        metrics,
+        gossip: Gossip::default(),
     };
 
     // initialize the processes
     let miner = Miner::new(&context);
     let api = Api::new(&context);
     let peer = Peer::new(&context);
+    let relay = Relay::new(&context);
 
-    // miner, api and peer system run in separate threads
+    // miner, api, peer system and gossip relay run in separate threads
     // because mining is very cpu intensive
-    execution::run_in_parallel(vec![&miner, &api, &peer]);
+    execution::run_in_parallel(vec![&miner, &api, &peer, &relay]);
 }
@@ -1,30 +1,73 @@
 use crate::{
     model::{
-        Address, Block, BlockHash, Blockchain, Transaction, TransactionPool, TransactionVec,
-        BLOCK_SUBSIDY,
+        Address, Block, BlockHash, BlockStatus, Blockchain, PublicKey, Signature, Transaction,
+        TransactionPool, TransactionVec, EXTRA_NONCE_BITS,
     },
+    peer::Gossip,
     util::{
         execution::{sleep_millis, Runnable},
-        Context,
+        metrics::SOURCE_MINED,
+        Context, Metrics,
     },
 };
 use anyhow::Result;
-use thiserror::Error;
+use crossbeam_utils::thread;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Activity of the miner, to report the state of the node
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MinerStats {
+    pub running: bool,
+    pub blocks_mined: u64,
+    // hashes per second in the last search of a valid block
+    pub hash_rate: f64,
+}
+
+// Shares the activity of the miner with the rest of the node
+// Multiple threads can read/write concurrently to the stats
+#[derive(Debug, Clone, Default)]
+pub struct MinerStatus {
+    stats: Arc<Mutex<MinerStats>>,
+}
+
+impl MinerStatus {
+    // Returns a copy of the current stats
+    pub fn get_stats(&self) -> MinerStats {
+        self.stats.lock().unwrap().clone()
+    }
 
-#[derive(Error, Debug)]
-pub enum MinerError {
-    #[error("No valid block was mined at index `{0}`")]
-    BlockNotMined(u64),
+    fn update(&self, update: impl FnOnce(&mut MinerStats)) {
+        update(&mut self.stats.lock().unwrap());
+    }
 }
 
 pub struct Miner {
     miner_address: Address,
     max_blocks: u64,
     max_nonce: u64,
+    miner_threads: usize,
     tx_waiting_ms: u64,
+    max_block_transactions: usize,
+    max_block_bytes: usize,
     blockchain: Blockchain,
     pool: TransactionPool,
-    target: BlockHash,
+    status: MinerStatus,
+    metrics: Metrics,
+    gossip: Gossip,
+}
+
+// Shared between the mining threads while they search for a valid block
+#[derive(Default)]
+struct SearchProgress {
+    found: AtomicBool,
+    hash_count: AtomicU64,
 }
 
 impl Runnable for Miner {
@@ -35,21 +78,26 @@ impl Runnable for Miner {
 
 impl Miner {
     pub fn new(context: &Context) -> Miner {
-        let target = Self::create_target(context.config.difficulty);
-
         Miner {
             miner_address: context.config.miner_address.clone(),
             max_blocks: context.config.max_blocks,
-            max_nonce: context.config.max_nonce,
+            // at least one nonce is needed, otherwise the extra nonce would be changed forever without trying any block
+            max_nonce: context.config.max_nonce.max(1),
+            // at least one thread is needed to mine blocks
+            miner_threads: context.config.miner_threads.max(1),
             tx_waiting_ms: context.config.tx_waiting_ms,
+            max_block_transactions: context.config.max_block_transactions,
+            max_block_bytes: context.config.max_block_bytes,
             blockchain: context.blockchain.clone(),
             pool: context.pool.clone(),
-            target,
+            status: context.miner_status.clone(),
+            metrics: context.metrics.clone(),
+            gossip: context.gossip.clone(),
         }
     }
 
     // Try to constanly calculate and append new valid blocks to the blockchain,
-    // including all pending transactions in the transaction pool each time
+    // including the pending transactions with the highest fees that fit in a block each time
     pub fn start(&self) -> Result<()> {
         info!(
             "start minining with difficulty {}",
@@ -57,15 +105,26 @@ impl Miner {
         );
 
         // In each loop it tries to find the next valid block and append it to the blockchain
+        self.status.update(|stats| stats.running = true);
         let mut block_counter = 0;
         loop {
             if self.must_stop_mining(block_counter) {
                 info!("block limit reached, stopping mining");
+                self.status.update(|stats| stats.running = false);
                 return Ok(());
             }
 
-            // Empty all transactions from the pool, they will be included in the new block
-            let transactions = self.pool.pop();
+            // the generation is read before the balances and the last block,
+            // so any block added after this point cancels the mining of transactions that may not be valid anymore
+            let generation = self.blockchain.get_tip_generation();
+            let account_balances = self.blockchain.get_account_balances();
+
+            // Take the best ready transactions from the pool, they will be included in the new block
+            let transactions = self.pool.pop(
+                &account_balances,
+                self.max_block_transactions,
+                self.max_block_bytes,
+            );
 
             // Do not try to mine a block if there are no transactions in the pool
             if transactions.is_empty() {
@@ -75,17 +134,56 @@ impl Miner {
 
             // try to find a valid next block of the blockchain
             let last_block = self.blockchain.get_last_block();
-            let mining_result = self.mine_block(&last_block, &transactions.clone());
+            let difficulty = self.blockchain.get_next_difficulty();
+            let mining_result = self.mine_block(&last_block, difficulty, &transactions, generation);
             match mining_result {
                 Some(block) => {
-                    info!("valid block found for index {}", block.index);
-                    self.blockchain.add_block(block.clone())?;
-                    block_counter += 1;
+                    info!(
+                        "valid block found for index {} ({} bytes)",
+                        block.index,
+                        block.encode().len()
+                    );
+                    let result = self.blockchain.add_block(block.clone());
+                    if matches!(&result, Ok(status) if status.is_connected()) {
+                        self.metrics
+                            .blocks_added
+                            .with_label_values(&[SOURCE_MINED])
+                            .inc();
+                        self.gossip.announce_block(&block);
+                    }
+                    match result {
+                        Ok(BlockStatus::MainChain) => {
+                            block_counter += 1;
+                            self.status.update(|stats| stats.blocks_mined += 1);
+                        }
+                        // Another block may have been added while mining (e.g. from a peer), so we lost the race
+                        // The transactions not included in the main chain go back to the pool to try again
+                        // (mined blocks always follow a known block, so they cannot be orphans)
+                        Ok(BlockStatus::SideChain | BlockStatus::Orphan) => {
+                            warn!("mined block {} is not in the main chain", block.index);
+                            let account_balances = self.blockchain.get_account_balances();
+                            self.pool.requeue(transactions, &account_balances);
+                        }
+                        Err(error) => {
+                            warn!("mined block {} was rejected: {}", block.index, error);
+                            let account_balances = self.blockchain.get_account_balances();
+                            self.pool.requeue(transactions, &account_balances);
+                        }
+                    }
+                }
+                // Another block was added while mining, so we start again on top of the new last block
+                None if self.is_tip_outdated(generation) => {
+                    info!("new block added while mining, restarting on the new last block");
+                    let account_balances = self.blockchain.get_account_balances();
+                    self.pool.requeue(transactions, &account_balances);
                 }
+                // Otherwise the fees cannot be paid by a coinbase, so the transactions never fit in a valid block
                 None => {
                     let index = last_block.index + 1;
-                    error!("no valid block was foun for index {}", index);
-                    return Err(MinerError::BlockNotMined(index).into());
+                    error!(
+                        "no valid block can be mined for index {}, discarding its transactions",
+                        index
+                    );
                 }
             }
         }
@@ -102,49 +200,185 @@ impl Miner {
         self.max_blocks > 0 && block_counter >= self.max_blocks
     }
 
+    // check if a new block was added to the blockchain since the indicated generation
+    fn is_tip_outdated(&self, generation: u64) -> bool {
+        self.blockchain.get_tip_generation() != generation
+    }
+
     // Tries to find the next valid block of the blockchain
     // It will create blocks with different "nonce" values until one has a hash that matches the difficulty
+    // When all the nonces are exhausted, the "extra nonce" of the coinbase is changed and the search starts again,
+    // as that changes the data of the block and provides a brand new space of nonces
+    // The search only ends when a valid block is found or the last block changes from the indicated generation
     // Returns either a valid block (that satisfies the difficulty) or "None" if no block was found
-    fn mine_block(&self, last_block: &Block, transactions: &TransactionVec) -> Option<Block> {
+    fn mine_block(
+        &self,
+        last_block: &Block,
+        difficulty: u32,
+        transactions: &TransactionVec,
+        generation: u64,
+    ) -> Option<Block> {
         // Add the coinbase transaction as the first transaction in the block
-        let coinbase = self.create_coinbase_transaction();
+        // The fees of the transactions must add up, otherwise no valid block can be created with them
+        let coinbase = self
+            .create_coinbase_transaction(last_block.index + 1, transactions)
+            .ok()?;
+        let first_coinbase_nonce = coinbase.nonce;
         let mut block_transactions = transactions.clone();
         block_transactions.insert(0, coinbase);
 
-        for nonce in 0..self.max_nonce {
-            let next_block = self.create_next_block(last_block, block_transactions.clone(), nonce);
+        // the nonce of coinbase transactions is not used for anything else, so we use it as extra nonce
+        for extra_nonce in 0..1 << EXTRA_NONCE_BITS {
+            if self.is_tip_outdated(generation) {
+                return None;
+            }
+
+            block_transactions[0].nonce = first_coinbase_nonce + extra_nonce;
+            let result = self.search_block(last_block, difficulty, &block_transactions, generation);
+            if result.is_some() {
+                return result;
+            }
+
+            debug!(
+                "nonces exhausted, changing the extra nonce to {}",
+                extra_nonce + 1
+            );
+        }
+
+        None
+    }
+
+    // Searches a valid block with the indicated transactions, trying all the nonces up to the configured limit
+    // The nonces are split between multiple threads, and all of them stop as soon as one finds a valid block
+    fn search_block(
+        &self,
+        last_block: &Block,
+        difficulty: u32,
+        block_transactions: &TransactionVec,
+        generation: u64,
+    ) -> Option<Block> {
+        let progress = SearchProgress::default();
+        let start = Instant::now();
+
+        // each thread searches the nonces that start from its own number, in steps of the number of threads
+        let results = thread::scope(|s| {
+            let handles: Vec<_> = (0..self.miner_threads as u64)
+                .map(|first_nonce| {
+                    let progress = &progress;
+                    s.spawn(move |_| {
+                        self.search_nonces(
+                            last_block,
+                            difficulty,
+                            block_transactions,
+                            generation,
+                            first_nonce,
+                            progress,
+                        )
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+
+        let hash_count = progress.hash_count.into_inner();
+        self.metrics.hash_attempts.inc_by(hash_count);
+        self.report_hash_rate(hash_count, start.elapsed());
+
+        results.into_iter().flatten().next()
+    }
+
+    // Searches for a valid block in the nonces assigned to one of the mining threads
+    // Stops when the nonces are exhausted, any thread finds a valid block or the last block changes
+    fn search_nonces(
+        &self,
+        last_block: &Block,
+        difficulty: u32,
+        transactions: &TransactionVec,
+        generation: u64,
+        first_nonce: u64,
+        progress: &SearchProgress,
+    ) -> Option<Block> {
+        let target = Self::create_target(difficulty);
+        let mut result = None;
+        let mut hashes = 0;
+
+        // only the header is hashed, so the block is created once and then we just change the nonce
+        let mut next_block =
+            self.create_next_block(last_block, difficulty, transactions.clone(), first_nonce);
+
+        for nonce in (first_nonce..self.max_nonce).step_by(self.miner_threads) {
+            // there is no point in keep mining on top of an outdated block, or if another thread already succeeded
+            if progress.found.load(Ordering::Relaxed) || self.is_tip_outdated(generation) {
+                break;
+            }
+
+            next_block.nonce = nonce;
+            next_block.hash = next_block.calculate_hash();
+            hashes += 1;
 
             // A valid block must have a hash with enough starting zeroes
             // To check that, we simply compare against a binary data mask
-            if next_block.hash < self.target {
-                return Some(next_block);
+            if next_block.hash < target {
+                progress.found.store(true, Ordering::Relaxed);
+                result = Some(next_block);
+                break;
             }
         }
 
-        None
+        progress.hash_count.fetch_add(hashes, Ordering::Relaxed);
+        result
+    }
+
+    // Reports the combined hash rate of all the mining threads
+    fn report_hash_rate(&self, hash_count: u64, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            let hash_rate = hash_count as f64 / seconds;
+            info!(
+                "calculated {} hashes at {:.0} hashes/s",
+                hash_count, hash_rate
+            );
+            self.status.update(|stats| stats.hash_rate = hash_rate);
+        }
     }
 
     // Creates a valid next block for a blockchain
-    // Takes into account the index and the hash of the previous block
+    // Takes into account the index and the hash of the previous block, as well as the required difficulty
     fn create_next_block(
         &self,
         last_block: &Block,
+        difficulty: u32,
         transactions: TransactionVec,
         nonce: u64,
     ) -> Block {
-        let index = (last_block.index + 1) as u64;
+        let index = last_block.index + 1;
         let previous_hash = last_block.hash;
 
         // hash of the new block is automatically calculated on creation
-        Block::new(index, nonce, previous_hash, transactions)
+        Block::new(index, difficulty, nonce, previous_hash, transactions)
     }
 
-    fn create_coinbase_transaction(&self) -> Transaction {
-        Transaction {
+    // Coinbase transactions create new coins, so they are not signed by any sender
+    // The miner collects the block subsidy plus the fees of the transactions included in the block
+    fn create_coinbase_transaction(
+        &self,
+        index: u64,
+        transactions: &[Transaction],
+    ) -> Result<Transaction> {
+        Ok(Transaction {
             sender: Address::default(),
             recipient: self.miner_address.clone(),
-            amount: BLOCK_SUBSIDY,
-        }
+            amount: Blockchain::calculate_coinbase_amount(transactions)?,
+            fee: 0,
+            nonce: index << EXTRA_NONCE_BITS,
+            public_key: PublicKey::default(),
+            signature: Signature::default(),
+        })
     }
 }
 
@@ -152,8 +386,8 @@ impl Miner {
 mod tests {
     use super::*;
     use crate::model::{
-        test_util::{alice, bob},
-        Transaction,
+        test_util::{alice, alice_keypair, bob},
+        BLOCK_SUBSIDY,
     };
 
     // We use SHA 256 hashes
@@ -164,11 +398,12 @@ mod tests {
         let miner = create_default_miner();
         let block = create_empty_block();
 
-        let next_block = miner.create_next_block(&block, Vec::new(), 0);
+        let next_block = miner.create_next_block(&block, 1, Vec::new(), 0);
 
-        // the next block must follow the previous one
+        // the next block must follow the previous one, with the indicated difficulty
         assert_eq!(next_block.index, block.index + 1);
         assert_eq!(next_block.previous_hash, block.hash);
+        assert_eq!(next_block.difficulty, 1);
     }
 
     #[test]
@@ -200,7 +435,8 @@ mod tests {
         // check that the block is mined
         let miner = create_miner(difficulty, max_nonce);
         let last_block = create_empty_block();
-        let result = miner.mine_block(&last_block, &Vec::new());
+        let generation = miner.blockchain.get_tip_generation();
+        let result = miner.mine_block(&last_block, difficulty, &Vec::new(), generation);
         assert!(result.is_some());
 
         // check that the block is valid
@@ -209,19 +445,110 @@ mod tests {
     }
 
     #[test]
-    fn test_mine_block_not_found() {
-        // let's use a high difficulty target to never find a block
-        let difficulty = MAX_DIFFICULTY;
+    fn test_mine_block_found_with_multiple_threads() {
+        let difficulty = 8;
+        let max_nonce = 1_000_000;
 
-        // with a max_nonce so low, we will never find a block
-        // and also the test will end fast
-        let max_nonce = 10;
+        // the valid block can be found by any of the threads
+        let mut miner = create_miner(difficulty, max_nonce);
+        miner.miner_threads = 4;
+        let last_block = create_empty_block();
+        let generation = miner.blockchain.get_tip_generation();
+        let mined_block = miner.mine_block(&last_block, difficulty, &Vec::new(), generation);
 
-        // check that the block is not mined
-        let miner = create_miner(difficulty, max_nonce);
+        assert_mined_block_is_valid(&mined_block.unwrap(), &last_block, difficulty);
+    }
+
+    #[test]
+    fn test_mine_block_with_more_threads_than_nonces() {
+        // only the first thread has a nonce to try, which is enough with no difficulty
+        let mut miner = create_miner(0, 1);
+        miner.miner_threads = 4;
+        let last_block = create_empty_block();
+        let generation = miner.blockchain.get_tip_generation();
+        let mined_block = miner.mine_block(&last_block, 0, &Vec::new(), generation);
+
+        assert_eq!(mined_block.unwrap().nonce, 0);
+    }
+
+    #[test]
+    fn test_mine_block_collects_fees() {
+        let miner = create_miner(1, 1_000);
+        let last_block = create_empty_block();
+        let generation = miner.blockchain.get_tip_generation();
+        let transactions = vec![
+            alice_keypair().create_transaction_with_fee(bob(), 10, 2, 0),
+            alice_keypair().create_transaction_with_fee(bob(), 10, 3, 1),
+        ];
+
+        let mined_block = miner
+            .mine_block(&last_block, 1, &transactions, generation)
+            .unwrap();
+
+        // the coinbase pays the subsidy plus the fees to the miner
+        let coinbase = &mined_block.transactions[0];
+        assert_eq!(coinbase.recipient, miner_address());
+        assert_eq!(coinbase.amount, BLOCK_SUBSIDY + 5);
+    }
+
+    #[test]
+    fn test_mine_block_with_unique_coinbase() {
+        let miner = create_miner(0, 1);
+        let generation = miner.blockchain.get_tip_generation();
+        let first_block = create_empty_block();
+        let mut second_block = create_empty_block();
+        second_block.index = 1;
+
+        // the coinbases pay the same amount to the same miner, but they are in blocks of different heights
+        let first_coinbase = miner
+            .mine_block(&first_block, 0, &Vec::new(), generation)
+            .unwrap()
+            .transactions[0]
+            .clone();
+        let second_coinbase = miner
+            .mine_block(&second_block, 0, &Vec::new(), generation)
+            .unwrap()
+            .transactions[0]
+            .clone();
+
+        assert_eq!(first_coinbase.nonce, 1 << EXTRA_NONCE_BITS);
+        assert_eq!(second_coinbase.nonce, 2 << EXTRA_NONCE_BITS);
+        assert_ne!(first_coinbase.hash(), second_coinbase.hash());
+    }
+
+    #[test]
+    fn test_mine_block_rolls_extra_nonce() {
+        // with a single nonce, most of the blocks are not valid
+        let difficulty = 6;
+        let max_nonce = 1;
+
+        // but changing the extra nonce of the coinbase, a valid block is eventually found
+        let mut miner = create_miner(difficulty, max_nonce);
+        miner.miner_threads = 4;
         let last_block = create_empty_block();
-        let result = miner.mine_block(&last_block, &Vec::new());
+        let generation = miner.blockchain.get_tip_generation();
+        let mined_block = miner
+            .mine_block(&last_block, difficulty, &Vec::new(), generation)
+            .unwrap();
+
+        assert_eq!(mined_block.nonce, 0);
+        assert_mined_block_is_valid(&mined_block, &last_block, difficulty);
+    }
+
+    #[test]
+    fn test_mine_block_cancelled_by_new_block() {
+        // with a difficulty so high and so many nonces, mining would take forever
+        let miner = create_miner(1, u64::MAX);
+        let last_block = miner.blockchain.get_last_block();
+        let generation = miner.blockchain.get_tip_generation();
+
+        // but a new block is added to the blockchain (e.g. from a peer)
+        add_mock_transaction(&miner);
+
+        // so mining stops right away, as the last block is outdated
+        let result = miner.mine_block(&last_block, MAX_DIFFICULTY, &Vec::new(), generation);
         assert!(result.is_none());
+        assert!(miner.is_tip_outdated(generation));
     }
 
     #[test]
@@ -235,20 +562,27 @@ mod tests {
         let blockchain = miner.blockchain.clone();
         let pool = miner.pool.clone();
 
-        add_mock_transaction(&pool);
+        add_mock_transaction(&miner);
         let result = miner.run();
 
         // mining should be successful
         assert!(result.is_ok());
 
+        // the miner stops after reaching the block limit
+        let stats = miner.status.get_stats();
+        assert!(!stats.running);
+        assert_eq!(stats.blocks_mined, 1);
+        assert!(stats.hash_rate >= 0.0);
+
         // a new block should have been added to the blockchain
         let blocks = blockchain.get_all_blocks();
-        assert_eq!(blocks.len(), 2);
-        let genesis_block = &blocks[0];
-        let mined_block = &blocks[1];
+        assert_eq!(blocks.len(), 3);
+        let previous_block = &blocks[1];
+        let mined_block = &blocks[2];
 
-        // the mined block must be valid
-        assert_mined_block_is_valid(mined_block, genesis_block, blockchain.difficulty);
+        // the mined block must be valid, with the difficulty required by the blockchain
+        assert_mined_block_is_valid(mined_block, previous_block, blockchain.difficulty);
+        assert_eq!(mined_block.difficulty, blockchain.difficulty);
 
         // the mined block must include the transaction added previously plus the coinbase
         let mined_transactions = &mined_block.transactions;
@@ -256,24 +590,87 @@ mod tests {
 
         // the transaction pool must be empty
         // because the transaction was added to the block when mining
-        let transactions = pool.pop();
+        let transactions = pool.pop(&blockchain.get_account_balances(), 1_000, 1_000_000);
         assert!(transactions.is_empty());
     }
 
     #[test]
-    #[should_panic(expected = "No valid block was mined at index `1`")]
-    fn test_run_block_not_found() {
-        // with a max_nonce so low and difficulty so high
-        // we will never find a valid block
-        let difficulty = MAX_DIFFICULTY;
+    fn test_run_nonces_exhausted() {
+        // with a max_nonce so low, the nonces will be exhausted many times
+        let difficulty = 6;
         let max_nonce = 1;
         let miner = create_miner(difficulty, max_nonce);
+        add_mock_transaction(&miner);
+
+        // but mining must not fail, and the block is eventually found
+        miner.run().unwrap();
+        let blocks = miner.blockchain.get_all_blocks();
+        assert_eq!(blocks.len(), 3);
+        assert_mined_block_is_valid(&blocks[2], &blocks[1], difficulty);
+    }
+
+    #[test]
+    fn test_run_block_superseded() {
+        let difficulty = 1;
+        let max_nonce = 1_000_000;
+        let miner = create_miner(difficulty, max_nonce);
+        add_mock_transaction(&miner);
+
+        // a block for the same index arrives from a peer before the mined one is added
+        let account_balances = miner.blockchain.get_account_balances();
+        let transactions = miner.pool.pop(&account_balances, 1_000, 1_000_000);
+        let last_block = miner.blockchain.get_last_block();
+        let generation = miner.blockchain.get_tip_generation();
+        let mined_block = miner
+            .mine_block(&last_block, difficulty, &transactions, generation)
+            .unwrap();
+        let peer_block = miner
+            .mine_block(&last_block, difficulty, &Vec::new(), generation)
+            .unwrap();
+        miner.blockchain.add_block(peer_block).unwrap();
+
+        // the mined block is not in the main chain, but the transaction goes back to the pool
+        let status = miner.blockchain.add_block(mined_block).unwrap();
+        assert_eq!(status, BlockStatus::SideChain);
+        let account_balances = miner.blockchain.get_account_balances();
+        miner.pool.requeue(transactions, &account_balances);
+
+        // so the miner includes it in the next block on top of the winning one
+        miner.run().unwrap();
+        let mined_block = miner.blockchain.get_last_block();
+        assert_eq!(mined_block.index, 3);
+        assert_eq!(mined_block.transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_run_requeues_transactions_removed_from_main_chain() {
+        let difficulty = 1;
+        let max_nonce = 1_000_000;
+        let miner = create_miner(difficulty, max_nonce);
+        add_mock_transaction(&miner);
+        let funding_block = miner.blockchain.get_last_block();
 
-        let pool = &miner.pool;
-        add_mock_transaction(pool);
+        // the transaction is mined in a new block
+        miner.run().unwrap();
+        assert_eq!(miner.blockchain.get_last_block().transactions.len(), 2);
+
+        // but a peer sends a heavier branch without it, that replaces our last block
+        let mut last_block = funding_block;
+        for _ in 0..2 {
+            let generation = miner.blockchain.get_tip_generation();
+            let peer_block = miner
+                .mine_block(&last_block, difficulty, &Vec::new(), generation)
+                .unwrap();
+            miner.blockchain.add_block(peer_block.clone()).unwrap();
+            last_block = peer_block;
+        }
+        assert_eq!(miner.blockchain.get_last_block().hash, last_block.hash);
 
-        // mining should return a BlockNotMined error
+        // so the miner includes the transaction again on top of the new main chain
         miner.run().unwrap();
+        let mined_block = miner.blockchain.get_last_block();
+        assert_eq!(mined_block.index, 4);
+        assert_eq!(mined_block.transactions.len(), 2);
     }
 
     fn create_default_miner() -> Miner {
@@ -289,41 +686,72 @@ mod tests {
     fn create_miner(difficulty: u32, max_nonce: u64) -> Miner {
         let miner_address = miner_address();
         let max_blocks = 1;
+        let miner_threads = 1;
         let tx_waiting_ms = 1;
-        let target = Miner::create_target(difficulty);
+        let max_block_transactions = 1_000;
+        let max_block_bytes = 1_000_000;
 
-        let blockchain = Blockchain::new(difficulty);
-        let pool = TransactionPool::new();
+        let pool = TransactionPool::new(1_000, 1_000_000);
+        let blockchain = Blockchain::new(difficulty).with_transaction_pool(pool.clone());
 
         Miner {
             miner_address,
             max_blocks,
             max_nonce,
+            miner_threads,
             tx_waiting_ms,
+            max_block_transactions,
+            max_block_bytes,
             blockchain,
             pool,
-            target,
+            status: MinerStatus::default(),
+            metrics: Metrics::new(),
+            gossip: Gossip::default(),
         }
     }
 
     fn create_empty_block() -> Block {
-        return Block::new(0, 0, BlockHash::default(), Vec::new());
+        Block::new(0, 0, 0, BlockHash::default(), Vec::new())
     }
 
-    fn add_mock_transaction(pool: &TransactionPool) {
-        // the transaction is valid because the genesis block gives rewards to the miner address
+    fn add_mock_transaction(miner: &Miner) {
+        // first we add a block that gives rewards to the miner address
         // so that address can be a sender of funds to other addresses
-        let transaction = Transaction {
-            sender: miner_address(),
-            recipient: bob(),
-            amount: 3,
-        };
-        pool.add_transaction(transaction.clone());
+        let last_block = miner.blockchain.get_last_block();
+        let difficulty = miner.blockchain.get_next_difficulty();
+        let funding_block = (0..)
+            .map(|nonce| {
+                let transactions = vec![create_funding_coinbase()];
+                Block::new(1, difficulty, nonce, last_block.hash, transactions)
+            })
+            .find(|block| block.hash.leading_zeros() >= difficulty)
+            .unwrap();
+        miner.blockchain.add_block(funding_block).unwrap();
+
+        let transaction = alice_keypair().create_transaction(bob(), 3, 0);
+        let account_balances = miner.blockchain.get_account_balances();
+        miner
+            .pool
+            .add_transaction(transaction, &account_balances)
+            .unwrap();
+    }
+
+    fn create_funding_coinbase() -> Transaction {
+        Transaction {
+            sender: Address::default(),
+            recipient: miner_address(),
+            amount: BLOCK_SUBSIDY,
+            fee: 0,
+            // it's always the coinbase of the first block
+            nonce: 1 << EXTRA_NONCE_BITS,
+            public_key: PublicKey::default(),
+            signature: Signature::default(),
+        }
     }
 
     fn assert_mined_block_is_valid(mined_block: &Block, previous_block: &Block, difficulty: u32) {
         assert_eq!(mined_block.index, previous_block.index + 1);
         assert_eq!(mined_block.previous_hash, previous_block.hash);
-        assert!(mined_block.hash.leading_zeros() >= difficulty as u32);
+        assert!(mined_block.hash.leading_zeros() >= difficulty);
     }
 }
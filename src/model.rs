@@ -1,17 +1,26 @@
 mod account_balance_map;
 mod address;
 mod block;
+mod block_store;
 mod blockchain;
+mod encoding;
+mod merkle;
+mod signature;
 mod transaction;
 mod transaction_pool;
 
 // Explicitly controlling which individual identifiers we export
 // It also avoids verbose module imports from other files
+pub use account_balance_map::{Account, AccountBalanceMap, Nonce};
 pub use address::Address;
 pub use block::{Block, BlockHash};
-pub use blockchain::{Blockchain, BlockchainError, BLOCK_SUBSIDY};
-pub use transaction::Transaction;
-pub use transaction_pool::{TransactionPool, TransactionVec};
+pub use blockchain::{BlockStatus, Blockchain, ChainInfo, ConfirmedTransaction, EXTRA_NONCE_BITS};
+pub use merkle::MerkleProof;
+pub use signature::{PublicKey, Signature};
+pub use transaction::{Transaction, TransactionHash};
+pub use transaction_pool::{PendingTransaction, TransactionPool, TransactionVec};
 
 #[cfg(test)]
-pub use address::test_util;
+pub use blockchain::BLOCK_SUBSIDY;
+#[cfg(test)]
+pub mod test_util;
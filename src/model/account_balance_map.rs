@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 use thiserror::Error;
@@ -5,6 +6,7 @@ use thiserror::Error;
 use super::Address;
 
 pub type Amount = u64;
+pub type Nonce = u64;
 
 #[derive(Error, PartialEq, Debug)]
 pub enum AccountBalanceMapError {
@@ -13,52 +15,122 @@ pub enum AccountBalanceMapError {
 
     #[error("Insufficient funds")]
     InsufficientFunds,
+
+    #[error("Nonce already used")]
+    NonceAlreadyUsed,
+
+    #[error("Nonce out of order")]
+    NonceOutOfOrder,
+}
+
+// State of a single account
+// The nonce is the number of transfers sent from the account, so it's also the next valid nonce
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: Amount,
+    pub nonce: Nonce,
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct AccountBalanceMap(HashMap<Address, Amount>);
+// Previous state of a group of accounts, used to revert the changes made to them
+// A "None" value means that the account did not exist
+pub type SavedAccounts = Vec<(Address, Option<Account>)>;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AccountBalanceMap(HashMap<Address, Account>);
 
 impl AccountBalanceMap {
     pub fn add_amount(&mut self, recipient: &Address, amount: Amount) {
-        let balance = self.get_recipient_balance(recipient);
-        self.update_balance(recipient, balance + amount);
+        let account = self.0.entry(recipient.clone()).or_default();
+        account.balance += amount;
     }
 
+    // Moves funds between accounts
+    // The fee is also debited from the sender, it's up to the caller to credit it to the miner
+    // Each transfer must use exactly the next nonce of the sender, so transfers cannot be replayed or reordered
     pub fn transfer(
         &mut self,
         sender: &Address,
         recipient: &Address,
         amount: Amount,
+        fee: Amount,
+        nonce: Nonce,
     ) -> Result<(), AccountBalanceMapError> {
-        let sender_balance = self.get_sender_balance(sender)?;
-        let recipient_balance = self.get_recipient_balance(recipient);
+        let sender_account = self.get_sender_account(sender)?;
+
+        if nonce < sender_account.nonce {
+            return Err(AccountBalanceMapError::NonceAlreadyUsed);
+        }
 
-        if sender_balance < amount {
-            return Err(AccountBalanceMapError::InsufficientFunds);
+        if nonce > sender_account.nonce {
+            return Err(AccountBalanceMapError::NonceOutOfOrder);
         }
 
-        self.update_balance(sender, sender_balance - amount);
-        self.update_balance(recipient, recipient_balance + amount);
+        // an overflowing total can never be paid, so it's also a case of insufficient funds
+        let total = match amount.checked_add(fee) {
+            Some(total) if total <= sender_account.balance => total,
+            _ => return Err(AccountBalanceMapError::InsufficientFunds),
+        };
+
+        let sender_account = self.0.get_mut(sender).unwrap();
+        sender_account.balance -= total;
+        sender_account.nonce += 1;
+
+        self.add_amount(recipient, amount);
 
         Ok(())
     }
 
-    fn get_recipient_balance(&self, recipient: &Address) -> Amount {
-        match self.0.get(recipient) {
-            Some(amount) => *amount,
+    // Checks if the address has ever received funds
+    pub fn contains(&self, address: &Address) -> bool {
+        self.0.contains_key(address)
+    }
+
+    // Returns a copy of the state of the address, which is empty for unknown addresses
+    pub fn get_account(&self, address: &Address) -> Account {
+        self.0.get(address).cloned().unwrap_or_default()
+    }
+
+    // Returns the current balance of the address, which is zero for unknown addresses
+    pub fn get_balance(&self, address: &Address) -> Amount {
+        match self.0.get(address) {
+            Some(account) => account.balance,
             None => 0,
         }
     }
 
-    fn get_sender_balance(&self, sender: &Address) -> Result<Amount, AccountBalanceMapError> {
-        match self.0.get(sender) {
-            Some(balance) => Ok(*balance),
-            None => Err(AccountBalanceMapError::SenderAccountDoesNotExist),
+    // Returns the next valid nonce for transfers sent from the address
+    pub fn get_nonce(&self, address: &Address) -> Nonce {
+        match self.0.get(address) {
+            Some(account) => account.nonce,
+            None => 0,
+        }
+    }
+
+    // Returns the current state of the indicated accounts, so they can be restored later
+    pub fn save_accounts<'a>(
+        &self,
+        addresses: impl IntoIterator<Item = &'a Address>,
+    ) -> SavedAccounts {
+        addresses
+            .into_iter()
+            .map(|address| (address.clone(), self.0.get(address).cloned()))
+            .collect()
+    }
+
+    // Reverts the accounts to a previously saved state, removing the ones that did not exist
+    pub fn restore_accounts(&mut self, saved_accounts: &[(Address, Option<Account>)]) {
+        for (address, account) in saved_accounts {
+            match account {
+                Some(account) => self.0.insert(address.clone(), account.clone()),
+                None => self.0.remove(address),
+            };
         }
     }
 
-    fn update_balance(&mut self, address: &Address, new_balance: Amount) {
-        let balance = self.0.entry(address.clone()).or_insert(0);
-        *balance = new_balance;
+    fn get_sender_account(&self, sender: &Address) -> Result<&Account, AccountBalanceMapError> {
+        match self.0.get(sender) {
+            Some(account) => Ok(account),
+            None => Err(AccountBalanceMapError::SenderAccountDoesNotExist),
+        }
     }
 }
@@ -4,9 +4,12 @@ use std::{
     str::FromStr,
 };
 
+use crypto::{digest::Digest, sha2::Sha256};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use super::PublicKey;
+
 // Addresses are 32-bytes long
 type Byte = u8;
 const LEN: usize = 32;
@@ -25,6 +28,12 @@ pub enum AddressError {
 #[serde(try_from = "String", into = "String")]
 pub struct Address([Byte; LEN]);
 
+impl Address {
+    pub fn as_bytes(&self) -> &[Byte] {
+        &self.0
+    }
+}
+
 impl TryFrom<Vec<Byte>> for Address {
     type Error = AddressError;
 
@@ -62,39 +71,23 @@ impl From<Address> for String {
     }
 }
 
-impl fmt::Display for Address {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", hex::encode(self.0))
-    }
-}
+// The address of an account is the SHA-256 hash of its public key
+// So only the owner of the secret key can sign transactions that spend from the address
+impl From<&PublicKey> for Address {
+    fn from(public_key: &PublicKey) -> Self {
+        let mut byte_hash = [0; LEN];
+        let mut hasher = Sha256::new();
 
-// Some sample addresses to be used in tests all over the project
-// We export functions to workaround constant value restrictions in Rust
-#[cfg(test)]
-pub mod test_util {
-    use std::convert::TryFrom;
-
-    use super::Address;
-
-    pub fn alice() -> Address {
-        Address::try_from(
-            "f780b958227ff0bf5795ede8f9f7eaac67e7e06666b043a400026cbd421ce28e".to_string(),
-        )
-        .unwrap()
-    }
+        hasher.input(public_key.as_bytes());
+        hasher.result(&mut byte_hash);
 
-    pub fn bob() -> Address {
-        Address::try_from(
-            "51df097c03c0a6e64e54a6fce90cb6968adebd85955917ed438e3d3c05f2f00f".to_string(),
-        )
-        .unwrap()
+        Address(byte_hash)
     }
+}
 
-    pub fn carol() -> Address {
-        Address::try_from(
-            "b4f8293fb123ef3ff9ad49e923f4afc732774ee2bfdc3b278a359b54473c2277".to_string(),
-        )
-        .unwrap()
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
     }
 }
 
@@ -1,10 +1,12 @@
 use chrono::prelude::*;
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
 use ethereum_types::U256;
 use serde::{Deserialize, Serialize};
 
-use super::Transaction;
+use super::{
+    encoding::{self, Encoder},
+    merkle::{self, MerkleProof},
+    Transaction,
+};
 
 // We encapsulate the paricular hash value implementation
 // to be able to easily change it in the future
@@ -15,16 +17,48 @@ pub type BlockHash = U256;
 pub struct Block {
     pub index: u64,
     pub timestamp: i64,
+    pub difficulty: u32,
     pub nonce: u64,
     pub previous_hash: BlockHash,
+    pub merkle_root: BlockHash,
     pub hash: BlockHash,
     pub transactions: Vec<Transaction>,
 }
 
+// Fields of the block covered by the hash
+// The transactions are only included through the merkle root, so the header has always the same size
+#[derive(Debug)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: i64,
+    pub difficulty: u32,
+    pub nonce: u64,
+    pub previous_hash: BlockHash,
+    pub merkle_root: BlockHash,
+}
+
+impl BlockHeader {
+    // Canonical binary encoding of the header, the data covered by the block hash
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_fields(&mut Encoder::new()).finish()
+    }
+
+    fn encode_fields<'a>(&self, encoder: &'a mut Encoder) -> &'a mut Encoder {
+        encoder
+            .u64(self.index)
+            .i64(self.timestamp)
+            .u32(self.difficulty)
+            .u64(self.nonce)
+            .hash(&self.previous_hash)
+            .hash(&self.merkle_root)
+    }
+}
+
 impl Block {
-    // Create a brand new block. The hash value will be caclulated and set automatically.
+    // Create a brand new block. The merkle root and hash values will be caclulated and set automatically.
     pub fn new(
         index: u64,
+        difficulty: u32,
         nonce: u64,
         previous_hash: BlockHash,
         transactions: Vec<Transaction>,
@@ -32,30 +66,138 @@ impl Block {
         let mut block = Block {
             index,
             timestamp: Utc::now().timestamp_millis(),
+            difficulty,
             nonce,
             previous_hash,
+            merkle_root: BlockHash::default(),
             hash: BlockHash::default(),
             transactions,
         };
+        block.merkle_root = block.calculate_merkle_root();
         block.hash = block.calculate_hash();
 
         block
     }
 
-    // Calculate the hash value of the block
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            difficulty: self.difficulty,
+            nonce: self.nonce,
+            previous_hash: self.previous_hash,
+            merkle_root: self.merkle_root,
+        }
+    }
+
+    // Calculate the SHA-256 hash value of the block, only over the encoded header
     pub fn calculate_hash(&self) -> BlockHash {
-        // We cannot use the hash field to calculate the hash
-        let mut hashable_data = self.clone();
-        hashable_data.hash = BlockHash::default();
-        let serialized = serde_json::to_string(&hashable_data).unwrap();
+        encoding::calculate_hash(&self.header().encode())
+    }
+
+    // Canonical binary encoding of the whole block: the header followed by the list of transactions
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        self.header().encode_fields(&mut encoder);
+
+        encoder.u32(self.transactions.len() as u32);
+        for transaction in self.transactions.iter() {
+            transaction.encode_fields(&mut encoder);
+        }
+
+        encoder.finish()
+    }
+
+    // Calculate the root of the merkle tree of the hashes of the transactions, in the same order as in the block
+    pub fn calculate_merkle_root(&self) -> BlockHash {
+        merkle::calculate_merkle_root(&self.transaction_hashes())
+    }
 
-        // Cacluate and return the SHA-256 hash value for the block
-        let mut byte_hash = <[u8; 32]>::default();
-        let mut hasher = Sha256::new();
+    // Returns the proof that the transaction in the indicated position is included in the merkle root of the block
+    pub fn create_merkle_proof(&self, transaction_index: usize) -> Option<MerkleProof> {
+        merkle::create_merkle_proof(&self.transaction_hashes(), transaction_index)
+    }
+
+    fn transaction_hashes(&self) -> Vec<BlockHash> {
+        self.transactions
+            .iter()
+            .map(|transaction| transaction.hash())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::test_util::{alice_keypair, bob};
+
+    use super::*;
+
+    // Fields of the header of a sample block, encoded
+    const ENCODING_VERSION: &str = "01";
+    const INDEX: &str = "0000000000000001";
+    const TIMESTAMP: &str = "00000174876e8000";
+    const DIFFICULTY: &str = "00000002";
+    const NONCE: &str = "0000000000000003";
+    const PREVIOUS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000004";
+    const MERKLE_ROOT: &str = "0000000000000000000000000000000000000000000000000000000000000005";
+
+    fn create_sample_block(transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(1, 2, 3, BlockHash::from(4), transactions);
+        block.timestamp = 1_600_000_000_000;
+        block.merkle_root = BlockHash::from(5);
+        block.hash = block.calculate_hash();
+
+        block
+    }
+
+    // The encoding determines the block hashes, so any change on it must be intentional
+    #[test]
+    fn should_match_golden_encoding() {
+        let block = create_sample_block(Vec::new());
+
+        let header_fields = [
+            ENCODING_VERSION,
+            INDEX,
+            TIMESTAMP,
+            DIFFICULTY,
+            NONCE,
+            PREVIOUS_HASH,
+            MERKLE_ROOT,
+        ];
+        assert_eq!(hex::encode(block.header().encode()), header_fields.concat());
+
+        let expected_hash = "0xdffe4c59c7782b4721e0299624f3e9733318f900e31e6145c00c7e756415ebac";
+        assert_eq!(serde_json::to_value(block.hash).unwrap(), expected_hash);
+
+        // without transactions, the block is the header followed by a zero length
+        let transaction_count = "00000000";
+        assert_eq!(
+            hex::encode(block.encode()),
+            [&header_fields[..], &[transaction_count]].concat().concat()
+        );
+    }
+
+    #[test]
+    fn should_encode_transactions_after_header() {
+        let transaction = alice_keypair().create_transaction(bob(), 10, 0);
+        let block = create_sample_block(vec![transaction.clone()]);
+
+        // the transactions do not repeat the version of the encoding
+        let mut expected = block.header().encode();
+        expected.extend_from_slice(&1_u32.to_be_bytes());
+        expected.extend_from_slice(&transaction.encode()[1..]);
+        assert_eq!(block.encode(), expected);
+    }
 
-        hasher.input_str(&serialized);
-        hasher.result(&mut byte_hash);
+    #[test]
+    fn should_not_hash_transactions_directly() {
+        // the transactions only change the hash through the merkle root
+        let mut block = create_sample_block(Vec::new());
+        let hash = block.hash;
+        block.transactions = vec![alice_keypair().create_transaction(bob(), 10, 0)];
+        assert_eq!(block.calculate_hash(), hash);
 
-        U256::from(byte_hash)
+        block.merkle_root = block.calculate_merkle_root();
+        assert_ne!(block.calculate_hash(), hash);
     }
 }
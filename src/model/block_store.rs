@@ -0,0 +1,285 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use super::{blockchain::BlockVec, AccountBalanceMap, Block, BlockHash};
+
+const BLOCKS_FILE_NAME: &str = "blocks.jsonl";
+const SNAPSHOTS_DIR_NAME: &str = "snapshots";
+
+// Amount of snapshots kept, in case the last one belongs to a branch that was left out of the main chain
+const MAX_SNAPSHOTS: usize = 2;
+
+#[derive(Error, PartialEq, Debug)]
+pub enum BlockStoreError {
+    #[error("Corrupted block at line {0} of the block store")]
+    CorruptedBlock(usize),
+}
+
+// State of all the accounts right after processing a block of the main chain
+// It allows to rebuild the account balances without processing all the previous blocks again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountSnapshot {
+    pub index: u64,
+    pub hash: BlockHash,
+    pub account_balances: AccountBalanceMap,
+}
+
+// Append-only file that stores the blocks of the blockchain, one JSON-serialized block per line
+// Each block is flushed to disk before being added to the blockchain, so the file never misses an accepted block
+// A write interrupted in the middle (e.g. the process was killed) leaves an incomplete last line,
+// which is detected and discarded the next time the store is opened
+// Any other invalid line means that the file is corrupted, so the store refuses to open instead of losing blocks
+#[derive(Debug)]
+pub struct BlockStore {
+    file: File,
+    // amount of bytes of complete blocks, the file is restored to it if an append fails
+    len: u64,
+    snapshots_dir: PathBuf,
+}
+
+impl BlockStore {
+    // Opens (or creates) the store in the indicated directory, returning all the stored blocks in order
+    pub fn open(data_dir: &Path) -> Result<(BlockStore, BlockVec)> {
+        let snapshots_dir = data_dir.join(SNAPSHOTS_DIR_NAME);
+        fs::create_dir_all(&snapshots_dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(data_dir.join(BLOCKS_FILE_NAME))?;
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let (blocks, len) = BlockStore::parse_blocks(&content)?;
+
+        // discard the remains of an interrupted write, so new blocks are appended after the last complete one
+        if len < content.len() as u64 {
+            warn!(
+                "discarding {} bytes of an incomplete block at the end of the block store",
+                content.len() as u64 - len
+            );
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+
+        let store = BlockStore {
+            file,
+            len,
+            snapshots_dir,
+        };
+
+        Ok((store, blocks))
+    }
+
+    // Writes a block at the end of the store, only returning after it's safely on disk
+    pub fn append(&mut self, block: &Block) -> Result<()> {
+        let mut line = serde_json::to_vec(block)?;
+        line.push(b'\n');
+
+        let result = self
+            .file
+            .write_all(&line)
+            .and_then(|_| self.file.sync_data());
+
+        // a partial write would corrupt the next appends, so we remove it
+        if let Err(error) = result {
+            self.file.set_len(self.len)?;
+            return Err(error.into());
+        }
+
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    // Writes a new snapshot and removes the oldest ones
+    // The snapshot is written in a temporary file and then renamed, so a crash never leaves an incomplete snapshot
+    pub fn save_snapshot(&self, snapshot: &AccountSnapshot) -> Result<()> {
+        let path = self.snapshots_dir.join(format!("{}.json", snapshot.index));
+        let temp_path = path.with_extension("tmp");
+
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&serde_json::to_vec(snapshot)?)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        File::open(&self.snapshots_dir)?.sync_all()?;
+
+        for index in self
+            .list_snapshot_indexes()?
+            .into_iter()
+            .skip(MAX_SNAPSHOTS)
+        {
+            fs::remove_file(self.snapshots_dir.join(format!("{}.json", index)))?;
+        }
+
+        Ok(())
+    }
+
+    // Returns all the snapshots that can be read, from the newest to the oldest
+    pub fn load_snapshots(&self) -> Result<Vec<AccountSnapshot>> {
+        let mut snapshots = Vec::new();
+        for index in self.list_snapshot_indexes()? {
+            let path = self.snapshots_dir.join(format!("{}.json", index));
+            let snapshot = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|content| Ok(serde_json::from_slice::<AccountSnapshot>(&content)?));
+
+            match snapshot {
+                Ok(snapshot) => snapshots.push(snapshot),
+                Err(error) => warn!("ignoring unreadable snapshot {:?}: {}", path, error),
+            }
+        }
+
+        Ok(snapshots)
+    }
+
+    // The snapshot files are named after the index of their block, we return them from the highest to the lowest
+    fn list_snapshot_indexes(&self) -> Result<Vec<u64>> {
+        let mut indexes: Vec<u64> = Vec::new();
+        for entry in fs::read_dir(&self.snapshots_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(index) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse().ok())
+            {
+                indexes.push(index);
+            }
+        }
+        indexes.sort_unstable_by(|a, b| b.cmp(a));
+
+        Ok(indexes)
+    }
+
+    // Returns the blocks of all the complete lines, and the amount of bytes they take
+    // A corrupted line is an error, only an incomplete last line is ignored
+    fn parse_blocks(content: &[u8]) -> Result<(BlockVec, u64), BlockStoreError> {
+        let mut blocks = BlockVec::new();
+        let mut len = 0;
+
+        // the last element after splitting is the content after the last line break, which is never complete
+        let mut lines = content.split(|byte| *byte == b'\n').collect::<Vec<&[u8]>>();
+        lines.pop();
+
+        for (position, line) in lines.iter().enumerate() {
+            match serde_json::from_slice::<Block>(line) {
+                Ok(block) => blocks.push(block),
+                Err(_) => return Err(BlockStoreError::CorruptedBlock(position + 1)),
+            }
+            len += line.len() as u64 + 1;
+        }
+
+        Ok((blocks, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::test_util::create_temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn should_return_appended_blocks_when_reopened() {
+        let data_dir = create_temp_dir("block_store_reopen");
+
+        // a new store is empty
+        let (mut store, blocks) = BlockStore::open(&data_dir).unwrap();
+        assert!(blocks.is_empty());
+
+        let block_1 = Block::new(1, 0, 0, BlockHash::default(), Vec::new());
+        let block_2 = Block::new(2, 0, 0, block_1.hash, Vec::new());
+        store.append(&block_1).unwrap();
+        store.append(&block_2).unwrap();
+        drop(store);
+
+        // the blocks are returned in the same order
+        let (_, blocks) = BlockStore::open(&data_dir).unwrap();
+        let hashes: Vec<BlockHash> = blocks.iter().map(|block| block.hash).collect();
+        assert_eq!(hashes, vec![block_1.hash, block_2.hash]);
+    }
+
+    #[test]
+    fn should_discard_incomplete_block() {
+        let data_dir = create_temp_dir("block_store_incomplete");
+        let block_1 = Block::new(1, 0, 0, BlockHash::default(), Vec::new());
+        let block_2 = Block::new(2, 0, 0, block_1.hash, Vec::new());
+        let (mut store, _) = BlockStore::open(&data_dir).unwrap();
+        store.append(&block_1).unwrap();
+        drop(store);
+
+        // simulate a crash in the middle of writing the second block
+        let serialized = serde_json::to_vec(&block_2).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(data_dir.join(BLOCKS_FILE_NAME))
+            .unwrap();
+        file.write_all(&serialized[..serialized.len() / 2]).unwrap();
+        drop(file);
+
+        // only the complete block is returned
+        let (mut store, blocks) = BlockStore::open(&data_dir).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].hash, block_1.hash);
+
+        // and new blocks are appended after it
+        store.append(&block_2).unwrap();
+        drop(store);
+        let (_, blocks) = BlockStore::open(&data_dir).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].hash, block_2.hash);
+    }
+
+    #[test]
+    fn should_not_open_with_corrupted_block() {
+        let data_dir = create_temp_dir("block_store_corrupted");
+        let block_1 = Block::new(1, 0, 0, BlockHash::default(), Vec::new());
+        let block_2 = Block::new(2, 0, 0, block_1.hash, Vec::new());
+        let (mut store, _) = BlockStore::open(&data_dir).unwrap();
+        store.append(&block_1).unwrap();
+        store.append(&block_2).unwrap();
+        drop(store);
+
+        // damage the first block, which is a complete line so it was not an interrupted write
+        let path = data_dir.join(BLOCKS_FILE_NAME);
+        let mut content = fs::read(&path).unwrap();
+        content[0] = b'#';
+        fs::write(&path, &content).unwrap();
+
+        // the store is not opened, and the blocks after the damaged one are kept in the file
+        let err = BlockStore::open(&data_dir)
+            .unwrap_err()
+            .downcast::<BlockStoreError>()
+            .unwrap();
+        assert_eq!(err, BlockStoreError::CorruptedBlock(1));
+        assert_eq!(fs::read(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn should_keep_latest_snapshots() {
+        let data_dir = create_temp_dir("block_store_snapshots");
+        let (store, _) = BlockStore::open(&data_dir).unwrap();
+
+        for index in [10, 20, 30] {
+            let snapshot = AccountSnapshot {
+                index,
+                hash: BlockHash::from(index),
+                account_balances: AccountBalanceMap::default(),
+            };
+            store.save_snapshot(&snapshot).unwrap();
+        }
+
+        // only the newest snapshots are kept, starting by the latest
+        let snapshots = store.load_snapshots().unwrap();
+        let indexes: Vec<u64> = snapshots.iter().map(|snapshot| snapshot.index).collect();
+        assert_eq!(indexes, vec![30, 20]);
+        assert_eq!(snapshots[0].hash, BlockHash::from(30));
+    }
+}
@@ -1,20 +1,56 @@
 use anyhow::Result;
+use chrono::Utc;
+use ethereum_types::U256;
+use serde::Serialize;
 use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
     slice::Iter,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
-use super::{account_balance_map::AccountBalanceMap, Block, BlockHash, Transaction};
+use super::{
+    account_balance_map::{Account, AccountBalanceMap, AccountBalanceMapError, SavedAccounts},
+    block_store::{AccountSnapshot, BlockStore},
+    transaction::TransactionError,
+    Address, Block, BlockHash, Transaction, TransactionHash, TransactionPool, TransactionVec,
+};
+use crate::util::Metrics;
 
 pub type BlockVec = Vec<Block>;
 
 // We don't need to export this because concurrency is encapsulated in this file
-type SyncedBlockVec = Arc<Mutex<BlockVec>>;
-type SyncedAccountBalanceVec = Arc<Mutex<AccountBalanceMap>>;
+type SyncedChainState = Arc<Mutex<ChainState>>;
+type SyncedGeneration = Arc<AtomicU64>;
 
 pub const BLOCK_SUBSIDY: u64 = 100;
 
+// The nonce of a coinbase has the index of its block in the upper bits and the extra nonce in the lower ones
+// So the coinbases of different blocks never have the same hash, even if they pay the same amount to the same miner
+pub const EXTRA_NONCE_BITS: u32 = 32;
+
+// We use SHA 256 hashes, so there cannot be more leading zeroes
+const MAX_DIFFICULTY: u32 = 256;
+
+// A block must be more recent than the median timestamp of this amount of previous blocks
+// The median is used so a single block with a wrong time cannot block the next ones
+const MEDIAN_TIME_BLOCKS: usize = 11;
+
+// How far ahead of the local clock the timestamp of a block can be, to allow for small clock differences
+const MAX_FUTURE_BLOCK_TIME_MS: i64 = 2 * 60 * 60 * 1000;
+
+// Default limits of the blocks kept while waiting for their previous block to arrive
+const MAX_ORPHAN_BLOCKS: usize = 100;
+const ORPHAN_EXPIRY_MS: u64 = 600_000;
+
+// Default limit of how many blocks below the last block of the main chain a new branch can start
+const MAX_FORK_DEPTH: u64 = 100;
+
 // Error types to return when trying to add blocks with invalid fields
 #[derive(Error, PartialEq, Debug)]
 #[allow(clippy::enum_variant_names)]
@@ -28,51 +64,524 @@ pub enum BlockchainError {
     #[error("Invalid hash")]
     InvalidHash,
 
+    #[error("Invalid merkle root")]
+    InvalidMerkleRoot,
+
     #[error("Invalid difficulty")]
     InvalidDifficulty,
 
+    #[error("Block timestamp is not after the median of the previous blocks")]
+    TimestampTooOld,
+
+    #[error("Block timestamp is too far in the future")]
+    TimestampTooNew,
+
     #[error("Coinbase transaction not found")]
     CoinbaseTransactionNotFound,
 
     #[error("Invalid coinbase amount")]
     InvalidCoinbaseAmount,
+
+    #[error("Coinbase nonce does not include the block index")]
+    InvalidCoinbaseNonce,
+
+    #[error("Block already exists")]
+    BlockAlreadyExists,
+
+    #[error("Block forks too far below the last block")]
+    ForkTooDeep,
+
+    #[error("Snapshot block not found")]
+    SnapshotBlockNotFound,
+}
+
+// Where a valid block ended up after adding it to the blockchain
+#[derive(PartialEq, Debug)]
+pub enum BlockStatus {
+    // The block is the new last block, replacing other blocks of the main chain if it was in a heavier branch
+    MainChain,
+
+    // The block is kept in a branch with less work than the main chain, in case it becomes the heaviest one
+    SideChain,
+
+    // The previous block is still unknown, so the block is kept until it arrives (e.g. blocks received out of order)
+    Orphan,
+}
+
+impl BlockStatus {
+    // Orphans are only kept aside, so they are not added to the blockchain until they get connected
+    pub fn is_connected(&self) -> bool {
+        matches!(self, BlockStatus::MainChain | BlockStatus::SideChain)
+    }
+}
+
+// A transaction included in the main chain, along with the block that contains it
+#[derive(Debug, Clone)]
+pub struct ConfirmedTransaction {
+    pub transaction: Transaction,
+    pub block_index: u64,
+    pub block_hash: BlockHash,
+    // number of blocks in the main chain since the block of the transaction, including it
+    pub confirmations: u64,
+}
+
+// Summary of the main chain, to report the state of the node
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainInfo {
+    // index of the last block
+    pub height: u64,
+    pub tip_hash: BlockHash,
+    // difficulty that the next block must have
+    pub difficulty: u32,
+    pub cumulative_work: U256,
+}
+
+// A page of the confirmed transactions of an address, from the newest to the oldest
+#[derive(Debug, Clone)]
+pub struct AddressTransactions {
+    // number of transactions of the address in the main chain, not only the ones in the page
+    pub total: usize,
+    pub transactions: Vec<ConfirmedTransaction>,
+}
+
+// Position of a transaction in the main chain
+#[derive(Debug, Clone, Copy)]
+struct TransactionLocation {
+    block_hash: BlockHash,
+    position: usize,
+}
+
+// A block known by the blockchain, along with the data needed to switch between branches
+#[derive(Debug)]
+struct BlockEntry {
+    block: Block,
+    // total proof of work of the branch that ends with this block
+    cumulative_work: U256,
+    // state of the accounts before processing the block, only known after the block was in the main chain
+    saved_accounts: Option<SavedAccounts>,
+}
+
+// A block whose previous block is unknown, along with the time it was received to be able to expire it
+#[derive(Debug)]
+struct OrphanBlock {
+    block: Block,
+    received_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct ChainState {
+    // all the valid blocks, including the ones in branches other than the main chain
+    entries: HashMap<BlockHash, BlockEntry>,
+    // hashes of the blocks in the main chain, so the position of each one is also its index
+    main_chain: Vec<BlockHash>,
+    // account balances after processing all the blocks in the main chain
+    account_balances: AccountBalanceMap,
+    // block of the main chain that includes each transaction
+    transaction_index: HashMap<TransactionHash, BlockHash>,
+    // transactions of the main chain sent or received by each address, in the order of the main chain
+    address_index: HashMap<Address, Vec<TransactionLocation>>,
+    // blocks waiting for their previous block, grouped by the hash of the previous block
+    orphans: HashMap<BlockHash, Vec<OrphanBlock>>,
+    // where the blocks are persisted, if the blockchain is not only kept in memory
+    store: Option<BlockStore>,
+    // index of the last block whose account balances were saved in a snapshot
+    last_snapshot_index: u64,
+}
+
+impl ChainState {
+    // The genesis block is the first block of the main chain
+    fn new() -> ChainState {
+        let genesis_block = Blockchain::create_genesis_block();
+
+        let mut state = ChainState::default();
+        let genesis_hash = genesis_block.hash;
+        let genesis_entry = BlockEntry {
+            block: genesis_block,
+            cumulative_work: U256::zero(),
+            saved_accounts: Some(SavedAccounts::new()),
+        };
+        state.entries.insert(genesis_hash, genesis_entry);
+        state.main_chain.push(genesis_hash);
+
+        state
+    }
+
+    fn get_last_entry(&self) -> &BlockEntry {
+        let last_hash = self.main_chain.last().unwrap();
+        &self.entries[last_hash]
+    }
+
+    // Adds a block at the end of the main chain, indexing its transactions
+    fn push_main_chain(&mut self, hash: BlockHash) {
+        let transactions = &self.entries[&hash].block.transactions;
+        for (position, transaction) in transactions.iter().enumerate() {
+            self.transaction_index.insert(transaction.hash(), hash);
+
+            let location = TransactionLocation {
+                block_hash: hash,
+                position,
+            };
+            for address in ChainState::get_addresses(transaction, position) {
+                self.address_index
+                    .entry(address.clone())
+                    .or_default()
+                    .push(location);
+            }
+        }
+        self.main_chain.push(hash);
+    }
+
+    // Removes from the indexes the transactions of a block that is not in the main chain anymore
+    // Blocks are removed from the end of the main chain, so they must be unindexed from the newest to the oldest
+    fn unindex_transactions(&mut self, hash: &BlockHash) {
+        let transactions = &self.entries[hash].block.transactions;
+        for (position, transaction) in transactions.iter().enumerate() {
+            // identical transactions (like coinbases of other miners with the same fields) may be in other blocks
+            let transaction_hash = transaction.hash();
+            if self.transaction_index.get(&transaction_hash) == Some(hash) {
+                self.transaction_index.remove(&transaction_hash);
+            }
+
+            // the transactions of the block are the last ones indexed for each of its addresses
+            for address in ChainState::get_addresses(transaction, position) {
+                if let Some(locations) = self.address_index.get_mut(address) {
+                    while locations.last().map(|location| location.block_hash) == Some(*hash) {
+                        locations.pop();
+                    }
+                    if locations.is_empty() {
+                        self.address_index.remove(address);
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns the addresses involved in the transaction in the indicated position of a block
+    // The sender of the coinbase (the first transaction) is ignored, as it does not spend any funds
+    fn get_addresses(transaction: &Transaction, position: usize) -> Vec<&Address> {
+        if position == 0 || transaction.sender == transaction.recipient {
+            return vec![&transaction.recipient];
+        }
+
+        vec![&transaction.sender, &transaction.recipient]
+    }
+
+    fn get_confirmed_transaction(&self, location: &TransactionLocation) -> ConfirmedTransaction {
+        let block = &self.entries[&location.block_hash].block;
+        let last_index = self.get_last_entry().block.index;
+
+        ConfirmedTransaction {
+            transaction: block.transactions[location.position].clone(),
+            block_index: block.index,
+            block_hash: location.block_hash,
+            confirmations: last_index - block.index + 1,
+        }
+    }
+
+    fn is_in_main_chain(&self, hash: &BlockHash) -> bool {
+        let index = self.entries[hash].block.index as usize;
+        self.main_chain.get(index) == Some(hash)
+    }
+
+    // Returns the index of the last block of the main chain that the given block descends from
+    fn get_fork_index(&self, block: &Block) -> u64 {
+        let mut ancestor = block;
+        while !self.is_in_main_chain(&ancestor.hash) {
+            ancestor = &self.entries[&ancestor.previous_hash].block;
+        }
+
+        ancestor.index
+    }
+
+    // Returns the block that is the indicated amount of blocks before the given one, in the same branch
+    fn get_ancestor<'a>(&'a self, block: &'a Block, depth: u64) -> &'a Block {
+        let mut ancestor = block;
+        for _ in 0..depth {
+            ancestor = &self.entries[&ancestor.previous_hash].block;
+        }
+
+        ancestor
+    }
+
+    // Returns the median timestamp of the given block and the ones before it, up to "MEDIAN_TIME_BLOCKS" blocks
+    fn get_median_timestamp(&self, block: &Block) -> i64 {
+        let mut timestamps = vec![block.timestamp];
+        let mut ancestor = block;
+        while ancestor.index > 0 && timestamps.len() < MEDIAN_TIME_BLOCKS {
+            ancestor = &self.entries[&ancestor.previous_hash].block;
+            timestamps.push(ancestor.timestamp);
+        }
+        timestamps.sort_unstable();
+
+        timestamps[timestamps.len() / 2]
+    }
+
+    // Removes a block, as well as all the blocks that descend from it
+    fn remove_branch(&mut self, hash: &BlockHash) {
+        let mut removed = HashSet::from([*hash]);
+        let mut pending = vec![*hash];
+        while let Some(parent) = pending.pop() {
+            for (child, entry) in self.entries.iter() {
+                if entry.block.previous_hash == parent && removed.insert(*child) {
+                    pending.push(*child);
+                }
+            }
+        }
+
+        self.entries.retain(|hash, _| !removed.contains(hash));
+    }
+
+    fn contains_orphan(&self, hash: &BlockHash) -> bool {
+        self.orphans
+            .values()
+            .flatten()
+            .any(|orphan| orphan.block.hash == *hash)
+    }
+
+    fn count_orphans(&self) -> usize {
+        self.orphans.values().map(Vec::len).sum()
+    }
+
+    // Keeps a block until its previous block arrives, evicting the oldest orphans if there are too many
+    fn add_orphan(&mut self, block: Block, max_orphans: usize) {
+        while self.count_orphans() >= max_orphans {
+            self.remove_oldest_orphan();
+        }
+
+        let orphan = OrphanBlock {
+            block,
+            received_at: Instant::now(),
+        };
+        self.orphans
+            .entry(orphan.block.previous_hash)
+            .or_default()
+            .push(orphan);
+    }
+
+    fn remove_oldest_orphan(&mut self) {
+        let oldest = self
+            .orphans
+            .values()
+            .flatten()
+            .min_by_key(|orphan| orphan.received_at)
+            .map(|orphan| orphan.block.hash);
+
+        if let Some(oldest) = oldest {
+            for orphans in self.orphans.values_mut() {
+                orphans.retain(|orphan| orphan.block.hash != oldest);
+            }
+            self.orphans.retain(|_, orphans| !orphans.is_empty());
+        }
+    }
+
+    fn remove_expired_orphans(&mut self, expiry: Duration) {
+        for orphans in self.orphans.values_mut() {
+            orphans.retain(|orphan| orphan.received_at.elapsed() < expiry);
+        }
+        self.orphans.retain(|_, orphans| !orphans.is_empty());
+    }
+
+    // Returns the orphans that were waiting for the indicated block, removing them from the orphan pool
+    fn take_orphans(&mut self, previous_hash: &BlockHash) -> Vec<Block> {
+        self.orphans
+            .remove(previous_hash)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|orphan| orphan.block)
+            .collect()
+    }
 }
 
 // Struct that holds all the blocks in the blockhain
-// Multiple threads can read/write concurrently to the list of blocks
+// The main chain is the branch with the most cumulative proof of work,
+// but blocks of other branches are also kept in case they eventually have more work
+// Multiple threads can read/write concurrently to the blocks
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     pub difficulty: u32,
-    blocks: SyncedBlockVec,
-    account_balances: SyncedAccountBalanceVec,
+    pub difficulty_adjustment_interval: u64,
+    pub target_block_time_ms: u64,
+    max_orphan_blocks: usize,
+    orphan_expiry: Duration,
+    max_fork_depth: u64,
+    snapshot_interval: u64,
+    // where the transactions of the blocks removed from the main chain go back, if there is any
+    pool: Option<TransactionPool>,
+    state: SyncedChainState,
+    tip_generation: SyncedGeneration,
+    metrics: Metrics,
 }
 
 // Basic operations in the blockchain are encapsulated in the implementation
 // Encapsulates concurrency concerns, so external callers do not need to know how it's handled
 impl Blockchain {
     // Creates a brand new blockchain with a genesis block
+    // All blocks must have the indicated difficulty, unless difficulty adjustment is enabled
     pub fn new(difficulty: u32) -> Blockchain {
-        let genesis_block = Blockchain::create_genesis_block();
-
-        // add the genesis block to the synced vec of blocks
-        let blocks = vec![genesis_block];
-        let synced_blocks = Arc::new(Mutex::new(blocks));
-        let synced_account_balances = SyncedAccountBalanceVec::default();
+        let state = ChainState::new();
 
         Blockchain {
             difficulty,
-            blocks: synced_blocks,
-            account_balances: synced_account_balances,
+            difficulty_adjustment_interval: 0,
+            target_block_time_ms: 0,
+            max_orphan_blocks: MAX_ORPHAN_BLOCKS,
+            orphan_expiry: Duration::from_millis(ORPHAN_EXPIRY_MS),
+            max_fork_depth: MAX_FORK_DEPTH,
+            snapshot_interval: 0,
+            pool: None,
+            state: Arc::new(Mutex::new(state)),
+            tip_generation: SyncedGeneration::default(),
+            metrics: Metrics::new(),
+        }
+    }
+
+    // Reports the blocks rejected by the blockchain in the indicated metrics, instead of in its own ones
+    pub fn with_metrics(mut self, metrics: Metrics) -> Blockchain {
+        self.metrics = metrics;
+        self
+    }
+
+    // Enables the recalculation of the difficulty every "interval" blocks,
+    // so that blocks are mined at the indicated target time on average, regardless of the mining power of the network
+    // The initial difficulty of the blockchain is used until the first adjustment
+    pub fn with_difficulty_adjustment(
+        mut self,
+        interval: u64,
+        target_block_time_ms: u64,
+    ) -> Blockchain {
+        self.difficulty_adjustment_interval = interval;
+        self.target_block_time_ms = target_block_time_ms;
+        self
+    }
+
+    // Limits the amount of blocks kept while waiting for their previous block, and for how long they are kept
+    // A maximum of 0 disables the orphan pool, so blocks with an unknown previous block are rejected
+    pub fn with_orphan_limits(
+        mut self,
+        max_orphan_blocks: usize,
+        orphan_expiry_ms: u64,
+    ) -> Blockchain {
+        self.max_orphan_blocks = max_orphan_blocks;
+        self.orphan_expiry = Duration::from_millis(orphan_expiry_ms);
+        self
+    }
+
+    // Limits how many blocks below the last block of the main chain a side branch can start
+    // Old blocks only required the difficulty of their time, so without a limit new branches from them would be cheap
+    pub fn with_max_fork_depth(mut self, max_fork_depth: u64) -> Blockchain {
+        self.max_fork_depth = max_fork_depth;
+        self
+    }
+
+    // Persists the blocks in the indicated directory, so they are not lost when the node restarts
+    // The blocks already stored are validated and added again, rebuilding the account balances
+    // Every "snapshot_interval" blocks (0 to disable it) the account balances are also saved, so on restart
+    // only the blocks after the latest snapshot need to be processed
+    // It must be called after the rest of the settings, as they are needed to validate the stored blocks
+    pub fn with_block_store(
+        mut self,
+        data_dir: &Path,
+        snapshot_interval: u64,
+    ) -> Result<Blockchain> {
+        self.snapshot_interval = snapshot_interval;
+        let (store, blocks) = BlockStore::open(data_dir)?;
+        let mut state = self.state.lock().unwrap();
+
+        // start from the latest snapshot that matches the stored blocks
+        for snapshot in store.load_snapshots()? {
+            let index = snapshot.index;
+            match self.load_snapshot(&blocks, snapshot) {
+                Ok(snapshot_state) => {
+                    info!("loaded account snapshot at index {}", index);
+                    *state = snapshot_state;
+                    break;
+                }
+                Err(error) => warn!("ignoring account snapshot at index {}: {}", index, error),
+            }
+        }
+
+        // the store is not set yet, so the blocks are not written again
+        for block in blocks {
+            if state.entries.contains_key(&block.hash) {
+                continue;
+            }
+
+            let index = block.index;
+            if let Err(error) = self.add_block_to_state(&mut state, block) {
+                // a block of an invalid branch may have been stored before the branch was found invalid
+                warn!("ignoring stored block {}: {}", index, error);
+            }
+        }
+
+        info!(
+            "loaded {} blocks from the block store",
+            state.main_chain.len() - 1
+        );
+
+        state.store = Some(store);
+        drop(state);
+
+        Ok(self)
+    }
+
+    // Returns the transactions of the blocks removed from the main chain to the indicated pool, so they are mined again
+    // It must be called after loading the stored blocks, as the transactions of the blocks replaced while loading
+    // were already handled before the restart
+    pub fn with_transaction_pool(mut self, pool: TransactionPool) -> Blockchain {
+        self.pool = Some(pool);
+        self
+    }
+
+    // Builds the main chain up to the block of the snapshot, taking the account balances from the snapshot
+    // The blocks are validated, but their transactions are not processed again
+    fn load_snapshot(&self, blocks: &[Block], snapshot: AccountSnapshot) -> Result<ChainState> {
+        let mut state = ChainState::new();
+
+        // find the branch of the snapshot block among the stored blocks
+        let stored_blocks: HashMap<BlockHash, &Block> =
+            blocks.iter().map(|block| (block.hash, block)).collect();
+        let mut branch = Vec::new();
+        let mut hash = snapshot.hash;
+        while let Some(block) = stored_blocks.get(&hash) {
+            branch.push(*block);
+            hash = block.previous_hash;
+        }
+        if hash != state.main_chain[0] || branch.len() as u64 != snapshot.index {
+            return Err(BlockchainError::SnapshotBlockNotFound.into());
         }
+
+        for block in branch.into_iter().rev() {
+            Blockchain::validate_hashes(block)?;
+
+            let previous = &state.entries[&block.previous_hash];
+            self.validate_block(&state, block, &previous.block)?;
+
+            // the previous state of the accounts is unknown, so these blocks cannot be rolled back
+            let work = Blockchain::calculate_work(block.difficulty);
+            let entry = BlockEntry {
+                block: block.clone(),
+                cumulative_work: previous.cumulative_work.saturating_add(work),
+                saved_accounts: None,
+            };
+            state.entries.insert(block.hash, entry);
+            state.push_main_chain(block.hash);
+        }
+
+        state.account_balances = snapshot.account_balances;
+        state.last_snapshot_index = snapshot.index;
+
+        Ok(state)
     }
 
     fn create_genesis_block() -> Block {
         let index = 0;
+        let difficulty = 0;
         let nonce = 0;
         let previous_hash = BlockHash::default();
         let transactions = Vec::new();
 
-        let mut block = Block::new(index, nonce, previous_hash, transactions);
+        // the genesis block is not mined, so its difficulty does not depend on the configuration of the node
+        let mut block = Block::new(index, difficulty, nonce, previous_hash, transactions);
 
         // to easily sync multiple nodes in a network, the genesis blocks must match
         // so we clear the timestamp so the hash of the genesis block is predictable
@@ -82,199 +591,704 @@ impl Blockchain {
         block
     }
 
-    // Returns a copy of the most recent block in the blockchain
+    // Returns a copy of the most recent block in the main chain
     pub fn get_last_block(&self) -> Block {
-        let blocks = self.blocks.lock().unwrap();
+        let state = self.state.lock().unwrap();
 
-        blocks[blocks.len() - 1].clone()
+        state.get_last_entry().block.clone()
     }
 
-    // Returns a copy of the whole list of blocks
+    // Returns a copy of the whole list of blocks in the main chain
     pub fn get_all_blocks(&self) -> BlockVec {
-        let blocks = self.blocks.lock().unwrap();
+        let state = self.state.lock().unwrap();
 
-        blocks.clone()
+        state
+            .main_chain
+            .iter()
+            .map(|hash| state.entries[hash].block.clone())
+            .collect()
     }
 
-    // Tries to append a new block into the blockchain
-    // It will validate that the values of the new block are consistend with the blockchain state
-    // This operation is safe to be called concurrently from multiple threads
-    pub fn add_block(&self, block: Block) -> Result<()> {
-        // the "blocks" attribute is protected by a Mutex
-        // so only one thread at a time can access the value when the lock is held
-        // that prevents adding multiple valid blocks at the same time
-        // preserving the correct order of indexes and hashes of the blockchain
-        let mut blocks = self.blocks.lock().unwrap();
-        let last = &blocks[blocks.len() - 1];
+    // Returns a summary of the main chain, all taken at the same time
+    pub fn get_chain_info(&self) -> ChainInfo {
+        let state = self.state.lock().unwrap();
+        let last_entry = state.get_last_entry();
 
-        // check that the index is valid
-        if block.index != last.index + 1 {
-            return Err(BlockchainError::InvalidIndex.into());
+        ChainInfo {
+            height: last_entry.block.index,
+            tip_hash: last_entry.block.hash,
+            difficulty: self.calculate_next_difficulty(&state, &last_entry.block),
+            cumulative_work: last_entry.cumulative_work,
         }
+    }
 
-        // check that the previous_hash is valid
-        if block.previous_hash != last.hash {
-            return Err(BlockchainError::InvalidPreviousHash.into());
-        }
+    // Returns a copy of the blocks of the main chain starting at the indicated index, up to "limit" blocks
+    pub fn get_blocks(&self, from: u64, limit: usize) -> BlockVec {
+        let state = self.state.lock().unwrap();
+
+        state
+            .main_chain
+            .iter()
+            .skip(from.try_into().unwrap_or(usize::MAX))
+            .take(limit)
+            .map(|hash| state.entries[hash].block.clone())
+            .collect()
+    }
 
-        // check that the hash matches the data
-        if block.hash != block.calculate_hash() {
-            return Err(BlockchainError::InvalidHash.into());
-        }
+    // Returns a copy of the block of the main chain with the indicated index
+    pub fn get_block_by_index(&self, index: u64) -> Option<Block> {
+        let state = self.state.lock().unwrap();
 
-        // check that the difficulty is correct
-        if block.hash.leading_zeros() < self.difficulty {
-            return Err(BlockchainError::InvalidDifficulty.into());
+        let hash = state.main_chain.get(usize::try_from(index).ok()?)?;
+        Some(state.entries[hash].block.clone())
+    }
+
+    // Returns a copy of the block with the indicated hash, in the main chain or in any other branch
+    pub fn get_block(&self, hash: &BlockHash) -> Option<Block> {
+        let state = self.state.lock().unwrap();
+
+        state.entries.get(hash).map(|entry| entry.block.clone())
+    }
+
+    // Returns a copy of the transaction with the indicated hash, if it's included in the main chain
+    pub fn get_transaction(&self, hash: &TransactionHash) -> Option<ConfirmedTransaction> {
+        let state = self.state.lock().unwrap();
+
+        let block_hash = state.transaction_index.get(hash)?;
+        let position = state.entries[block_hash]
+            .block
+            .transactions
+            .iter()
+            .position(|transaction| transaction.hash() == *hash)?;
+        let location = TransactionLocation {
+            block_hash: *block_hash,
+            position,
+        };
+
+        Some(state.get_confirmed_transaction(&location))
+    }
+
+    // Returns the transactions of the main chain sent or received by the address, from the newest to the oldest
+    // Only the indicated range is returned, skipping the "offset" newest transactions
+    pub fn get_address_transactions(
+        &self,
+        address: &Address,
+        offset: usize,
+        limit: usize,
+    ) -> AddressTransactions {
+        let state = self.state.lock().unwrap();
+
+        let locations = match state.address_index.get(address) {
+            Some(locations) => locations.as_slice(),
+            None => &[],
+        };
+        let transactions = locations
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .map(|location| state.get_confirmed_transaction(location))
+            .collect();
+
+        AddressTransactions {
+            total: locations.len(),
+            transactions,
         }
+    }
 
-        // update the account balances by processing the block transactions
-        self.update_account_balances(&block.transactions)?;
+    // Checks if the block is already known, in the main chain or in any other branch
+    pub fn contains_block(&self, hash: &BlockHash) -> bool {
+        let state = self.state.lock().unwrap();
 
-        // append the block to the end
-        blocks.push(block);
+        state.entries.contains_key(hash)
+    }
 
-        Ok(())
+    // Returns a counter that changes every time the last block of the blockchain changes
+    // Long running tasks over the last block (like mining) can check it to know when their work is outdated,
+    // without the need of locking the list of blocks
+    pub fn get_tip_generation(&self) -> u64 {
+        self.tip_generation.load(Ordering::SeqCst)
     }
 
-    fn update_account_balances(&self, transactions: &[Transaction]) -> Result<()> {
-        let mut account_balances = self.account_balances.lock().unwrap();
-        // note that if any transaction (including coinbase) is invalid, an error will be returned before updating the balances
-        let new_account_balances =
-            Blockchain::calculate_new_account_balances(&account_balances, transactions)?;
-        *account_balances = new_account_balances;
+    // Returns the difficulty that the next block of the main chain must have
+    pub fn get_next_difficulty(&self) -> u32 {
+        let state = self.state.lock().unwrap();
+        let last_block = &state.get_last_entry().block;
 
-        Ok(())
+        self.calculate_next_difficulty(&state, last_block)
     }
 
-    fn calculate_new_account_balances(
-        account_balances: &AccountBalanceMap,
-        transactions: &[Transaction],
-    ) -> Result<AccountBalanceMap> {
-        // we work on a copy of the account balances
-        let mut new_account_balances = account_balances.clone();
-        let mut iter = transactions.iter();
+    // Returns a copy of the account balances after processing all the blocks of the main chain
+    pub fn get_account_balances(&self) -> AccountBalanceMap {
+        let state = self.state.lock().unwrap();
 
-        // the first transaction is always the coinbase transaction
-        // in which the miner receives the mining rewards
-        Blockchain::process_coinbase(&mut new_account_balances, iter.next())?;
+        state.account_balances.clone()
+    }
 
-        // the rest of the transactions are regular transfers between accounts
-        Blockchain::process_transfers(&mut new_account_balances, iter)?;
+    // Returns a copy of the state of a single account, which is empty for unknown addresses
+    pub fn get_account(&self, address: &Address) -> Account {
+        let state = self.state.lock().unwrap();
 
-        Ok(new_account_balances)
+        state.account_balances.get_account(address)
     }
 
-    fn process_coinbase(
-        account_balances: &mut AccountBalanceMap,
-        coinbase: Option<&Transaction>,
-    ) -> Result<()> {
-        // The coinbase transaction is required in a valid block
-        let coinbase = match coinbase {
-            Some(transaction) => transaction,
-            None => return Err(BlockchainError::CoinbaseTransactionNotFound.into()),
-        };
+    // Returns the account balances with only the indicated accounts, instead of copying all of them
+    // It's enough to validate the transactions sent from those accounts
+    pub fn get_accounts<'a>(
+        &self,
+        addresses: impl IntoIterator<Item = &'a Address>,
+    ) -> AccountBalanceMap {
+        let state = self.state.lock().unwrap();
+        let saved_accounts = state.account_balances.save_accounts(addresses);
+
+        let mut account_balances = AccountBalanceMap::default();
+        account_balances.restore_accounts(&saved_accounts);
+        account_balances
+    }
 
-        // In coinbase transactions, we only need to check that the amount is valid,
-        // because whoever provides a valid proof-of-work block can receive the new coins
-        let is_valid_amount = coinbase.amount == BLOCK_SUBSIDY;
-        if !is_valid_amount {
-            return Err(BlockchainError::InvalidCoinbaseAmount.into());
+    // Tries to add a new block into the blockchain
+    // It will validate that the values of the new block are consistent with the previous block, which can be
+    // the last block of the main chain or any other known block, creating a new branch
+    // If the branch of the new block has more work than the main chain, it becomes the main chain
+    // If the previous block is unknown, the new block is kept as an orphan and added as soon as its previous block is
+    // This operation is safe to be called concurrently from multiple threads
+    pub fn add_block(&self, block: Block) -> Result<BlockStatus> {
+        // the "state" attribute is protected by a Mutex
+        // so only one thread at a time can access the value when the lock is held
+        // that prevents adding multiple valid blocks at the same time
+        // preserving the correct order of indexes and hashes of the blockchain
+        let mut state = self.state.lock().unwrap();
+
+        let result = self.add_block_to_state(&mut state, block);
+        if let Err(error) = &result {
+            let reason = Blockchain::get_rejection_reason(error);
+            self.metrics
+                .blocks_rejected
+                .with_label_values(&[&reason])
+                .inc();
         }
 
-        // The amount is valid so we add the new coins to the miner's address
-        account_balances.add_amount(&coinbase.recipient, coinbase.amount);
-
-        Ok(())
+        result
     }
 
-    fn process_transfers(
-        new_account_balances: &mut AccountBalanceMap,
-        transaction_iter: Iter<Transaction>,
-    ) -> Result<()> {
-        // each transaction is validated using the updated account balances from previous transactions
-        // that means that we allow multiple transacions from the same address in the same block
-        // as long as they are consistent
-        for tx in transaction_iter {
-            new_account_balances.transfer(&tx.sender, &tx.recipient, tx.amount)?
+    // Name of the error that caused a block to be rejected, to group the rejections in the metrics
+    fn get_rejection_reason(error: &anyhow::Error) -> String {
+        if let Some(error) = error.downcast_ref::<BlockchainError>() {
+            return format!("{:?}", error);
+        }
+        if let Some(error) = error.downcast_ref::<AccountBalanceMapError>() {
+            return format!("{:?}", error);
+        }
+        if let Some(error) = error.downcast_ref::<TransactionError>() {
+            return format!("{:?}", error);
         }
 
-        Ok(())
+        // e.g. the block could not be written in the block store
+        "Other".to_string()
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::model::{
-        account_balance_map::AccountBalanceMapError,
-        test_util::{alice, bob, carol},
-        Address, Transaction,
-    };
 
-    use super::*;
+    fn add_block_to_state(&self, state: &mut ChainState, block: Block) -> Result<BlockStatus> {
+        state.remove_expired_orphans(self.orphan_expiry);
 
-    const NO_DIFFICULTY: u32 = 0;
+        let hash = block.hash;
+        let status = self.connect_block(state, block)?;
+        if status != BlockStatus::Orphan {
+            self.connect_orphans(state, hash);
+        }
 
-    #[test]
-    fn should_have_valid_genesis_block() {
-        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        Ok(status)
+    }
 
-        // check that a new blockchain has one and only one block
-        let blocks = blockchain.get_all_blocks();
-        assert_eq!(blocks.len(), 1);
+    // Adds all the orphans that descend from the indicated block, which was just added to the blockchain
+    fn connect_orphans(&self, state: &mut ChainState, hash: BlockHash) {
+        let mut pending = vec![hash];
+        while let Some(previous_hash) = pending.pop() {
+            for orphan in state.take_orphans(&previous_hash) {
+                let index = orphan.index;
+                let hash = orphan.hash;
+                match self.connect_block(state, orphan) {
+                    Ok(_) => {
+                        info!("connected orphan block {} to the blockchain", index);
+                        pending.push(hash);
+                    }
+                    Err(error) => warn!("discarded orphan block {}: {}", index, error),
+                }
+            }
+        }
+    }
 
-        // check that the last block is in the blockchain
-        let block = blockchain.get_last_block();
-        assert_eq!(block.hash, blocks[0].hash);
+    // Validates a block against its previous block and adds it to the branch it belongs to
+    fn connect_block(&self, state: &mut ChainState, block: Block) -> Result<BlockStatus> {
+        Blockchain::validate_hashes(&block)?;
 
-        // check that the genesis block has valid values
-        assert_eq!(block.index, 0);
-        assert_eq!(block.nonce, 0);
-        assert_eq!(block.previous_hash, BlockHash::default());
-        assert!(block.transactions.is_empty());
-    }
+        // the difficulty depends on the timestamps, so they cannot be set freely by the miner
+        if block.timestamp > Utc::now().timestamp_millis() + MAX_FUTURE_BLOCK_TIME_MS {
+            return Err(BlockchainError::TimestampTooNew.into());
+        }
 
-    #[test]
-    fn should_let_adding_valid_blocks() {
-        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        if state.entries.contains_key(&block.hash) || state.contains_orphan(&block.hash) {
+            return Err(BlockchainError::BlockAlreadyExists.into());
+        }
 
-        // create a valid block
-        let previous_hash = blockchain.get_last_block().hash;
-        let coinbase = Transaction {
-            sender: Address::default(), // sender is ignored in coinbases
-            recipient: bob(),
-            amount: BLOCK_SUBSIDY,
+        // check that the previous_hash is valid, or keep the block until the previous one arrives
+        let previous = match state.entries.get(&block.previous_hash) {
+            Some(entry) => entry,
+            None => return self.add_orphan(state, block),
         };
-        let tx1 = Transaction {
-            sender: bob(),
-            recipient: alice(),
-            amount: 5,
-        };
-        let tx2 = Transaction {
-            sender: alice(),
-            recipient: bob(),
-            amount: 5,
+        self.validate_block(state, &block, &previous.block)?;
+
+        // side branches must start close to the last block, so they cost about as much work as the main chain
+        // it's checked before storing the block, so cheap branches from old blocks cannot fill the memory or disk
+        let last_index = state.get_last_entry().block.index;
+        if last_index - state.get_fork_index(&previous.block) > self.max_fork_depth {
+            return Err(BlockchainError::ForkTooDeep.into());
+        }
+
+        // keep the block, as its branch may become the main chain now or in the future
+        let hash = block.hash;
+        let work = Blockchain::calculate_work(block.difficulty);
+        let cumulative_work = previous.cumulative_work.saturating_add(work);
+        if let Some(store) = state.store.as_mut() {
+            store.append(&block)?;
+        }
+        let entry = BlockEntry {
+            block,
+            cumulative_work,
+            saved_accounts: None,
         };
-        let block = Block::new(1, 0, previous_hash, vec![coinbase, tx1, tx2]);
+        state.entries.insert(hash, entry);
 
-        // add it to the blockchain and check it was really added
-        let result = blockchain.add_block(block.clone());
-        assert!(result.is_ok());
+        // in case of a tie, we keep the branch that we received first
+        if cumulative_work <= state.get_last_entry().cumulative_work {
+            return Ok(BlockStatus::SideChain);
+        }
 
-        let blocks = blockchain.get_all_blocks();
-        assert_eq!(blocks.len(), 2);
+        self.switch_main_chain(state, hash)?;
 
-        let last_block = blockchain.get_last_block();
-        assert_eq!(last_block.hash, block.hash);
+        Ok(BlockStatus::MainChain)
     }
 
-    #[test]
-    fn should_not_let_adding_block_with_invalid_index() {
-        let blockchain = Blockchain::new(NO_DIFFICULTY);
+    // Checks that the hash matches the header, and that the merkle root in the header matches the transactions
+    fn validate_hashes(block: &Block) -> Result<()> {
+        if block.hash != block.calculate_hash() {
+            return Err(BlockchainError::InvalidHash.into());
+        }
 
-        // create a block with invalid index
+        if block.merkle_root != block.calculate_merkle_root() {
+            return Err(BlockchainError::InvalidMerkleRoot.into());
+        }
+
+        Ok(())
+    }
+
+    // Checks the values of a block that depend on its previous block
+    fn validate_block(&self, state: &ChainState, block: &Block, previous: &Block) -> Result<()> {
+        // check that the index is valid
+        if block.index != previous.index + 1 {
+            return Err(BlockchainError::InvalidIndex.into());
+        }
+
+        // check that the block is not older than the previous ones, so the difficulty cannot be raised at will
+        if block.timestamp <= state.get_median_timestamp(previous) {
+            return Err(BlockchainError::TimestampTooOld.into());
+        }
+
+        // check that the difficulty is the one required by the previous blocks, and that the hash satisfies it
+        if block.difficulty != self.calculate_next_difficulty(state, previous) {
+            return Err(BlockchainError::InvalidDifficulty.into());
+        }
+        if block.hash.leading_zeros() < block.difficulty {
+            return Err(BlockchainError::InvalidDifficulty.into());
+        }
+
+        // check that the coinbase commits to the index of the block, so it cannot be the same as in another block
+        // (a block without coinbase is rejected when processing its transactions)
+        if let Some(coinbase) = block.transactions.first() {
+            if coinbase.nonce >> EXTRA_NONCE_BITS != block.index {
+                return Err(BlockchainError::InvalidCoinbaseNonce.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Keeps a block whose previous block is unknown
+    // Only the checks that do not depend on the previous blocks can be done, the rest are done when it gets connected
+    fn add_orphan(&self, state: &mut ChainState, block: Block) -> Result<BlockStatus> {
+        if self.max_orphan_blocks == 0 {
+            return Err(BlockchainError::InvalidPreviousHash.into());
+        }
+
+        // the genesis block is the only one without a previous block
+        if block.index == 0 {
+            return Err(BlockchainError::InvalidIndex.into());
+        }
+
+        // the difficulty of the block is chosen by its sender, so it must be at least the one required after our last block
+        // otherwise anyone could fill the orphans with blocks that cost nothing, evicting the real ones
+        // (orphans from after a decrease of the difficulty are rejected, but they are received again when syncing)
+        let min_difficulty = self.calculate_next_difficulty(state, &state.get_last_entry().block);
+        if block.difficulty < min_difficulty {
+            return Err(BlockchainError::InvalidDifficulty.into());
+        }
+
+        // and the hash must satisfy it, so orphans are as expensive to create as any other block
+        if block.hash.leading_zeros() < block.difficulty {
+            return Err(BlockchainError::InvalidDifficulty.into());
+        }
+
+        state.add_orphan(block, self.max_orphan_blocks);
+
+        Ok(BlockStatus::Orphan)
+    }
+
+    // Makes the branch that ends with the indicated block the main chain
+    // The blocks of the current main chain that are not in the branch are rolled back,
+    // and then the blocks of the branch are processed in order
+    // If any block of the branch is invalid, it's discarded (along with its descendants) and the main chain does not change
+    fn switch_main_chain(&self, state: &mut ChainState, new_last_hash: BlockHash) -> Result<()> {
+        // find the blocks of the branch that are not in the main chain, from the oldest to the newest
+        let mut branch = Vec::new();
+        let mut hash = new_last_hash;
+        while !state.is_in_main_chain(&hash) {
+            branch.push(hash);
+            hash = state.entries[&hash].block.previous_hash;
+        }
+        branch.reverse();
+        let fork_index = state.entries[&hash].block.index as usize;
+
+        // we work on a copy of the account balances, so nothing changes if the branch is invalid
+        let disconnected = state.main_chain.split_off(fork_index + 1);
+        let can_roll_back = disconnected
+            .iter()
+            .all(|hash| state.entries[hash].saved_accounts.is_some());
+        let mut account_balances = if can_roll_back {
+            let mut account_balances = state.account_balances.clone();
+            for hash in disconnected.iter().rev() {
+                let saved_accounts = state.entries[hash].saved_accounts.as_ref().unwrap();
+                account_balances.restore_accounts(saved_accounts);
+            }
+            account_balances
+        } else {
+            // the blocks loaded from a snapshot cannot be rolled back, so we process the main chain again
+            match Blockchain::replay_main_chain(state) {
+                Ok(account_balances) => account_balances,
+                Err(error) => {
+                    state.main_chain.extend(disconnected);
+                    return Err(error);
+                }
+            }
+        };
+
+        let mut branch_saved_accounts = Vec::new();
+        for hash in branch.iter() {
+            let transactions = &state.entries[hash].block.transactions;
+            match Blockchain::process_block(&mut account_balances, transactions) {
+                Ok(saved_accounts) => branch_saved_accounts.push(saved_accounts),
+                Err(error) => {
+                    state.main_chain.extend(disconnected);
+                    state.remove_branch(hash);
+                    return Err(error);
+                }
+            }
+        }
+
+        // the branch is valid, so it replaces the previous blocks in the main chain
+        for hash in disconnected.iter().rev() {
+            state.unindex_transactions(hash);
+        }
+        for (hash, saved_accounts) in branch.into_iter().zip(branch_saved_accounts) {
+            state.entries.get_mut(&hash).unwrap().saved_accounts = Some(saved_accounts);
+            state.push_main_chain(hash);
+        }
+        state.account_balances = account_balances;
+
+        // the transactions in the removed blocks (except coinbases) are not in the main chain anymore
+        if !disconnected.is_empty() {
+            info!(
+                "chain reorganization at index {}, {} blocks removed from the main chain",
+                fork_index + 1,
+                disconnected.len()
+            );
+        }
+        // the pool validates them against the new balances, so the ones included in the new branch are dropped
+        if let Some(pool) = &self.pool {
+            let transfers: TransactionVec = disconnected
+                .iter()
+                .flat_map(|hash| state.entries[hash].block.transactions.iter().skip(1))
+                .cloned()
+                .collect();
+            pool.requeue(transfers, &state.account_balances);
+        }
+
+        // notify that there is a new last block, while still holding the lock
+        // so the counter never falls behind the main chain
+        self.tip_generation.fetch_add(1, Ordering::SeqCst);
+
+        self.save_snapshot_if_needed(state);
+
+        Ok(())
+    }
+
+    // Calculates the account balances by processing all the blocks of the main chain from the start,
+    // saving the previous state of the accounts of each block so they can be rolled back later
+    fn replay_main_chain(state: &mut ChainState) -> Result<AccountBalanceMap> {
+        let mut account_balances = AccountBalanceMap::default();
+
+        // the genesis block has no transactions
+        for hash in state.main_chain.iter().skip(1) {
+            let entry = state.entries.get_mut(hash).unwrap();
+            let saved_accounts =
+                Blockchain::process_block(&mut account_balances, &entry.block.transactions)?;
+            entry.saved_accounts = Some(saved_accounts);
+        }
+
+        Ok(account_balances)
+    }
+
+    // Saves the account balances every "snapshot_interval" blocks of the main chain
+    // Failing to save a snapshot only makes the next restart slower, so the error is just logged
+    fn save_snapshot_if_needed(&self, state: &mut ChainState) {
+        let store = match state.store.as_ref() {
+            Some(store) if self.snapshot_interval > 0 => store,
+            _ => return,
+        };
+
+        let last_block = &state.get_last_entry().block;
+        if last_block.index < state.last_snapshot_index + self.snapshot_interval {
+            return;
+        }
+
+        let snapshot = AccountSnapshot {
+            index: last_block.index,
+            hash: last_block.hash,
+            account_balances: state.account_balances.clone(),
+        };
+        match store.save_snapshot(&snapshot) {
+            Ok(()) => state.last_snapshot_index = snapshot.index,
+            Err(error) => error!(
+                "could not save account snapshot at index {}: {}",
+                snapshot.index, error
+            ),
+        }
+    }
+
+    // The expected amount of hashes needed to mine a block, which doubles with each extra leading zero required
+    fn calculate_work(difficulty: u32) -> U256 {
+        if difficulty >= MAX_DIFFICULTY {
+            return U256::MAX;
+        }
+
+        U256::one() << difficulty
+    }
+
+    // Calculates the difficulty of the block that follows the indicated one
+    // It only depends on the previous blocks, so all nodes agree on the difficulty of every block
+    // At the start of each interval, the difficulty increases if the blocks of the last interval were mined too fast
+    // (in less than half the expected time), or decreases if they were too slow (more than double the expected time)
+    fn calculate_next_difficulty(&self, state: &ChainState, last: &Block) -> u32 {
+        // the genesis block is not mined, so the first block uses the initial difficulty
+        if last.index == 0 {
+            return self.difficulty;
+        }
+
+        let interval = self.difficulty_adjustment_interval;
+        let next_index = last.index + 1;
+        if interval == 0 || !next_index.is_multiple_of(interval) {
+            return last.difficulty;
+        }
+
+        // the window cannot start at the genesis block, as it does not have a real timestamp
+        if next_index < interval + 2 {
+            return last.difficulty;
+        }
+
+        let first = state.get_ancestor(last, interval);
+        let elapsed_ms = last.timestamp.saturating_sub(first.timestamp);
+        let expected_ms = interval.saturating_mul(self.target_block_time_ms) as i64;
+
+        if elapsed_ms < expected_ms / 2 {
+            (last.difficulty + 1).min(MAX_DIFFICULTY)
+        } else if elapsed_ms > expected_ms.saturating_mul(2) {
+            last.difficulty.saturating_sub(1)
+        } else {
+            last.difficulty
+        }
+    }
+
+    // Updates the account balances with the transactions of a block
+    // Returns the previous state of the modified accounts, so the block can be rolled back later
+    // If any transaction (including coinbase) is invalid, an error will be returned before updating the balances
+    fn process_block(
+        account_balances: &mut AccountBalanceMap,
+        transactions: &[Transaction],
+    ) -> Result<SavedAccounts> {
+        let new_account_balances =
+            Blockchain::calculate_new_account_balances(account_balances, transactions)?;
+
+        let modified_accounts: HashSet<&Address> = transactions
+            .iter()
+            .flat_map(|tx| [&tx.sender, &tx.recipient])
+            .collect();
+        let saved_accounts = account_balances.save_accounts(modified_accounts);
+        *account_balances = new_account_balances;
+
+        Ok(saved_accounts)
+    }
+
+    fn calculate_new_account_balances(
+        account_balances: &AccountBalanceMap,
+        transactions: &[Transaction],
+    ) -> Result<AccountBalanceMap> {
+        // we work on a copy of the account balances
+        let mut new_account_balances = account_balances.clone();
+        let mut iter = transactions.iter();
+
+        // the first transaction is always the coinbase transaction
+        // in which the miner receives the mining rewards
+        let coinbase = iter.next();
+        let transfers = iter.as_slice();
+        Blockchain::process_coinbase(&mut new_account_balances, coinbase, transfers)?;
+
+        // the rest of the transactions are regular transfers between accounts
+        Blockchain::process_transfers(&mut new_account_balances, iter)?;
+
+        Ok(new_account_balances)
+    }
+
+    // Returns the amount that a coinbase transaction must have in a block with the indicated transfers
+    // Miners receive the block subsidy plus the fees of all the transfers included in the block
+    pub fn calculate_coinbase_amount(transfers: &[Transaction]) -> Result<u64> {
+        transfers
+            .iter()
+            .try_fold(BLOCK_SUBSIDY, |total, tx| total.checked_add(tx.fee))
+            .ok_or_else(|| BlockchainError::InvalidCoinbaseAmount.into())
+    }
+
+    fn process_coinbase(
+        account_balances: &mut AccountBalanceMap,
+        coinbase: Option<&Transaction>,
+        transfers: &[Transaction],
+    ) -> Result<()> {
+        // The coinbase transaction is required in a valid block
+        let coinbase = match coinbase {
+            Some(transaction) => transaction,
+            None => return Err(BlockchainError::CoinbaseTransactionNotFound.into()),
+        };
+
+        // In coinbase transactions, we only need to check that the amount is valid,
+        // because whoever provides a valid proof-of-work block can receive the new coins
+        let is_valid_amount = coinbase.amount == Blockchain::calculate_coinbase_amount(transfers)?;
+        if !is_valid_amount {
+            return Err(BlockchainError::InvalidCoinbaseAmount.into());
+        }
+
+        // The amount is valid so we add the new coins to the miner's address
+        account_balances.add_amount(&coinbase.recipient, coinbase.amount);
+
+        Ok(())
+    }
+
+    fn process_transfers(
+        new_account_balances: &mut AccountBalanceMap,
+        transaction_iter: Iter<Transaction>,
+    ) -> Result<()> {
+        // each transaction is validated using the updated account balances from previous transactions
+        // that means that we allow multiple transacions from the same address in the same block
+        // as long as they are consistent
+        for tx in transaction_iter {
+            // only the owner of the sender address can spend its funds
+            tx.verify_signature()?;
+            new_account_balances.transfer(&tx.sender, &tx.recipient, tx.amount, tx.fee, tx.nonce)?
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{
+        account_balance_map::AccountBalanceMapError,
+        test_util::{
+            alice, alice_keypair, bob, bob_keypair, carol, carol_keypair, create_temp_dir,
+        },
+        transaction::TransactionError,
+        Address, PublicKey, Signature, Transaction,
+    };
+
+    use super::*;
+
+    const NO_DIFFICULTY: u32 = 0;
+    const NO_SNAPSHOTS: u64 = 0;
+
+    #[test]
+    fn should_have_valid_genesis_block() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        // check that a new blockchain has one and only one block
+        let blocks = blockchain.get_all_blocks();
+        assert_eq!(blocks.len(), 1);
+
+        // check that the last block is in the blockchain
+        let block = blockchain.get_last_block();
+        assert_eq!(block.hash, blocks[0].hash);
+
+        // check that the genesis block has valid values
+        assert_eq!(block.index, 0);
+        assert_eq!(block.nonce, 0);
+        assert_eq!(block.previous_hash, BlockHash::default());
+        assert!(block.transactions.is_empty());
+    }
+
+    #[test]
+    fn should_let_adding_valid_blocks() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        // create a valid block
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let tx1 = bob_keypair().create_transaction(alice(), 5, 0);
+        let tx2 = alice_keypair().create_transaction(bob(), 5, 0);
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase, tx1, tx2]);
+
+        // add it to the blockchain and check it was really added
+        let result = blockchain.add_block(block.clone());
+        assert!(result.is_ok());
+
+        let blocks = blockchain.get_all_blocks();
+        assert_eq!(blocks.len(), 2);
+
+        let last_block = blockchain.get_last_block();
+        assert_eq!(last_block.hash, block.hash);
+    }
+
+    #[test]
+    fn should_change_tip_generation_only_when_adding_blocks() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let generation = blockchain.get_tip_generation();
+
+        // an invalid block does not change the last block
+        let previous_hash = blockchain.get_last_block().hash;
+        let invalid_block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, Vec::new());
+        assert!(blockchain.add_block(invalid_block).is_err());
+        assert_eq!(blockchain.get_tip_generation(), generation);
+
+        // a valid one does
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase]);
+        blockchain.add_block(block).unwrap();
+        assert_ne!(blockchain.get_tip_generation(), generation);
+    }
+
+    #[test]
+    fn should_not_let_adding_block_with_invalid_index() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        // create a block with invalid index
         let invalid_index = 2;
         let previous_hash = blockchain.get_last_block().hash;
-        let block = Block::new(invalid_index, 0, previous_hash, Vec::new());
+        let block = Block::new(invalid_index, NO_DIFFICULTY, 0, previous_hash, Vec::new());
 
         // try adding the invalid block, it should return an error
         let result = blockchain.add_block(block.clone());
@@ -282,12 +1296,57 @@ mod tests {
     }
 
     #[test]
-    fn should_not_let_adding_block_with_invalid_previous_hash() {
+    fn should_keep_block_with_unknown_previous_hash_as_orphan() {
         let blockchain = Blockchain::new(NO_DIFFICULTY);
 
+        // create a block with unknown previous hash
+        let unknown_previous_hash = BlockHash::default();
+        let block = Block::new(1, NO_DIFFICULTY, 0, unknown_previous_hash, Vec::new());
+
+        // it's kept as an orphan, out of the blockchain
+        let result = blockchain.add_block(block.clone());
+        assert_eq!(result.unwrap(), BlockStatus::Orphan);
+        assert!(!blockchain.contains_block(&block.hash));
+        assert_eq!(blockchain.get_all_blocks().len(), 1);
+
+        // adding it again is not allowed
+        let result = blockchain.add_block(block);
+        assert_err(result, BlockchainError::BlockAlreadyExists);
+
+        // a block with index 0 can never be connected, as only the genesis block has no previous block
+        let block = Block::new(0, NO_DIFFICULTY, 0, unknown_previous_hash, Vec::new());
+        let result = blockchain.add_block(block);
+        assert_err(result, BlockchainError::InvalidIndex);
+    }
+
+    #[test]
+    fn should_not_keep_orphans_below_the_required_difficulty() {
+        let difficulty = 1;
+        let blockchain = Blockchain::new(difficulty);
+
+        // the sender chooses the difficulty of the block, but it cannot be lower than the one of the chain
+        let unknown_previous_hash = BlockHash::default();
+        let block = Block::new(1, NO_DIFFICULTY, 0, unknown_previous_hash, Vec::new());
+        let result = blockchain.add_block(block);
+        assert_err(result, BlockchainError::InvalidDifficulty);
+
+        // an orphan with the required difficulty is kept
+        let mut block = Block::new(1, difficulty, 0, unknown_previous_hash, Vec::new());
+        while block.hash.leading_zeros() < difficulty {
+            block.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        let result = blockchain.add_block(block);
+        assert_eq!(result.unwrap(), BlockStatus::Orphan);
+    }
+
+    #[test]
+    fn should_not_let_adding_block_with_invalid_previous_hash_without_orphans() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY).with_orphan_limits(0, ORPHAN_EXPIRY_MS);
+
         // create a block with invalid previous hash
         let invalid_previous_hash = BlockHash::default();
-        let block = Block::new(1, 0, invalid_previous_hash, Vec::new());
+        let block = Block::new(1, NO_DIFFICULTY, 0, invalid_previous_hash, Vec::new());
 
         // try adding the invalid block, it should return an error
         let result = blockchain.add_block(block.clone());
@@ -300,7 +1359,7 @@ mod tests {
 
         // create a block with invalid hash
         let previous_hash = blockchain.get_last_block().hash;
-        let mut block = Block::new(1, 0, previous_hash, Vec::new());
+        let mut block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, Vec::new());
         block.hash = BlockHash::default();
 
         // try adding the invalid block, it should return an error
@@ -308,15 +1367,53 @@ mod tests {
         assert_err(result, BlockchainError::InvalidHash);
     }
 
+    #[test]
+    fn should_count_rejected_blocks_by_reason() {
+        let metrics = Metrics::new();
+        let blockchain = Blockchain::new(NO_DIFFICULTY).with_metrics(metrics.clone());
+        let previous_hash = blockchain.get_last_block().hash;
+
+        let mut block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, Vec::new());
+        block.hash = BlockHash::default();
+        blockchain.add_block(block).unwrap_err();
+
+        // blocks without coinbase are rejected when processing their transactions
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, Vec::new());
+        blockchain.add_block(block.clone()).unwrap_err();
+        blockchain.add_block(block).unwrap_err();
+
+        let text = metrics.encode();
+        assert!(text.contains("blockchain_blocks_rejected_total{reason=\"InvalidHash\"} 1"));
+        assert!(text.contains(
+            "blockchain_blocks_rejected_total{reason=\"CoinbaseTransactionNotFound\"} 2"
+        ));
+    }
+
+    #[test]
+    fn should_not_let_adding_block_with_invalid_merkle_root() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        // the transactions of the block are changed, and the hash is recalculated without changing the merkle root
+        let previous_hash = blockchain.get_last_block().hash;
+        let mut block = create_block_with_coinbase(&previous_hash, 1, bob());
+        block.transactions[0].recipient = carol();
+        block.hash = block.calculate_hash();
+
+        // try adding the invalid block, it should return an error
+        let result = blockchain.add_block(block.clone());
+        assert_err(result, BlockchainError::InvalidMerkleRoot);
+    }
+
     #[test]
     fn should_not_let_adding_block_with_invalid_difficulty() {
         // set up a blockchain with an insane difficulty
         let difficulty: u32 = 30;
         let blockchain = Blockchain::new(difficulty);
 
-        // create a valid block
+        // create a block with the required difficulty
         let previous_hash = blockchain.get_last_block().hash;
-        let block = Block::new(1, 0, previous_hash, Vec::new());
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let block = Block::new(1, difficulty, 0, previous_hash, vec![coinbase.clone()]);
 
         // ensure that the hash actually does NOT meet the difficulty
         assert!(block.hash.leading_zeros() < difficulty);
@@ -324,6 +1421,118 @@ mod tests {
         // try adding the invalid block, it should return an error
         let result = blockchain.add_block(block.clone());
         assert_err(result, BlockchainError::InvalidDifficulty);
+
+        // a block that claims a lower difficulty is not valid either
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase]);
+        let result = blockchain.add_block(block);
+        assert_err(result, BlockchainError::InvalidDifficulty);
+    }
+
+    #[test]
+    fn should_not_let_adding_block_with_timestamp_before_the_median() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let now = Utc::now().timestamp_millis();
+        for (index, timestamp) in [(1, now - 30), (2, now - 20), (3, now - 10)] {
+            let last_block = blockchain.get_last_block();
+            let block = create_block_with_timestamp(&last_block.hash, index, timestamp);
+            blockchain.add_block(block).unwrap();
+        }
+
+        // the median of the genesis block and the three new ones is the timestamp of the second one
+        let last_block = blockchain.get_last_block();
+        let block = create_block_with_timestamp(&last_block.hash, 4, now - 20);
+        let result = blockchain.add_block(block);
+        assert_err(result, BlockchainError::TimestampTooOld);
+
+        // a block older than the last one is still valid if it's after the median
+        let block = create_block_with_timestamp(&last_block.hash, 4, now - 15);
+        assert!(blockchain.add_block(block).is_ok());
+    }
+
+    #[test]
+    fn should_not_let_adding_block_with_timestamp_too_far_in_the_future() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let previous_hash = blockchain.get_last_block().hash;
+
+        let timestamp = Utc::now().timestamp_millis() + MAX_FUTURE_BLOCK_TIME_MS + 60_000;
+        let block = create_block_with_timestamp(&previous_hash, 1, timestamp);
+        let result = blockchain.add_block(block);
+        assert_err(result, BlockchainError::TimestampTooNew);
+
+        // a block slightly ahead of the local time is fine, as the clocks of the nodes are never exactly the same
+        let timestamp = Utc::now().timestamp_millis() + 60_000;
+        let block = create_block_with_timestamp(&previous_hash, 1, timestamp);
+        assert!(blockchain.add_block(block).is_ok());
+    }
+
+    #[test]
+    fn should_keep_difficulty_without_adjustment() {
+        let blockchain = Blockchain::new(1);
+
+        // no matter how fast the blocks are mined, the difficulty never changes
+        for _ in 0..5 {
+            assert_eq!(blockchain.get_next_difficulty(), 1);
+            add_mined_block(&blockchain, 1);
+        }
+    }
+
+    #[test]
+    fn should_increase_difficulty_when_blocks_are_too_fast() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY).with_difficulty_adjustment(2, 1_000);
+
+        // the first window starts after the genesis block
+        for _ in 0..3 {
+            assert_eq!(blockchain.get_next_difficulty(), NO_DIFFICULTY);
+            add_mined_block(&blockchain, 1);
+        }
+
+        // the blocks were mined instantly, so the difficulty must increase at the start of the interval
+        assert_eq!(blockchain.get_next_difficulty(), 1);
+        add_mined_block(&blockchain, 1);
+
+        // and stay the same during the rest of the interval
+        assert_eq!(blockchain.get_next_difficulty(), 1);
+    }
+
+    #[test]
+    fn should_decrease_difficulty_when_blocks_are_too_slow() {
+        let blockchain = Blockchain::new(2).with_difficulty_adjustment(2, 1_000);
+
+        // each block takes 10 times the target block time
+        for _ in 0..3 {
+            assert_eq!(blockchain.get_next_difficulty(), 2);
+            add_mined_block(&blockchain, 10_000);
+        }
+
+        assert_eq!(blockchain.get_next_difficulty(), 1);
+    }
+
+    #[test]
+    fn should_keep_difficulty_when_blocks_are_on_time() {
+        let blockchain = Blockchain::new(1).with_difficulty_adjustment(2, 1_000);
+
+        for _ in 0..3 {
+            add_mined_block(&blockchain, 1_000);
+        }
+
+        assert_eq!(blockchain.get_next_difficulty(), 1);
+    }
+
+    #[test]
+    fn should_get_single_accounts() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let previous_hash = blockchain.get_last_block().hash;
+        let block = create_block_with_coinbase(&previous_hash, 1, bob());
+        blockchain.add_block(block).unwrap();
+
+        let account = blockchain.get_account(&bob());
+        assert_eq!(account.balance, BLOCK_SUBSIDY);
+        assert_eq!(blockchain.get_account(&alice()), Account::default());
+
+        // only the requested accounts are copied, unknown ones are left out
+        let account_balances = blockchain.get_accounts([&bob(), &alice()]);
+        assert_eq!(account_balances.get_account(&bob()), account);
+        assert!(!account_balances.contains(&alice()));
     }
 
     #[test]
@@ -332,7 +1541,7 @@ mod tests {
 
         // create a block without a coinbase
         let previous_hash = blockchain.get_last_block().hash;
-        let block = Block::new(1, 0, previous_hash, vec![]);
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![]);
 
         // try adding the invalid block, it should return an error
         let result = blockchain.add_block(block.clone());
@@ -345,18 +1554,99 @@ mod tests {
 
         // create a block with an invalid coinbase amount
         let previous_hash = blockchain.get_last_block().hash;
-        let coinbase = Transaction {
-            sender: Address::default(),
-            recipient: Address::default(),
-            amount: BLOCK_SUBSIDY + 1,
-        };
-        let block = Block::new(1, 0, previous_hash, vec![coinbase]);
+        let coinbase = create_coinbase(1, Address::default(), BLOCK_SUBSIDY + 1);
+        let block = create_block(&previous_hash, 1, vec![coinbase]);
 
         // try adding the invalid block, it should return an error
         let result = blockchain.add_block(block.clone());
         assert_err(result, BlockchainError::InvalidCoinbaseAmount);
     }
 
+    #[test]
+    fn should_require_the_block_index_in_the_coinbase_nonce() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let previous_hash = blockchain.get_last_block().hash;
+
+        // the coinbase of a block with a different index, or without any index
+        for nonce in [2 << EXTRA_NONCE_BITS, 1] {
+            let mut coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+            coinbase.nonce = nonce;
+            let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase]);
+
+            let result = blockchain.add_block(block);
+            assert_err(result, BlockchainError::InvalidCoinbaseNonce);
+        }
+
+        // the extra nonce in the lower bits can be anything
+        let mut coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        coinbase.nonce = (1 << EXTRA_NONCE_BITS) + 5;
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase]);
+        assert_eq!(blockchain.add_block(block).unwrap(), BlockStatus::MainChain);
+    }
+
+    #[test]
+    fn should_pay_fees_to_the_miner() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        // bob mines the first block, so he can pay fees in the next one
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase]);
+        blockchain.add_block(block).unwrap();
+
+        // carol mines a block with two transactions of bob, collecting their fees
+        let previous_hash = blockchain.get_last_block().hash;
+        let tx1 = bob_keypair().create_transaction_with_fee(alice(), 10, 2, 0);
+        let tx2 = bob_keypair().create_transaction_with_fee(alice(), 10, 3, 1);
+        let coinbase = create_coinbase(2, carol(), BLOCK_SUBSIDY + 5);
+        let block = create_block(&previous_hash, 2, vec![coinbase, tx1, tx2]);
+        blockchain.add_block(block).unwrap();
+
+        // the fees are debited from bob and credited to carol
+        let account_balances = blockchain.get_account_balances();
+        assert_eq!(account_balances.get_balance(&bob()), BLOCK_SUBSIDY - 25);
+        assert_eq!(account_balances.get_balance(&alice()), 20);
+        assert_eq!(account_balances.get_balance(&carol()), BLOCK_SUBSIDY + 5);
+    }
+
+    #[test]
+    fn should_not_let_adding_block_with_coinbase_missing_fees() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase]);
+        blockchain.add_block(block).unwrap();
+
+        // the coinbase only claims the subsidy, ignoring the fee of the transaction
+        let previous_hash = blockchain.get_last_block().hash;
+        let transaction = bob_keypair().create_transaction_with_fee(alice(), 10, 2, 0);
+        let coinbase = create_coinbase(2, carol(), BLOCK_SUBSIDY + 3);
+        let block = create_block(&previous_hash, 2, vec![coinbase, transaction]);
+
+        let result = blockchain.add_block(block);
+        assert_err(result, BlockchainError::InvalidCoinbaseAmount);
+    }
+
+    #[test]
+    fn should_not_let_add_transaction_with_insufficient_funds_for_fee() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase]);
+        blockchain.add_block(block).unwrap();
+
+        // bob has exactly the amount of the transfer, but not enough to also pay the fee
+        let previous_hash = blockchain.get_last_block().hash;
+        let transaction = bob_keypair().create_transaction_with_fee(alice(), BLOCK_SUBSIDY, 1, 0);
+        let coinbase = create_coinbase(2, carol(), BLOCK_SUBSIDY + 1);
+        let block = create_block(&previous_hash, 2, vec![coinbase, transaction]);
+
+        let result = blockchain.add_block(block);
+        assert_balance_err(result, AccountBalanceMapError::InsufficientFunds);
+    }
+
     #[test]
     fn should_not_let_add_transaction_with_insufficient_funds() {
         let blockchain = Blockchain::new(NO_DIFFICULTY);
@@ -364,19 +1654,17 @@ mod tests {
         // create an invalid block
         let previous_hash = blockchain.get_last_block().hash;
         // the coinbase is valid
-        let coinbase = Transaction {
-            sender: Address::default(), // sender is ignored in coinbases
-            recipient: bob(),
-            amount: BLOCK_SUBSIDY,
-        };
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
         // but the following transaction has an invalid amount
-        let invalid_transaction = Transaction {
-            sender: bob(),
-            recipient: alice(),
-            // the amount is greated than what bob has
-            amount: BLOCK_SUBSIDY + 1,
-        };
-        let block = Block::new(1, 0, previous_hash, vec![coinbase, invalid_transaction]);
+        // the amount is greated than what bob has
+        let invalid_transaction = bob_keypair().create_transaction(alice(), BLOCK_SUBSIDY + 1, 0);
+        let block = Block::new(
+            1,
+            NO_DIFFICULTY,
+            0,
+            previous_hash,
+            vec![coinbase, invalid_transaction],
+        );
 
         // try adding the invalid block, it should return an error
         let result = blockchain.add_block(block.clone());
@@ -390,35 +1678,746 @@ mod tests {
         // create a valid block
         let previous_hash = blockchain.get_last_block().hash;
         // the coinbase is valid
-        let coinbase = Transaction {
-            sender: Address::default(), // sender is ignored in coinbases
-            recipient: bob(),
-            amount: BLOCK_SUBSIDY,
-        };
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
         // but the sender does not exist
-        let invalid_transaction = Transaction {
-            // the sender address do not have any funds from previous transactions
-            sender: carol(),
-            recipient: bob(),
-            amount: 1,
-        };
-        let block = Block::new(1, 0, previous_hash, vec![coinbase, invalid_transaction]);
+        // the sender address do not have any funds from previous transactions
+        let invalid_transaction = carol_keypair().create_transaction(bob(), 1, 0);
+        let block = Block::new(
+            1,
+            NO_DIFFICULTY,
+            0,
+            previous_hash,
+            vec![coinbase, invalid_transaction],
+        );
+
+        // try adding the invalid block, it should return an error
+        let result = blockchain.add_block(block.clone());
+        assert_balance_err(result, AccountBalanceMapError::SenderAccountDoesNotExist);
+    }
+
+    #[test]
+    fn should_let_add_multiple_transactions_from_same_sender() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        // consecutive nonces from the same sender are valid in the same block
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let tx1 = bob_keypair().create_transaction(alice(), 5, 0);
+        let tx2 = bob_keypair().create_transaction(alice(), 5, 1);
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase, tx1, tx2]);
+        blockchain.add_block(block).unwrap();
+
+        // the nonce of the sender is updated after each transaction
+        let account_balances = blockchain.get_account_balances();
+        assert_eq!(account_balances.get_nonce(&bob()), 2);
+        assert_eq!(account_balances.get_nonce(&alice()), 0);
+    }
+
+    #[test]
+    fn should_not_let_replay_transaction() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        // add a valid block with a transaction from bob
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let transaction = bob_keypair().create_transaction(alice(), 5, 0);
+        let block = Block::new(
+            1,
+            NO_DIFFICULTY,
+            0,
+            previous_hash,
+            vec![coinbase, transaction.clone()],
+        );
+        blockchain.add_block(block).unwrap();
+
+        // try to include the same transaction again in the next block
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(2, bob(), BLOCK_SUBSIDY);
+        let block = create_block(&previous_hash, 2, vec![coinbase, transaction]);
+
+        let result = blockchain.add_block(block);
+        assert_balance_err(result, AccountBalanceMapError::NonceAlreadyUsed);
+    }
+
+    #[test]
+    fn should_not_let_add_transaction_with_nonce_out_of_order() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        // bob's first transaction must have nonce 0, so nonce 1 leaves a gap
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let transaction = bob_keypair().create_transaction(alice(), 5, 1);
+        let block = Block::new(
+            1,
+            NO_DIFFICULTY,
+            0,
+            previous_hash,
+            vec![coinbase, transaction],
+        );
+
+        let result = blockchain.add_block(block);
+        assert_balance_err(result, AccountBalanceMapError::NonceOutOfOrder);
+    }
+
+    #[test]
+    fn should_not_let_add_transaction_with_invalid_signature() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        let previous_hash = blockchain.get_last_block().hash;
+        // bob receives the coinbase, so he has funds
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        // but the transaction spending them was tampered after being signed
+        let mut invalid_transaction = bob_keypair().create_transaction(alice(), 1, 0);
+        invalid_transaction.recipient = carol();
+        let block = Block::new(
+            1,
+            NO_DIFFICULTY,
+            0,
+            previous_hash,
+            vec![coinbase, invalid_transaction],
+        );
+
+        // try adding the invalid block, it should return an error
+        let result = blockchain.add_block(block.clone());
+        assert_transaction_err(result, TransactionError::InvalidSignature);
+    }
+
+    #[test]
+    fn should_not_let_spend_funds_of_other_accounts() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        // alice signs a transaction spending bob's funds with her own keys
+        let mut invalid_transaction = alice_keypair().create_transaction(alice(), 1, 0);
+        invalid_transaction.sender = bob();
+        invalid_transaction.signature = alice_keypair().sign(&invalid_transaction.signing_data());
+        let block = Block::new(
+            1,
+            NO_DIFFICULTY,
+            0,
+            previous_hash,
+            vec![coinbase, invalid_transaction],
+        );
 
         // try adding the invalid block, it should return an error
         let result = blockchain.add_block(block.clone());
+        assert_transaction_err(result, TransactionError::SenderPublicKeyMismatch);
+    }
+
+    #[test]
+    fn should_not_let_adding_the_same_block_twice() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+
+        let previous_hash = blockchain.get_last_block().hash;
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let block = Block::new(1, NO_DIFFICULTY, 0, previous_hash, vec![coinbase]);
+        blockchain.add_block(block.clone()).unwrap();
+
+        let result = blockchain.add_block(block);
+        assert_err(result, BlockchainError::BlockAlreadyExists);
+    }
+
+    #[test]
+    fn should_keep_competing_blocks_in_side_chain() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let genesis_hash = blockchain.get_last_block().hash;
+
+        // two blocks with the same previous block
+        let block_a = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let block_b = create_block_with_coinbase(&genesis_hash, 1, carol());
+        assert_eq!(
+            blockchain.add_block(block_a.clone()).unwrap(),
+            BlockStatus::MainChain
+        );
+        assert_eq!(
+            blockchain.add_block(block_b.clone()).unwrap(),
+            BlockStatus::SideChain
+        );
+
+        // both have the same work, so the first one stays in the main chain
+        assert_eq!(blockchain.get_last_block().hash, block_a.hash);
+        assert!(blockchain.contains_block(&block_b.hash));
+        let account_balances = blockchain.get_account_balances();
+        assert_eq!(account_balances.get_balance(&bob()), BLOCK_SUBSIDY);
+        assert_eq!(account_balances.get_balance(&carol()), 0);
+    }
+
+    #[test]
+    fn should_not_keep_branches_that_fork_too_deep() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY).with_max_fork_depth(2);
+        let genesis_hash = blockchain.get_last_block().hash;
+        let mut previous_hash = genesis_hash;
+        for index in 1..=3 {
+            let block = create_block_with_coinbase(&previous_hash, index, bob());
+            previous_hash = block.hash;
+            blockchain.add_block(block).unwrap();
+        }
+
+        // a branch from the first block is still close enough to the last one
+        let block_1 = blockchain.get_block_by_index(1).unwrap();
+        let side_block = create_block_with_coinbase(&block_1.hash, 2, carol());
+        assert_eq!(
+            blockchain.add_block(side_block.clone()).unwrap(),
+            BlockStatus::SideChain
+        );
+
+        // but a branch from the genesis block is not
+        let deep_block = create_block_with_coinbase(&genesis_hash, 1, carol());
+        let result = blockchain.add_block(deep_block.clone());
+        assert_err(result, BlockchainError::ForkTooDeep);
+        assert!(!blockchain.contains_block(&deep_block.hash));
+
+        // once the main chain grows, the older side branches cannot be extended either
+        let block = create_block_with_coinbase(&previous_hash, 4, bob());
+        blockchain.add_block(block).unwrap();
+        let side_block_2 = create_block_with_coinbase(&side_block.hash, 3, carol());
+        let result = blockchain.add_block(side_block_2);
+        assert_err(result, BlockchainError::ForkTooDeep);
+    }
+
+    #[test]
+    fn should_reorganize_to_the_branch_with_more_work() {
+        let pool = TransactionPool::new(10, 10_000);
+        let blockchain = Blockchain::new(NO_DIFFICULTY).with_transaction_pool(pool.clone());
+        let genesis_hash = blockchain.get_last_block().hash;
+        let generation = blockchain.get_tip_generation();
+
+        // the main chain includes a transfer from bob to alice
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let transaction = bob_keypair().create_transaction(alice(), 10, 0);
+        let block_a = Block::new(
+            1,
+            NO_DIFFICULTY,
+            0,
+            genesis_hash,
+            vec![coinbase, transaction.clone()],
+        );
+        blockchain.add_block(block_a.clone()).unwrap();
+
+        // a competing branch without that transfer gets longer
+        let block_b1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let block_b2 = create_block_with_coinbase(&block_b1.hash, 2, carol());
+        assert_eq!(
+            blockchain.add_block(block_b1.clone()).unwrap(),
+            BlockStatus::SideChain
+        );
+        assert_eq!(
+            blockchain.add_block(block_b2.clone()).unwrap(),
+            BlockStatus::MainChain
+        );
+
+        // so it becomes the main chain
+        let hashes: Vec<BlockHash> = blockchain.get_all_blocks().iter().map(|b| b.hash).collect();
+        assert_eq!(hashes, vec![genesis_hash, block_b1.hash, block_b2.hash]);
+        assert_ne!(blockchain.get_tip_generation(), generation);
+
+        // the balances of the removed block are rolled back
+        let account_balances = blockchain.get_account_balances();
+        assert_eq!(account_balances.get_balance(&bob()), BLOCK_SUBSIDY);
+        assert_eq!(account_balances.get_nonce(&bob()), 0);
+        assert!(!account_balances.contains(&alice()));
+        assert_eq!(account_balances.get_balance(&carol()), BLOCK_SUBSIDY);
+
+        // and its transfer goes back to the pool, as it's not in the blockchain anymore
+        assert_eq!(pool.count_transactions(), 1);
+        assert!(pool.get_transaction(&transaction.hash()).is_some());
+    }
+
+    #[test]
+    fn should_summarize_the_main_chain() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let genesis_hash = blockchain.get_last_block().hash;
+
+        // the genesis block does not add any work
+        let info = blockchain.get_chain_info();
+        assert_eq!(info.height, 0);
+        assert_eq!(info.tip_hash, genesis_hash);
+        assert_eq!(info.difficulty, NO_DIFFICULTY);
+        assert_eq!(info.cumulative_work, U256::zero());
+
+        let block = create_block_with_coinbase(&genesis_hash, 1, alice());
+        blockchain.add_block(block.clone()).unwrap();
+        let info = blockchain.get_chain_info();
+        assert_eq!(info.height, 1);
+        assert_eq!(info.tip_hash, block.hash);
+        assert_eq!(info.cumulative_work, U256::one());
+    }
+
+    #[test]
+    fn should_get_blocks_of_the_main_chain_by_index() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let genesis_hash = blockchain.get_last_block().hash;
+        let block_a1 = create_block_with_coinbase(&genesis_hash, 1, alice());
+        let block_a2 = create_block_with_coinbase(&block_a1.hash, 2, alice());
+        let block_b1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        for block in [block_a1.clone(), block_a2.clone(), block_b1.clone()] {
+            blockchain.add_block(block).unwrap();
+        }
+
+        // ranges are cut at the end of the main chain
+        let hashes = |blocks: BlockVec| -> Vec<BlockHash> {
+            blocks.iter().map(|block| block.hash).collect()
+        };
+        assert_eq!(
+            hashes(blockchain.get_blocks(1, 5)),
+            vec![block_a1.hash, block_a2.hash]
+        );
+        assert_eq!(hashes(blockchain.get_blocks(0, 1)), vec![genesis_hash]);
+        assert!(blockchain.get_blocks(3, 5).is_empty());
+
+        // blocks of other branches are not returned by index
+        assert_eq!(
+            blockchain.get_block_by_index(1).unwrap().hash,
+            block_a1.hash
+        );
+        assert!(blockchain.get_block_by_index(3).is_none());
+        assert!(blockchain.get_block(&block_b1.hash).is_some());
+    }
+
+    #[test]
+    fn should_find_transactions_of_the_main_chain() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let genesis_hash = blockchain.get_last_block().hash;
+
+        let coinbase = create_coinbase(1, bob(), BLOCK_SUBSIDY);
+        let transaction = bob_keypair().create_transaction(alice(), 10, 0);
+        let block_a1 = Block::new(
+            1,
+            NO_DIFFICULTY,
+            0,
+            genesis_hash,
+            vec![coinbase, transaction.clone()],
+        );
+        let block_a2 = create_block_with_coinbase(&block_a1.hash, 2, bob());
+        blockchain.add_block(block_a1.clone()).unwrap();
+        blockchain.add_block(block_a2.clone()).unwrap();
+
+        // the transaction is found in its block, with the blocks on top of it as confirmations
+        let confirmed = blockchain.get_transaction(&transaction.hash()).unwrap();
+        assert_eq!(confirmed.transaction.signature, transaction.signature);
+        assert_eq!(confirmed.block_index, 1);
+        assert_eq!(confirmed.block_hash, block_a1.hash);
+        assert_eq!(confirmed.confirmations, 2);
+
+        // a competing branch without the transaction becomes the main chain
+        let mut previous_hash = genesis_hash;
+        for index in 1..=3 {
+            let block = create_block_with_coinbase(&previous_hash, index, carol());
+            previous_hash = block.hash;
+            blockchain.add_block(block).unwrap();
+        }
+        assert!(blockchain.get_transaction(&transaction.hash()).is_none());
+
+        // the coinbases of the new main chain are found instead
+        let coinbase = &blockchain.get_last_block().transactions[0];
+        let confirmed = blockchain.get_transaction(&coinbase.hash()).unwrap();
+        assert_eq!(confirmed.block_hash, previous_hash);
+        assert_eq!(confirmed.confirmations, 1);
+    }
+
+    #[test]
+    fn should_find_transactions_of_an_address() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let genesis_hash = blockchain.get_last_block().hash;
+
+        // bob mines two blocks and sends funds to alice in the second one
+        let block_a1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let transaction = bob_keypair().create_transaction(alice(), 10, 0);
+        let block_a2 = create_block(
+            &block_a1.hash,
+            2,
+            vec![
+                create_coinbase(2, bob(), BLOCK_SUBSIDY),
+                transaction.clone(),
+            ],
+        );
+        blockchain.add_block(block_a1.clone()).unwrap();
+        blockchain.add_block(block_a2.clone()).unwrap();
+
+        // the transactions of bob are returned from the newest to the oldest
+        let page = blockchain.get_address_transactions(&bob(), 0, 10);
+        assert_eq!(page.total, 3);
+        let hashes: Vec<TransactionHash> = page
+            .transactions
+            .iter()
+            .map(|confirmed| confirmed.transaction.hash())
+            .collect();
+        let expected_hashes = vec![
+            transaction.hash(),
+            block_a2.transactions[0].hash(),
+            block_a1.transactions[0].hash(),
+        ];
+        assert_eq!(hashes, expected_hashes);
+        assert_eq!(page.transactions[2].block_index, 1);
+        assert_eq!(page.transactions[2].confirmations, 2);
+
+        // only the requested range is returned
+        let page = blockchain.get_address_transactions(&bob(), 1, 1);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.transactions.len(), 1);
+        assert_eq!(page.transactions[0].block_hash, block_a2.hash);
+
+        // the sender of coinbases is not indexed
+        let page = blockchain.get_address_transactions(&Address::default(), 0, 10);
+        assert_eq!(page.total, 0);
+
+        // after a reorganization, only the transactions of the new main chain are returned
+        let block_b2 = create_block_with_coinbase(&block_a1.hash, 2, carol());
+        let block_b3 = create_block_with_coinbase(&block_b2.hash, 3, carol());
+        blockchain.add_block(block_b2).unwrap();
+        blockchain.add_block(block_b3).unwrap();
+
+        let page = blockchain.get_address_transactions(&bob(), 0, 10);
+        assert_eq!(page.total, 1);
+        assert_eq!(page.transactions[0].block_hash, block_a1.hash);
+        assert_eq!(page.transactions[0].confirmations, 3);
+        assert_eq!(
+            blockchain.get_address_transactions(&alice(), 0, 10).total,
+            0
+        );
+        assert_eq!(
+            blockchain.get_address_transactions(&carol(), 0, 10).total,
+            2
+        );
+    }
+
+    #[test]
+    fn should_not_reorganize_to_an_invalid_branch() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let genesis_hash = blockchain.get_last_block().hash;
+
+        let block_a = create_block_with_coinbase(&genesis_hash, 1, bob());
+        blockchain.add_block(block_a.clone()).unwrap();
+        let generation = blockchain.get_tip_generation();
+
+        // the competing branch spends funds that alice does not have
+        let block_b1 = create_block_with_coinbase(&genesis_hash, 1, carol());
+        let coinbase = create_coinbase(2, carol(), BLOCK_SUBSIDY);
+        let transaction = alice_keypair().create_transaction(carol(), 10, 0);
+        let block_b2 = create_block(&block_b1.hash, 2, vec![coinbase, transaction]);
+        blockchain.add_block(block_b1.clone()).unwrap();
+        let result = blockchain.add_block(block_b2.clone());
         assert_balance_err(result, AccountBalanceMapError::SenderAccountDoesNotExist);
+
+        // so the main chain does not change, and the invalid block is discarded
+        assert_eq!(blockchain.get_last_block().hash, block_a.hash);
+        assert_eq!(blockchain.get_tip_generation(), generation);
+        assert_eq!(
+            blockchain.get_account_balances().get_balance(&bob()),
+            BLOCK_SUBSIDY
+        );
+        assert!(blockchain.contains_block(&block_b1.hash));
+        assert!(!blockchain.contains_block(&block_b2.hash));
+    }
+
+    #[test]
+    fn should_connect_orphans_when_previous_block_arrives() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let genesis_hash = blockchain.get_last_block().hash;
+        let block_1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let block_2 = create_block_with_coinbase(&block_1.hash, 2, bob());
+        let block_3 = create_block_with_coinbase(&block_2.hash, 3, bob());
+
+        // the blocks arrive in reverse order, so the last ones are orphans
+        assert_eq!(
+            blockchain.add_block(block_3.clone()).unwrap(),
+            BlockStatus::Orphan
+        );
+        assert_eq!(
+            blockchain.add_block(block_2.clone()).unwrap(),
+            BlockStatus::Orphan
+        );
+        assert_eq!(blockchain.get_last_block().hash, genesis_hash);
+
+        // when the first one arrives, all of them are connected
+        assert_eq!(
+            blockchain.add_block(block_1.clone()).unwrap(),
+            BlockStatus::MainChain
+        );
+        let hashes: Vec<BlockHash> = blockchain
+            .get_all_blocks()
+            .iter()
+            .map(|block| block.hash)
+            .collect();
+        assert_eq!(
+            hashes,
+            vec![genesis_hash, block_1.hash, block_2.hash, block_3.hash]
+        );
+        assert_eq!(
+            blockchain.get_account_balances().get_balance(&bob()),
+            3 * BLOCK_SUBSIDY
+        );
+    }
+
+    #[test]
+    fn should_discard_invalid_orphans_when_connecting() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY);
+        let genesis_hash = blockchain.get_last_block().hash;
+        let block_1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+
+        // the index of the orphan can only be checked when its previous block arrives
+        let invalid_block = create_block_with_coinbase(&block_1.hash, 3, bob());
+        assert_eq!(
+            blockchain.add_block(invalid_block.clone()).unwrap(),
+            BlockStatus::Orphan
+        );
+
+        blockchain.add_block(block_1.clone()).unwrap();
+        assert_eq!(blockchain.get_last_block().hash, block_1.hash);
+        assert!(!blockchain.contains_block(&invalid_block.hash));
+    }
+
+    #[test]
+    fn should_evict_oldest_orphan_when_full() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY).with_orphan_limits(1, ORPHAN_EXPIRY_MS);
+        let genesis_hash = blockchain.get_last_block().hash;
+        let block_1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let block_2 = create_block_with_coinbase(&block_1.hash, 2, bob());
+        let other_block_2 = create_block_with_coinbase(&block_1.hash, 2, carol());
+
+        // there is only room for one orphan, so the second one evicts the first one
+        blockchain.add_block(block_2.clone()).unwrap();
+        blockchain.add_block(other_block_2.clone()).unwrap();
+
+        blockchain.add_block(block_1).unwrap();
+        assert!(!blockchain.contains_block(&block_2.hash));
+        assert_eq!(blockchain.get_last_block().hash, other_block_2.hash);
+    }
+
+    #[test]
+    fn should_expire_orphans() {
+        let blockchain = Blockchain::new(NO_DIFFICULTY).with_orphan_limits(MAX_ORPHAN_BLOCKS, 0);
+        let genesis_hash = blockchain.get_last_block().hash;
+        let block_1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let block_2 = create_block_with_coinbase(&block_1.hash, 2, bob());
+
+        // the orphan expires before its previous block arrives
+        assert_eq!(
+            blockchain.add_block(block_2.clone()).unwrap(),
+            BlockStatus::Orphan
+        );
+        blockchain.add_block(block_1.clone()).unwrap();
+
+        assert!(!blockchain.contains_block(&block_2.hash));
+        assert_eq!(blockchain.get_last_block().hash, block_1.hash);
+    }
+
+    #[test]
+    fn should_load_blocks_from_block_store() {
+        let data_dir = create_temp_dir("blockchain_load");
+        let blockchain = Blockchain::new(NO_DIFFICULTY)
+            .with_block_store(&data_dir, NO_SNAPSHOTS)
+            .unwrap();
+        let genesis_hash = blockchain.get_last_block().hash;
+
+        // add a main chain with a transfer, and a competing block in a side chain
+        let block_1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let transfer = bob_keypair().create_transaction(alice(), 10, 0);
+        let coinbase = create_coinbase(2, carol(), BLOCK_SUBSIDY);
+        let block_2 = create_block(&block_1.hash, 2, vec![coinbase, transfer]);
+        let side_block = create_block_with_coinbase(&genesis_hash, 1, carol());
+        blockchain.add_block(block_1).unwrap();
+        blockchain.add_block(block_2.clone()).unwrap();
+        blockchain.add_block(side_block.clone()).unwrap();
+
+        // a new blockchain on the same directory has the same blocks and balances
+        let loaded = Blockchain::new(NO_DIFFICULTY)
+            .with_block_store(&data_dir, NO_SNAPSHOTS)
+            .unwrap();
+        assert_eq!(loaded.get_all_blocks().len(), 3);
+        assert_eq!(loaded.get_last_block().hash, block_2.hash);
+        assert!(loaded.contains_block(&side_block.hash));
+        let account_balances = loaded.get_account_balances();
+        assert_eq!(account_balances.get_balance(&alice()), 10);
+        assert_eq!(account_balances.get_balance(&bob()), BLOCK_SUBSIDY - 10);
+        assert_eq!(account_balances.get_nonce(&bob()), 1);
+    }
+
+    #[test]
+    fn should_load_account_balances_from_snapshot() {
+        let data_dir = create_temp_dir("blockchain_snapshot");
+        let blockchain = Blockchain::new(NO_DIFFICULTY)
+            .with_block_store(&data_dir, 2)
+            .unwrap();
+        let genesis_hash = blockchain.get_last_block().hash;
+
+        // the snapshot is saved at the second block, the third one is processed again on restart
+        let block_1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let transfer = bob_keypair().create_transaction(alice(), 10, 0);
+        let coinbase = create_coinbase(2, carol(), BLOCK_SUBSIDY);
+        let block_2 = create_block(&block_1.hash, 2, vec![coinbase, transfer]);
+        let block_3 = create_block_with_coinbase(&block_2.hash, 3, bob());
+        blockchain.add_block(block_1.clone()).unwrap();
+        blockchain.add_block(block_2).unwrap();
+        blockchain.add_block(block_3.clone()).unwrap();
+
+        let loaded = Blockchain::new(NO_DIFFICULTY)
+            .with_block_store(&data_dir, 2)
+            .unwrap();
+        assert_eq!(loaded.get_last_block().hash, block_3.hash);
+        let account_balances = loaded.get_account_balances();
+        assert_eq!(account_balances.get_balance(&alice()), 10);
+        assert_eq!(account_balances.get_balance(&bob()), 2 * BLOCK_SUBSIDY - 10);
+        assert_eq!(account_balances.get_balance(&carol()), BLOCK_SUBSIDY);
+
+        // the blocks up to the snapshot were not processed
+        let state = loaded.state.lock().unwrap();
+        assert!(state.entries[&block_1.hash].saved_accounts.is_none());
+        assert!(state.entries[&block_3.hash].saved_accounts.is_some());
+    }
+
+    #[test]
+    fn should_reorganize_blocks_loaded_from_snapshot() {
+        let data_dir = create_temp_dir("blockchain_snapshot_reorg");
+        let blockchain = Blockchain::new(NO_DIFFICULTY)
+            .with_block_store(&data_dir, 2)
+            .unwrap();
+        let genesis_hash = blockchain.get_last_block().hash;
+        let block_1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        let transfer = bob_keypair().create_transaction(alice(), 10, 0);
+        let coinbase = create_coinbase(2, bob(), BLOCK_SUBSIDY);
+        let block_2 = create_block(&block_1.hash, 2, vec![coinbase, transfer]);
+        blockchain.add_block(block_1).unwrap();
+        blockchain.add_block(block_2).unwrap();
+
+        let pool = TransactionPool::new(10, 10_000);
+        let loaded = Blockchain::new(NO_DIFFICULTY)
+            .with_block_store(&data_dir, 2)
+            .unwrap()
+            .with_transaction_pool(pool.clone());
+
+        // a heavier branch from the genesis block replaces the blocks loaded from the snapshot
+        let mut previous_hash = genesis_hash;
+        for index in 1..=3 {
+            let block = create_block_with_coinbase(&previous_hash, index, bob());
+            previous_hash = block.hash;
+            loaded.add_block(block).unwrap();
+        }
+        assert_eq!(loaded.get_last_block().hash, previous_hash);
+        let account_balances = loaded.get_account_balances();
+        assert_eq!(account_balances.get_balance(&alice()), 0);
+        assert_eq!(account_balances.get_balance(&bob()), 3 * BLOCK_SUBSIDY);
+        assert_eq!(pool.count_transactions(), 1);
+    }
+
+    #[test]
+    fn should_ignore_snapshot_of_unknown_block() {
+        let data_dir = create_temp_dir("blockchain_unknown_snapshot");
+        let blockchain = Blockchain::new(NO_DIFFICULTY)
+            .with_block_store(&data_dir, NO_SNAPSHOTS)
+            .unwrap();
+        let genesis_hash = blockchain.get_last_block().hash;
+        let block_1 = create_block_with_coinbase(&genesis_hash, 1, bob());
+        blockchain.add_block(block_1.clone()).unwrap();
+
+        // the snapshot does not match the stored blocks, so all of them are processed
+        let (store, _) = BlockStore::open(&data_dir).unwrap();
+        let snapshot = AccountSnapshot {
+            index: 1,
+            hash: BlockHash::default(),
+            account_balances: AccountBalanceMap::default(),
+        };
+        store.save_snapshot(&snapshot).unwrap();
+
+        let loaded = Blockchain::new(NO_DIFFICULTY)
+            .with_block_store(&data_dir, NO_SNAPSHOTS)
+            .unwrap();
+        assert_eq!(loaded.get_last_block().hash, block_1.hash);
+        assert_eq!(
+            loaded.get_account_balances().get_balance(&bob()),
+            BLOCK_SUBSIDY
+        );
     }
 
-    fn assert_err(result: Result<(), anyhow::Error>, error_type: BlockchainError) {
+    fn create_block(
+        previous_hash: &BlockHash,
+        index: u64,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let mut block = Block::new(index, NO_DIFFICULTY, 0, *previous_hash, transactions);
+
+        // the blocks must be more recent than the previous ones, even if they are created in the same millisecond
+        block.timestamp += index as i64;
+        block.hash = block.calculate_hash();
+
+        block
+    }
+
+    fn create_block_with_coinbase(
+        previous_hash: &BlockHash,
+        index: u64,
+        recipient: Address,
+    ) -> Block {
+        let coinbase = create_coinbase(index, recipient, BLOCK_SUBSIDY);
+        create_block(previous_hash, index, vec![coinbase])
+    }
+
+    fn create_block_with_timestamp(previous_hash: &BlockHash, index: u64, timestamp: i64) -> Block {
+        let mut block = create_block_with_coinbase(previous_hash, index, bob());
+        block.timestamp = timestamp;
+        block.hash = block.calculate_hash();
+
+        block
+    }
+
+    // adds an empty block mined with the required difficulty, the indicated time after the last block
+    fn add_mined_block(blockchain: &Blockchain, elapsed_ms: i64) {
+        let last_block = blockchain.get_last_block();
+        let difficulty = blockchain.get_next_difficulty();
+        let coinbase = create_coinbase(last_block.index + 1, bob(), BLOCK_SUBSIDY);
+        let mut block = Block::new(
+            last_block.index + 1,
+            difficulty,
+            0,
+            last_block.hash,
+            vec![coinbase],
+        );
+
+        // the genesis block does not have a real timestamp, so we start counting from the first block
+        if last_block.index > 0 {
+            block.timestamp = last_block.timestamp + elapsed_ms;
+        }
+
+        // search for a nonce that satisfies the difficulty
+        block.hash = block.calculate_hash();
+        while block.hash.leading_zeros() < difficulty {
+            block.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+
+        blockchain.add_block(block).unwrap();
+    }
+
+    // coinbase transactions do not need to be signed, as they only create new coins
+    // and they include the index of their block in the nonce
+    fn create_coinbase(index: u64, recipient: Address, amount: u64) -> Transaction {
+        Transaction {
+            sender: Address::default(), // sender is ignored in coinbases
+            recipient,
+            amount,
+            fee: 0,
+            nonce: index << EXTRA_NONCE_BITS,
+            public_key: PublicKey::default(),
+            signature: Signature::default(),
+        }
+    }
+
+    fn assert_err(result: Result<BlockStatus>, error_type: BlockchainError) {
         let err = result.unwrap_err().downcast::<BlockchainError>().unwrap();
         assert_eq!(err, error_type);
     }
 
-    fn assert_balance_err(result: Result<(), anyhow::Error>, error_type: AccountBalanceMapError) {
+    fn assert_balance_err(result: Result<BlockStatus>, error_type: AccountBalanceMapError) {
         let err = result
             .unwrap_err()
             .downcast::<AccountBalanceMapError>()
             .unwrap();
         assert_eq!(err, error_type);
     }
+
+    fn assert_transaction_err(result: Result<BlockStatus>, error_type: TransactionError) {
+        let err = result.unwrap_err().downcast::<TransactionError>().unwrap();
+        assert_eq!(err, error_type);
+    }
 }
@@ -0,0 +1,89 @@
+use crypto::{digest::Digest, sha2::Sha256};
+use ethereum_types::U256;
+
+// Version of the binary encoding, written as the first byte of every encoded value
+// Any change in the encoding must use a new version, as it changes the hashes and signatures
+pub const ENCODING_VERSION: u8 = 1;
+
+// Canonical binary encoding of the data covered by hashes and signatures
+// Unlike JSON, it does not depend on field names, serde attributes or how each type is formatted:
+// integers are big-endian with a fixed size, hashes and keys are raw bytes and lists are prefixed by their length
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder {
+            bytes: vec![ENCODING_VERSION],
+        }
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Encoder {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Encoder {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn i64(&mut self, value: i64) -> &mut Encoder {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    // Fixed-size values (like addresses or keys), so they are written without length
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Encoder {
+        self.bytes.extend_from_slice(value);
+        self
+    }
+
+    pub fn hash(&mut self, value: &U256) -> &mut Encoder {
+        let mut bytes = [0; 32];
+        value.to_big_endian(&mut bytes);
+        self.bytes(&bytes)
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+// SHA-256 hash of the encoded data, as a 256-bit value
+pub fn calculate_hash(data: &[u8]) -> U256 {
+    let mut byte_hash = <[u8; 32]>::default();
+    let mut hasher = Sha256::new();
+
+    hasher.input(data);
+    hasher.result(&mut byte_hash);
+
+    U256::from(byte_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_values_in_big_endian() {
+        let bytes = Encoder::new()
+            .u32(1)
+            .u64(2)
+            .i64(-1)
+            .bytes(&[0xab, 0xcd])
+            .hash(&U256::from(3))
+            .finish();
+
+        let expected = [
+            "01",
+            "00000001",
+            "0000000000000002",
+            "ffffffffffffffff",
+            "abcd",
+            "0000000000000000000000000000000000000000000000000000000000000003",
+        ];
+        assert_eq!(hex::encode(bytes), expected.concat());
+    }
+}
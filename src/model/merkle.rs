@@ -0,0 +1,259 @@
+use ethereum_types::U256;
+use serde::{Deserialize, Serialize};
+
+use super::encoding;
+
+// Nodes of the tree use the same 256-bit representation as block and transaction hashes
+pub type MerkleHash = U256;
+
+// Prefixes of the data hashed for the leaves and for the parent nodes, so an inner node can never pass for a leaf
+// None of them is an encoding version, so they cannot be confused with the hash of any encoded value either
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0xff;
+
+// Side of the node that has to be combined with the current hash, when walking up the tree
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleSibling {
+    pub hash: MerkleHash,
+    pub position: Position,
+}
+
+// Proves that a leaf is included in a merkle tree, with only the sibling nodes of the path from the leaf to the root
+// The position of the leaf and the amount of leaves fix the shape of the path, so it cannot be shortened
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub leaf_count: usize,
+    pub siblings: Vec<MerkleSibling>,
+}
+
+impl MerkleProof {
+    // Checks that combining the leaf with the siblings results in the expected root
+    // The siblings must be exactly the ones of the path of the leaf in a tree of leaf_count leaves
+    pub fn verify(&self, leaf: MerkleHash, root: MerkleHash) -> bool {
+        let positions: Vec<Position> = self
+            .siblings
+            .iter()
+            .map(|sibling| sibling.position)
+            .collect();
+        if self.index >= self.leaf_count
+            || positions != calculate_path_positions(self.index, self.leaf_count)
+        {
+            return false;
+        }
+
+        let calculated_root =
+            self.siblings
+                .iter()
+                .fold(hash_leaf(leaf), |hash, sibling| match sibling.position {
+                    Position::Left => hash_pair(sibling.hash, hash),
+                    Position::Right => hash_pair(hash, sibling.hash),
+                });
+
+        calculated_root == root
+    }
+}
+
+// Calculates the root of the tree built from the leaves in order
+// Pairs of nodes are hashed together level by level, and a node without pair goes up to the next level unchanged
+// An empty tree has a root of zero
+pub fn calculate_merkle_root(leaves: &[MerkleHash]) -> MerkleHash {
+    let mut level = hash_leaves(leaves);
+    while level.len() > 1 {
+        level = calculate_parent_level(&level);
+    }
+
+    level.first().copied().unwrap_or_default()
+}
+
+// Returns the proof for the leaf in the indicated position, or None if there is no such leaf
+pub fn create_merkle_proof(leaves: &[MerkleHash], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let leaf_index = index;
+
+    let mut siblings = Vec::new();
+    let mut level = hash_leaves(leaves);
+    let mut index = index;
+    while level.len() > 1 {
+        let sibling_index = index ^ 1;
+        if let Some(hash) = level.get(sibling_index) {
+            let position = match sibling_index < index {
+                true => Position::Left,
+                false => Position::Right,
+            };
+            siblings.push(MerkleSibling {
+                hash: *hash,
+                position,
+            });
+        }
+
+        level = calculate_parent_level(&level);
+        index /= 2;
+    }
+
+    Some(MerkleProof {
+        index: leaf_index,
+        leaf_count: leaves.len(),
+        siblings,
+    })
+}
+
+// Returns the side of each sibling in the path from a leaf to the root, in the same order as in a proof
+// A node without pair goes up unchanged, so it adds no sibling in that level
+fn calculate_path_positions(index: usize, leaf_count: usize) -> Vec<Position> {
+    let mut positions = Vec::new();
+    let mut level_len = leaf_count;
+    let mut index = index;
+    while level_len > 1 {
+        let sibling_index = index ^ 1;
+        if sibling_index < level_len {
+            positions.push(match sibling_index < index {
+                true => Position::Left,
+                false => Position::Right,
+            });
+        }
+
+        level_len = level_len.div_ceil(2);
+        index /= 2;
+    }
+
+    positions
+}
+
+fn hash_leaves(leaves: &[MerkleHash]) -> Vec<MerkleHash> {
+    leaves.iter().map(|leaf| hash_leaf(*leaf)).collect()
+}
+
+fn calculate_parent_level(level: &[MerkleHash]) -> Vec<MerkleHash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(*left, *right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn hash_leaf(leaf: MerkleHash) -> MerkleHash {
+    let mut data = [0_u8; 33];
+    data[0] = LEAF_PREFIX;
+    leaf.to_big_endian(&mut data[1..]);
+
+    encoding::calculate_hash(&data)
+}
+
+fn hash_pair(left: MerkleHash, right: MerkleHash) -> MerkleHash {
+    let mut data = [0_u8; 65];
+    data[0] = NODE_PREFIX;
+    left.to_big_endian(&mut data[1..33]);
+    right.to_big_endian(&mut data[33..]);
+
+    encoding::calculate_hash(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_leaves(count: u64) -> Vec<MerkleHash> {
+        (1..=count).map(MerkleHash::from).collect()
+    }
+
+    #[test]
+    fn should_calculate_root_of_small_trees() {
+        // an empty tree has a zero root, and a single leaf is the root itself once hashed
+        assert_eq!(calculate_merkle_root(&[]), MerkleHash::zero());
+        assert_eq!(
+            calculate_merkle_root(&create_leaves(1)),
+            hash_leaf(MerkleHash::from(1))
+        );
+
+        // with an odd amount of leaves, the last one is combined in the next level
+        let leaves = create_leaves(3);
+        let expected_root = hash_pair(
+            hash_pair(hash_leaf(leaves[0]), hash_leaf(leaves[1])),
+            hash_leaf(leaves[2]),
+        );
+        assert_eq!(calculate_merkle_root(&leaves), expected_root);
+    }
+
+    #[test]
+    fn should_change_root_when_leaves_change() {
+        let leaves = create_leaves(4);
+        let mut reordered_leaves = leaves.clone();
+        reordered_leaves.swap(0, 1);
+
+        assert_ne!(
+            calculate_merkle_root(&leaves),
+            calculate_merkle_root(&reordered_leaves)
+        );
+    }
+
+    #[test]
+    fn should_verify_proofs_of_all_leaves() {
+        for count in 1..=9 {
+            let leaves = create_leaves(count);
+            let root = calculate_merkle_root(&leaves);
+
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = create_merkle_proof(&leaves, index).unwrap();
+                assert!(proof.verify(*leaf, root));
+            }
+        }
+    }
+
+    #[test]
+    fn should_not_verify_invalid_proofs() {
+        let leaves = create_leaves(5);
+        let root = calculate_merkle_root(&leaves);
+        let proof = create_merkle_proof(&leaves, 2).unwrap();
+
+        // a different leaf or root
+        assert!(!proof.verify(leaves[3], root));
+        assert!(!proof.verify(leaves[2], MerkleHash::zero()));
+
+        // a tampered sibling
+        let mut tampered_proof = proof.clone();
+        tampered_proof.siblings[0].hash = MerkleHash::zero();
+        assert!(!tampered_proof.verify(leaves[2], root));
+
+        // a proof with a different shape than the path of the leaf
+        let mut tampered_proof = proof.clone();
+        tampered_proof
+            .siblings
+            .push(tampered_proof.siblings[0].clone());
+        assert!(!tampered_proof.verify(leaves[2], root));
+        let mut tampered_proof = proof.clone();
+        tampered_proof.leaf_count = 4;
+        assert!(!tampered_proof.verify(leaves[2], root));
+
+        // there is no proof for leaves that do not exist
+        assert!(create_merkle_proof(&leaves, 5).is_none());
+    }
+
+    #[test]
+    fn should_not_verify_inner_nodes_as_leaves() {
+        let leaves = create_leaves(4);
+        let root = calculate_merkle_root(&leaves);
+        let inner_node = hash_pair(hash_leaf(leaves[0]), hash_leaf(leaves[1]));
+
+        // the path from the inner node to the root, as if it were a leaf of a smaller tree
+        let mut proof = create_merkle_proof(&leaves, 0).unwrap();
+        proof.siblings.remove(0);
+        assert!(!proof.verify(inner_node, root));
+
+        // even if the amount of leaves matches the shortened path, the inner node is hashed as a leaf
+        proof.leaf_count = 2;
+        assert!(!proof.verify(inner_node, root));
+    }
+}
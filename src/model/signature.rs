@@ -0,0 +1,229 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    str::FromStr,
+};
+
+use crypto::ed25519;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// Ed25519 public keys are 32-bytes long and signatures are 64-bytes long
+type Byte = u8;
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(Error, PartialEq, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum SignatureError {
+    #[error("Invalid format")]
+    InvalidFormat,
+
+    #[error("Invalid length")]
+    InvalidLength,
+}
+
+// Public key of an account, used to verify the signatures of its transactions
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct PublicKey([Byte; PUBLIC_KEY_LEN]);
+
+// Ed25519 signature of a transaction, created with the secret key of the sender
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Signature([Byte; SIGNATURE_LEN]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[Byte] {
+        &self.0
+    }
+
+    // Checks that the signature was created for the message with the secret key of this public key
+    pub fn verify(&self, message: &[Byte], signature: &Signature) -> bool {
+        ed25519::verify(message, &self.0, &signature.0)
+    }
+}
+
+impl Signature {
+    pub fn as_bytes(&self) -> &[Byte] {
+        &self.0
+    }
+}
+
+// Arrays longer than 32 elements do not implement Default, so we need to do it manually
+impl Default for Signature {
+    fn default() -> Self {
+        Signature([0; SIGNATURE_LEN])
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Signature({})", self)
+    }
+}
+
+impl From<[Byte; PUBLIC_KEY_LEN]> for PublicKey {
+    fn from(bytes: [Byte; PUBLIC_KEY_LEN]) -> Self {
+        PublicKey(bytes)
+    }
+}
+
+impl From<[Byte; SIGNATURE_LEN]> for Signature {
+    fn from(bytes: [Byte; SIGNATURE_LEN]) -> Self {
+        Signature(bytes)
+    }
+}
+
+impl TryFrom<Vec<Byte>> for PublicKey {
+    type Error = SignatureError;
+
+    fn try_from(vec: Vec<Byte>) -> Result<Self, SignatureError> {
+        match vec.as_slice().try_into() {
+            Ok(byte_array) => Ok(PublicKey(byte_array)),
+            Err(_) => Err(SignatureError::InvalidLength),
+        }
+    }
+}
+
+impl TryFrom<Vec<Byte>> for Signature {
+    type Error = SignatureError;
+
+    fn try_from(vec: Vec<Byte>) -> Result<Self, SignatureError> {
+        match vec.as_slice().try_into() {
+            Ok(byte_array) => Ok(Signature(byte_array)),
+            Err(_) => Err(SignatureError::InvalidLength),
+        }
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = SignatureError;
+
+    fn try_from(s: String) -> Result<Self, SignatureError> {
+        match hex::decode(s) {
+            Ok(decoded_vec) => decoded_vec.try_into(),
+            Err(_) => Err(SignatureError::InvalidFormat),
+        }
+    }
+}
+
+impl TryFrom<String> for Signature {
+    type Error = SignatureError;
+
+    fn try_from(s: String) -> Result<Self, SignatureError> {
+        match hex::decode(s) {
+            Ok(decoded_vec) => decoded_vec.try_into(),
+            Err(_) => Err(SignatureError::InvalidFormat),
+        }
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = SignatureError;
+
+    fn from_str(s: &str) -> Result<Self, SignatureError> {
+        PublicKey::try_from(s.to_string())
+    }
+}
+
+impl FromStr for Signature {
+    type Err = SignatureError;
+
+    fn from_str(s: &str) -> Result<Self, SignatureError> {
+        Signature::try_from(s.to_string())
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(public_key: PublicKey) -> Self {
+        public_key.to_string()
+    }
+}
+
+impl From<Signature> for String {
+    fn from(signature: Signature) -> Self {
+        signature.to_string()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::model::test_util::alice_keypair;
+
+    use super::*;
+
+    #[test]
+    fn parse_json() {
+        let public_key = alice_keypair().public_key();
+        let public_key_json = serde_json::to_value(public_key.clone()).unwrap();
+        let parsed: PublicKey = serde_json::from_value(public_key_json).unwrap();
+        assert_eq!(parsed, public_key);
+
+        let signature = alice_keypair().sign(b"message");
+        let signature_json = serde_json::to_value(signature.clone()).unwrap();
+        let parsed: Signature = serde_json::from_value(signature_json).unwrap();
+        assert_eq!(parsed, signature);
+    }
+
+    #[test]
+    fn reject_invalid_length() {
+        // 31-byte string (62 hex chars)
+        let hex_str = "f780b958227ff0bf5795ede8f9f7eaac67e7e06666b043a400026cbd421ce2";
+        assert_eq!(
+            PublicKey::from_str(hex_str).unwrap_err(),
+            SignatureError::InvalidLength
+        );
+        assert_eq!(
+            Signature::from_str(hex_str).unwrap_err(),
+            SignatureError::InvalidLength
+        );
+    }
+
+    #[test]
+    fn reject_invalid_characters() {
+        let hex_str = "g780b958227ff0bf5795ede8f9f7eaac67e7e06666b043a400026cbd421ce28e";
+        assert_eq!(
+            PublicKey::from_str(hex_str).unwrap_err(),
+            SignatureError::InvalidFormat
+        );
+    }
+
+    #[test]
+    fn verify_valid_signature() {
+        let keypair = alice_keypair();
+        let signature = keypair.sign(b"message");
+
+        assert!(keypair.public_key().verify(b"message", &signature));
+    }
+
+    #[test]
+    fn reject_tampered_message() {
+        let keypair = alice_keypair();
+        let signature = keypair.sign(b"message");
+
+        assert!(!keypair.public_key().verify(b"tampered", &signature));
+    }
+
+    #[test]
+    fn reject_signature_from_other_key() {
+        let signature = alice_keypair().sign(b"message");
+        let other_public_key = PublicKey::from([1; PUBLIC_KEY_LEN]);
+
+        assert!(!other_public_key.verify(b"message", &signature));
+    }
+}
@@ -0,0 +1,95 @@
+// Some sample accounts to be used in tests all over the project
+// We export functions to workaround constant value restrictions in Rust
+use crypto::ed25519;
+use std::{fs, path::PathBuf};
+
+use super::{Address, Nonce, PublicKey, Signature, Transaction};
+
+// Holds the keys of an account, so tests can sign transactions on behalf of it
+pub struct KeyPair {
+    secret_key: [u8; 64],
+    public_key: PublicKey,
+}
+
+impl KeyPair {
+    pub fn from_seed(seed: &[u8; 32]) -> KeyPair {
+        let (secret_key, public_key) = ed25519::keypair(seed);
+
+        KeyPair {
+            secret_key,
+            public_key: PublicKey::from(public_key),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key.clone()
+    }
+
+    pub fn address(&self) -> Address {
+        Address::from(&self.public_key)
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        Signature::from(ed25519::signature(message, &self.secret_key))
+    }
+
+    // Creates a transfer from this account without fee, signed with its secret key
+    pub fn create_transaction(&self, recipient: Address, amount: u64, nonce: Nonce) -> Transaction {
+        self.create_transaction_with_fee(recipient, amount, 0, nonce)
+    }
+
+    // Creates a transfer from this account, signed with its secret key
+    pub fn create_transaction_with_fee(
+        &self,
+        recipient: Address,
+        amount: u64,
+        fee: u64,
+        nonce: Nonce,
+    ) -> Transaction {
+        let mut transaction = Transaction {
+            sender: self.address(),
+            recipient,
+            amount,
+            fee,
+            nonce,
+            public_key: self.public_key(),
+            signature: Signature::default(),
+        };
+        transaction.signature = self.sign(&transaction.signing_data());
+
+        transaction
+    }
+}
+
+pub fn alice_keypair() -> KeyPair {
+    KeyPair::from_seed(&[1; 32])
+}
+
+pub fn bob_keypair() -> KeyPair {
+    KeyPair::from_seed(&[2; 32])
+}
+
+pub fn carol_keypair() -> KeyPair {
+    KeyPair::from_seed(&[3; 32])
+}
+
+pub fn alice() -> Address {
+    alice_keypair().address()
+}
+
+pub fn bob() -> Address {
+    bob_keypair().address()
+}
+
+pub fn carol() -> Address {
+    carol_keypair().address()
+}
+
+// Returns an empty directory to store files during a test, removing the contents of previous runs
+pub fn create_temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust_blockchain_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
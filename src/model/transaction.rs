@@ -1,10 +1,187 @@
+use ethereum_types::U256;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use super::Address;
+use super::{
+    account_balance_map::Nonce,
+    encoding::{self, Encoder},
+    Address, PublicKey, Signature,
+};
+
+// Transaction hashes have the same representation as block hashes
+pub type TransactionHash = U256;
+
+#[derive(Error, PartialEq, Debug)]
+pub enum TransactionError {
+    #[error("Sender address does not match the public key")]
+    SenderPublicKeyMismatch,
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub sender: Address,
     pub recipient: Address,
     pub amount: u64,
+    pub fee: u64,
+    pub nonce: Nonce,
+    pub public_key: PublicKey,
+    pub signature: Signature,
+}
+
+impl Transaction {
+    // Canonical binary encoding of the fields covered by the signature
+    pub fn signing_data(&self) -> Vec<u8> {
+        self.encode_signed_fields(&mut Encoder::new()).finish()
+    }
+
+    // Canonical binary encoding of the whole transaction, including the public key and the signature
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_fields(&mut Encoder::new()).finish()
+    }
+
+    // Calculate the hash value of the transaction, including the signature
+    // It's used as the leaf of the transaction in the merkle tree of the block
+    pub fn hash(&self) -> TransactionHash {
+        encoding::calculate_hash(&self.encode())
+    }
+
+    // Writes the fields of the transaction, so it can be encoded as part of other values (like blocks)
+    pub fn encode_fields<'a>(&self, encoder: &'a mut Encoder) -> &'a mut Encoder {
+        self.encode_signed_fields(encoder)
+            .bytes(self.public_key.as_bytes())
+            .bytes(self.signature.as_bytes())
+    }
+
+    fn encode_signed_fields<'a>(&self, encoder: &'a mut Encoder) -> &'a mut Encoder {
+        encoder
+            .bytes(self.sender.as_bytes())
+            .bytes(self.recipient.as_bytes())
+            .u64(self.amount)
+            .u64(self.fee)
+            .u64(self.nonce)
+    }
+
+    // Size in bytes of the transaction as it's transmitted and stored in blocks
+    // Used to limit the space that transactions take in blocks and in the pool
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).unwrap().len()
+    }
+
+    // Checks that the transaction was signed by the owner of the sender address
+    pub fn verify_signature(&self) -> Result<(), TransactionError> {
+        // the sender address must be derived from the public key provided,
+        // otherwise anyone could sign with their own keys on behalf of other addresses
+        if self.sender != Address::from(&self.public_key) {
+            return Err(TransactionError::SenderPublicKeyMismatch);
+        }
+
+        if !self
+            .public_key
+            .verify(&self.signing_data(), &self.signature)
+        {
+            return Err(TransactionError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::test_util::{alice_keypair, bob, bob_keypair};
+
+    use super::*;
+
+    // Fields of a transaction from alice to bob, encoded
+    const ENCODING_VERSION: &str = "01";
+    const SENDER: &str = "34750f98bd59fcfc946da45aaabe933be154a4b5094e1c4abf42866505f3c97e";
+    const RECIPIENT: &str = "6a3803d5f059902a1c6dafbc9ba4729212f7caac08634cc3ae76b27529f03827";
+    const AMOUNT: &str = "000000000000000a";
+    const FEE: &str = "0000000000000001";
+    const NONCE: &str = "0000000000000002";
+    const PUBLIC_KEY: &str = "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c";
+    const SIGNATURE: &str = "3a4720946c3e42c2e96b8132636e7af42d8d5dc138f197285f74a0d191f8d58f\
+                             d7896d067c8c4c8cca25977bcfd19960339738b40f29260473bbea58a2cd6f0b";
+
+    // The encoding determines the signatures and hashes, so any change on it must be intentional
+    #[test]
+    fn should_match_golden_encoding() {
+        let transaction = alice_keypair().create_transaction_with_fee(bob(), 10, 1, 2);
+
+        let signed_fields = [ENCODING_VERSION, SENDER, RECIPIENT, AMOUNT, FEE, NONCE];
+        assert_eq!(
+            hex::encode(transaction.signing_data()),
+            signed_fields.concat()
+        );
+
+        let all_fields = [&signed_fields[..], &[PUBLIC_KEY, SIGNATURE]].concat();
+        assert_eq!(hex::encode(transaction.encode()), all_fields.concat());
+
+        let expected_hash = "0xa59d80ceb804814cf10072f12061e970fd6648e847f647d7e7e6ac786f9562ce";
+        assert_eq!(
+            serde_json::to_value(transaction.hash()).unwrap(),
+            expected_hash
+        );
+    }
+
+    #[test]
+    fn should_verify_signed_transaction() {
+        let transaction = alice_keypair().create_transaction(bob(), 10, 0);
+
+        assert!(transaction.verify_signature().is_ok());
+    }
+
+    #[test]
+    fn should_reject_tampered_transaction() {
+        let mut transaction = alice_keypair().create_transaction(bob(), 10, 0);
+        transaction.amount = 1_000;
+
+        let err = transaction.verify_signature().unwrap_err();
+        assert_eq!(err, TransactionError::InvalidSignature);
+    }
+
+    #[test]
+    fn should_reject_changed_fee() {
+        // the fee is signed, so nobody can make the sender pay more than agreed
+        let mut transaction = alice_keypair().create_transaction(bob(), 10, 0);
+        transaction.fee = 5;
+
+        let err = transaction.verify_signature().unwrap_err();
+        assert_eq!(err, TransactionError::InvalidSignature);
+    }
+
+    #[test]
+    fn should_reject_changed_nonce() {
+        // the nonce is signed, so a transaction cannot be replayed with a different nonce
+        let mut transaction = alice_keypair().create_transaction(bob(), 10, 0);
+        transaction.nonce = 1;
+
+        let err = transaction.verify_signature().unwrap_err();
+        assert_eq!(err, TransactionError::InvalidSignature);
+    }
+
+    #[test]
+    fn should_reject_unsigned_transaction() {
+        let mut transaction = alice_keypair().create_transaction(bob(), 10, 0);
+        transaction.signature = Signature::default();
+
+        let err = transaction.verify_signature().unwrap_err();
+        assert_eq!(err, TransactionError::InvalidSignature);
+    }
+
+    #[test]
+    fn should_reject_public_key_of_another_account() {
+        // bob signs a transaction that spends alice's funds
+        let alice = alice_keypair();
+        let bob = bob_keypair();
+        let mut transaction = alice.create_transaction(bob.address(), 10, 0);
+        transaction.public_key = bob.public_key();
+        transaction.signature = bob.sign(&transaction.signing_data());
+
+        let err = transaction.verify_signature().unwrap_err();
+        assert_eq!(err, TransactionError::SenderPublicKeyMismatch);
+    }
 }
@@ -1,108 +1,1003 @@
-use super::Transaction;
-use std::sync::{Arc, Mutex};
+use super::{
+    account_balance_map::AccountBalanceMapError, Account, AccountBalanceMap, Address, Nonce,
+    Transaction, TransactionHash,
+};
+use anyhow::Result;
+use chrono::Utc;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
 
 pub type TransactionVec = Vec<Transaction>;
 
+#[derive(Error, PartialEq, Debug)]
+pub enum TransactionPoolError {
+    #[error("Transaction is larger than the pool")]
+    TransactionTooLarge,
+
+    #[error("Transaction pool is full and the fee rate is too low")]
+    FeeRateTooLow,
+
+    #[error("Transaction amount must be greater than zero")]
+    ZeroAmount,
+
+    #[error("A transaction with the same nonce is already pending")]
+    DuplicateNonce,
+}
+
+// A copy of a transaction waiting in the pool, along with the data that the pool keeps about it
+#[derive(Debug, Clone)]
+pub struct PendingTransaction {
+    pub hash: TransactionHash,
+    pub transaction: Transaction,
+    pub size: usize,
+    // time (in milliseconds since the epoch) when the transaction was added to the pool
+    pub received_at: i64,
+}
+
+// A transaction waiting in the pool
+// We keep the size and hash to not serialize the transaction each time, and the arrival order to break ties
+#[derive(Debug, Clone)]
+struct PoolEntry {
+    transaction: Transaction,
+    hash: TransactionHash,
+    size: usize,
+    sequence: u64,
+    received_at: i64,
+}
+
+impl PoolEntry {
+    // Compares by fee per byte, so transactions paying more for the space they use are greater
+    // Between equal fee rates, older transactions are greater
+    fn cmp_priority(&self, other: &PoolEntry) -> Ordering {
+        let fee_rate = self.transaction.fee as u128 * other.size as u128;
+        let other_fee_rate = other.transaction.fee as u128 * self.size as u128;
+
+        fee_rate
+            .cmp(&other_fee_rate)
+            .then(other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl From<PoolEntry> for PendingTransaction {
+    fn from(entry: PoolEntry) -> PendingTransaction {
+        PendingTransaction {
+            hash: entry.hash,
+            transaction: entry.transaction,
+            size: entry.size,
+            received_at: entry.received_at,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    entries: Vec<PoolEntry>,
+    total_bytes: usize,
+    next_sequence: u64,
+}
+
 // We don't need to export this type because concurrency is encapsulated in this file
-type SyncedTransactionVec = Arc<Mutex<TransactionVec>>;
+type SyncedPoolState = Arc<Mutex<PoolState>>;
 
 // Represents a pool of unrealized transactions
 // Multiple threads can read/write concurrently to the pool
 #[derive(Debug, Clone)]
 pub struct TransactionPool {
-    transactions: SyncedTransactionVec,
+    max_transactions: usize,
+    max_bytes: usize,
+    state: SyncedPoolState,
 }
 
 // Basic operations in the transaction pool are encapsulated in the implementation
 // Encapsulates concurrency concerns, so external callers do not need to know how it's handled
 impl TransactionPool {
-    // Creates a empty transaction pool
-    pub fn new() -> TransactionPool {
+    // Creates a empty transaction pool, that will hold at most the indicated amount of transactions and bytes
+    pub fn new(max_transactions: usize, max_bytes: usize) -> TransactionPool {
         TransactionPool {
-            transactions: SyncedTransactionVec::default(),
+            max_transactions,
+            max_bytes,
+            state: SyncedPoolState::default(),
         }
     }
 
     // Adds a new transaction to the pool
-    pub fn add_transaction(&self, transaction: Transaction) {
-        // TODO: transactions should be validated before being included in the pool
-        let mut transactions = self.transactions.lock().unwrap();
-        transactions.push(transaction);
+    // The transaction is validated against the current account balances, so it can be included in a future block
+    // When the pool is full, the transactions with the lowest fee rate are evicted to make room,
+    // unless the new transaction has the lowest fee rate, in which case it's rejected
+    // Returns the hash of the transaction, so clients can follow it until it's included in a block
+    pub fn add_transaction(
+        &self,
+        transaction: Transaction,
+        account_balances: &AccountBalanceMap,
+    ) -> Result<TransactionHash> {
+        // only the owner of the sender address can spend its funds
+        transaction.verify_signature()?;
+
+        let mut state = self.state.lock().unwrap();
+        Self::validate_transaction(&state, &transaction, account_balances)?;
+
+        let entry = PoolEntry {
+            hash: transaction.hash(),
+            size: transaction.size(),
+            transaction,
+            sequence: state.next_sequence,
+            received_at: Utc::now().timestamp_millis(),
+        };
+
+        if entry.size > self.max_bytes {
+            return Err(TransactionPoolError::TransactionTooLarge.into());
+        }
+
+        self.make_room(&mut state, &entry)?;
+
+        let hash = entry.hash;
+        state.next_sequence += 1;
+        state.total_bytes += entry.size;
+        state.entries.push(entry);
         info!("transaction added");
+
+        Ok(hash)
     }
 
-    // Returns a copy of all transactions and empties the pool
+    // Returns a copy of the pending transaction with the indicated hash, if it's in the pool
+    pub fn get_transaction(&self, hash: &TransactionHash) -> Option<Transaction> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .entries
+            .iter()
+            .find(|entry| entry.hash == *hash)
+            .map(|entry| entry.transaction.clone())
+    }
+
+    pub fn count_transactions(&self) -> usize {
+        let state = self.state.lock().unwrap();
+
+        state.entries.len()
+    }
+
+    pub fn count_bytes(&self) -> usize {
+        let state = self.state.lock().unwrap();
+
+        state.total_bytes
+    }
+
+    // Returns a copy of all the transactions in the pool, from the oldest to the newest, without removing them
+    pub fn get_pending_transactions(&self) -> Vec<PendingTransaction> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .entries
+            .iter()
+            .cloned()
+            .map(PendingTransaction::from)
+            .collect()
+    }
+
+    // Removes the pending transaction with the indicated hash, returning it if it was in the pool
+    // The following transactions of the same sender are kept, but they are held back until the nonce is used again
+    pub fn remove_transaction(&self, hash: &TransactionHash) -> Option<PendingTransaction> {
+        let mut state = self.state.lock().unwrap();
+
+        let index = state.entries.iter().position(|entry| entry.hash == *hash)?;
+        let entry = state.entries.remove(index);
+        state.total_bytes -= entry.size;
+        info!("transaction removed from the pool");
+
+        Some(entry.into())
+    }
+
+    // Returns the balance that the address would have after processing all its pending transactions
+    // Incoming amounts are added and outgoing amounts (plus fees) are subtracted from the current balance
+    // Transactions with already used nonces are ignored, as they will never be included in a block
+    // Only the nonces of the senders that pay to the address are requested, so the caller does not need all the accounts
+    pub fn get_pending_balance(
+        &self,
+        address: &Address,
+        account: &Account,
+        get_nonce: impl Fn(&Address) -> Nonce,
+    ) -> u64 {
+        // the transactions are copied, so the pool is not locked while the nonces are requested
+        let transactions: TransactionVec = {
+            let state = self.state.lock().unwrap();
+            state
+                .entries
+                .iter()
+                .map(|entry| &entry.transaction)
+                .filter(|pending| pending.sender == *address || pending.recipient == *address)
+                .cloned()
+                .collect()
+        };
+
+        // we add up in a wider type, as the sum of multiple amounts could overflow
+        let mut balance = account.balance as i128;
+        for pending in transactions.iter() {
+            let sender_nonce = if pending.sender == *address {
+                account.nonce
+            } else {
+                get_nonce(&pending.sender)
+            };
+            if pending.nonce < sender_nonce {
+                continue;
+            }
+
+            if pending.sender == *address {
+                balance -= pending.amount as i128 + pending.fee as i128;
+            }
+            if pending.recipient == *address {
+                balance += pending.amount as i128;
+            }
+        }
+
+        balance.clamp(0, u64::MAX as i128) as u64
+    }
+
+    // Returns the transactions with the highest fee rate that are ready to be included in a block,
+    // up to the indicated amount of transactions and bytes, removing them from the pool
+    // A transaction is ready when its nonce follows the current nonce of the sender, or another selected transaction
+    // Transactions with future nonces are held back in the pool until the gap is filled,
+    // and transactions with already used nonces are discarded
     // This operation is safe to be called concurrently from multiple threads
-    pub fn pop(&self) -> TransactionVec {
-        // the "transactions" attribute is protected by a Mutex
+    pub fn pop(
+        &self,
+        account_balances: &AccountBalanceMap,
+        max_transactions: usize,
+        max_bytes: usize,
+    ) -> TransactionVec {
+        // the "state" attribute is protected by a Mutex
         // so only one thread at a time can access the value when the lock is held
         // preventing inconsitencies when adding new transactions while a pop is in course
-        let mut transactions = self.transactions.lock().unwrap();
-        let transactions_clone = transactions.clone();
-        transactions.clear();
+        let mut state = self.state.lock().unwrap();
+
+        // group the transactions of each sender, in the order they must be processed
+        // the sort is stable, so the arrival order is preserved between equal nonces
+        let mut entries = std::mem::take(&mut state.entries);
+        entries.sort_by_key(|entry| entry.transaction.nonce);
+        let mut queues = HashMap::<Address, VecDeque<PoolEntry>>::new();
+        for entry in entries {
+            let sender = entry.transaction.sender.clone();
+            queues.entry(sender).or_default().push_back(entry);
+        }
+
+        let mut next_nonces = HashMap::<Address, Nonce>::new();
+        for (sender, queue) in queues.iter_mut() {
+            let next_nonce = account_balances.get_nonce(sender);
+            Self::discard_used_nonces(queue, next_nonce);
+            next_nonces.insert(sender.clone(), next_nonce);
+        }
+
+        // in each step we select the ready transaction with the highest fee rate that still fits in the block
+        // balances may have changed since the transactions were added, so we check them again
+        let mut new_account_balances = account_balances.clone();
+        let mut selected = TransactionVec::new();
+        let mut selected_bytes = 0;
+        let mut blocked = HashMap::<Address, VecDeque<PoolEntry>>::new();
+        while selected.len() < max_transactions {
+            let best_sender = queues
+                .iter()
+                .filter_map(|(sender, queue)| queue.front().map(|entry| (sender, entry)))
+                .filter(|(sender, entry)| {
+                    entry.transaction.nonce == next_nonces[*sender]
+                        && selected_bytes + entry.size <= max_bytes
+                })
+                .max_by(|(_, a), (_, b)| a.cmp_priority(b))
+                .map(|(sender, _)| sender.clone());
+
+            let sender = match best_sender {
+                Some(sender) => sender,
+                None => break,
+            };
 
-        transactions_clone
+            // a sender that cannot afford its next transaction is not considered for this block
+            // but its transactions stay in the pool, as they can be valid after receiving funds
+            let queue = queues.get_mut(&sender).unwrap();
+            let tx = &queue.front().unwrap().transaction;
+            let transfer_result = new_account_balances.transfer(
+                &tx.sender,
+                &tx.recipient,
+                tx.amount,
+                tx.fee,
+                tx.nonce,
+            );
+            if transfer_result.is_err() {
+                let queue = queues.remove(&sender).unwrap();
+                blocked.insert(sender, queue);
+                continue;
+            }
+
+            // selecting a transaction makes the next one of the same sender ready
+            let entry = queue.pop_front().unwrap();
+            let next_nonce = next_nonces.get_mut(&sender).unwrap();
+            *next_nonce += 1;
+            Self::discard_used_nonces(queue, *next_nonce);
+
+            selected_bytes += entry.size;
+            selected.push(entry.transaction);
+        }
+
+        // the rest of the transactions stay in the pool
+        let mut remaining: Vec<PoolEntry> = queues
+            .into_values()
+            .chain(blocked.into_values())
+            .flatten()
+            .collect();
+        remaining.sort_by_key(|entry| entry.sequence);
+        state.total_bytes = remaining.iter().map(|entry| entry.size).sum();
+        state.entries = remaining;
+
+        selected
+    }
+
+    // Returns to the pool the transactions that were popped but did not end up in the blockchain,
+    // for example when the mined block lost the race against a block from a peer
+    // Transactions already included in the blockchain (their nonce is used) are dropped,
+    // and the rest go through the usual validation, so the ones that are no longer valid are dropped too
+    pub fn requeue(&self, transactions: TransactionVec, account_balances: &AccountBalanceMap) {
+        for transaction in transactions {
+            if transaction.nonce < account_balances.get_nonce(&transaction.sender) {
+                info!("dropping transaction already included in the blockchain");
+                continue;
+            }
+
+            if let Err(error) = self.add_transaction(transaction, account_balances) {
+                warn!("dropping transaction that could not be requeued: {}", error);
+            }
+        }
+    }
+
+    // Checks that the transaction can be processed after all the pending ones of the same sender
+    // Transactions with future nonces are valid, but the sender must be able to pay all of them
+    fn validate_transaction(
+        state: &PoolState,
+        transaction: &Transaction,
+        account_balances: &AccountBalanceMap,
+    ) -> Result<()> {
+        if transaction.amount == 0 {
+            return Err(TransactionPoolError::ZeroAmount.into());
+        }
+
+        let sender = &transaction.sender;
+        if !account_balances.contains(sender) {
+            return Err(AccountBalanceMapError::SenderAccountDoesNotExist.into());
+        }
+
+        let next_nonce = account_balances.get_nonce(sender);
+        if transaction.nonce < next_nonce {
+            return Err(AccountBalanceMapError::NonceAlreadyUsed.into());
+        }
+
+        // the sender must have enough funds for this transaction plus all its other pending spends
+        // we add up in a wider type, as the sum of multiple amounts could overflow
+        let mut total_spent = transaction.amount as u128 + transaction.fee as u128;
+        for entry in state.entries.iter() {
+            let pending = &entry.transaction;
+            if pending.sender != *sender || pending.nonce < next_nonce {
+                continue;
+            }
+
+            if pending.nonce == transaction.nonce {
+                return Err(TransactionPoolError::DuplicateNonce.into());
+            }
+
+            total_spent += pending.amount as u128 + pending.fee as u128;
+        }
+
+        if total_spent > account_balances.get_balance(sender) as u128 {
+            return Err(AccountBalanceMapError::InsufficientFunds.into());
+        }
+
+        Ok(())
+    }
+
+    // Evicts the transactions with lower fee rate than the new entry, until there is room for it
+    // If there is no room even after evicting all of them, nothing is evicted and an error is returned
+    fn make_room(&self, state: &mut PoolState, new_entry: &PoolEntry) -> Result<()> {
+        let mut count = state.entries.len() + 1;
+        let mut bytes = state.total_bytes + new_entry.size;
+
+        // candidates for eviction, from the lowest to the highest fee rate
+        let mut candidates: Vec<usize> = (0..state.entries.len()).collect();
+        candidates.sort_by(|a, b| state.entries[*a].cmp_priority(&state.entries[*b]));
+
+        let mut evicted = Vec::new();
+        for index in candidates {
+            if count <= self.max_transactions && bytes <= self.max_bytes {
+                break;
+            }
+
+            let entry = &state.entries[index];
+            if entry.cmp_priority(new_entry) != Ordering::Less {
+                break;
+            }
+
+            evicted.push(index);
+            count -= 1;
+            bytes -= entry.size;
+        }
+
+        if count > self.max_transactions || bytes > self.max_bytes {
+            return Err(TransactionPoolError::FeeRateTooLow.into());
+        }
+
+        // remove from the highest index, so the rest of indexes remain valid
+        evicted.sort_unstable_by(|a, b| b.cmp(a));
+        for index in evicted {
+            let entry = state.entries.remove(index);
+            state.total_bytes -= entry.size;
+            info!("transaction evicted from the pool");
+        }
+
+        Ok(())
+    }
+
+    fn discard_used_nonces(queue: &mut VecDeque<PoolEntry>, next_nonce: Nonce) {
+        while let Some(entry) = queue.front() {
+            if entry.transaction.nonce >= next_nonce {
+                return;
+            }
+
+            queue.pop_front();
+            info!("discarding transaction with already used nonce");
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::model::test_util::{alice, bob};
+    use crate::model::{
+        test_util::{alice, alice_keypair, bob, bob_keypair, carol, carol_keypair},
+        transaction::TransactionError,
+    };
 
     use super::*;
 
+    const NO_LIMIT: usize = usize::MAX;
+
     #[test]
     fn should_be_empty_after_creation() {
-        let transaction_pool = TransactionPool::new();
+        let transaction_pool = create_unlimited_pool();
 
-        let transactions = transaction_pool.pop();
+        let transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
         assert!(transactions.is_empty());
     }
 
     #[test]
     fn should_pop_single_value() {
-        let transaction_pool = TransactionPool::new();
+        let transaction_pool = create_unlimited_pool();
 
         // add a new transaction to the pool
-        let transaction = create_mock_transaction(1);
-        transaction_pool.add_transaction(transaction.clone());
+        let transaction = create_mock_transaction(1, 0);
+        transaction_pool
+            .add_transaction(transaction.clone(), &funded_balances())
+            .unwrap();
 
         // pop the values and check that the transaction is included
-        let mut transactions = transaction_pool.pop();
+        let mut transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
         assert_eq!(transactions.len(), 1);
         assert_eq!(transactions[0].amount, transaction.amount);
 
         // after the previous pop, the pool should still be empty
-        transactions = transaction_pool.pop();
+        transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
         assert!(transactions.is_empty());
     }
 
+    #[test]
+    fn should_find_pending_transactions_by_hash() {
+        let transaction_pool = create_unlimited_pool();
+
+        // the hash of the transaction is returned when adding it
+        let transaction = create_mock_transaction(1, 0);
+        let hash = transaction_pool
+            .add_transaction(transaction.clone(), &funded_balances())
+            .unwrap();
+        assert_eq!(hash, transaction.hash());
+
+        let pending = transaction_pool.get_transaction(&hash).unwrap();
+        assert_eq!(pending.signature, transaction.signature);
+
+        // it's not pending anymore after being popped
+        transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
+        assert!(transaction_pool.get_transaction(&hash).is_none());
+    }
+
+    #[test]
+    fn should_list_and_remove_pending_transactions() {
+        let transaction_pool = create_unlimited_pool();
+        let transaction_1 = create_mock_transaction(1, 0);
+        let transaction_2 = create_mock_transaction(2, 1);
+        for transaction in [transaction_1.clone(), transaction_2.clone()] {
+            transaction_pool
+                .add_transaction(transaction, &funded_balances())
+                .unwrap();
+        }
+
+        // listing the transactions does not remove them, and they are returned in arrival order
+        for _ in 0..2 {
+            let pending = transaction_pool.get_pending_transactions();
+            let hashes: Vec<TransactionHash> = pending.iter().map(|entry| entry.hash).collect();
+            assert_eq!(hashes, vec![transaction_1.hash(), transaction_2.hash()]);
+            assert_eq!(pending[0].size, transaction_1.size());
+            assert!(pending[0].received_at <= pending[1].received_at);
+        }
+
+        // a removed transaction is not pending anymore, and the next one is held back without its nonce
+        let removed = transaction_pool
+            .remove_transaction(&transaction_1.hash())
+            .unwrap();
+        assert_eq!(removed.transaction.signature, transaction_1.signature);
+        assert!(transaction_pool
+            .remove_transaction(&transaction_1.hash())
+            .is_none());
+        assert_eq!(transaction_pool.get_pending_transactions().len(), 1);
+        assert!(transaction_pool
+            .pop(&funded_balances(), NO_LIMIT, NO_LIMIT)
+            .is_empty());
+    }
+
+    #[test]
+    fn should_calculate_pending_balance() {
+        let transaction_pool = create_unlimited_pool();
+        let account_balances = funded_balances();
+
+        // alice sends to bob and then receives from carol
+        let transactions = [
+            alice_keypair().create_transaction_with_fee(bob(), 100, 5, 0),
+            carol_keypair().create_transaction(alice(), 30, 0),
+        ];
+        for transaction in transactions {
+            transaction_pool
+                .add_transaction(transaction, &account_balances)
+                .unwrap();
+        }
+
+        let pending_balance = |address| {
+            let account = account_balances.get_account(&address);
+            transaction_pool.get_pending_balance(&address, &account, |sender| {
+                account_balances.get_nonce(sender)
+            })
+        };
+        assert_eq!(pending_balance(alice()), 1_000 - 105 + 30);
+        assert_eq!(pending_balance(bob()), 1_000 + 100);
+        assert_eq!(pending_balance(carol()), 1_000 - 30);
+
+        // once alice's nonce is used, her transaction is not pending anymore
+        let mut account_balances = account_balances.clone();
+        account_balances
+            .transfer(&alice(), &carol(), 1, 0, 0)
+            .unwrap();
+        let account = account_balances.get_account(&alice());
+        let pending_balance = transaction_pool.get_pending_balance(&alice(), &account, |sender| {
+            account_balances.get_nonce(sender)
+        });
+        assert_eq!(pending_balance, 1_000 - 1 + 30);
+    }
+
     #[test]
     fn should_pop_multiple_values() {
-        let transaction_pool = TransactionPool::new();
+        let transaction_pool = create_unlimited_pool();
 
         // add a new transaction to the pool
-        let transaction_a = create_mock_transaction(1);
-        let transaction_b = create_mock_transaction(2);
-        transaction_pool.add_transaction(transaction_a.clone());
-        transaction_pool.add_transaction(transaction_b.clone());
+        let transaction_a = create_mock_transaction(1, 0);
+        let transaction_b = create_mock_transaction(2, 1);
+        transaction_pool
+            .add_transaction(transaction_a.clone(), &funded_balances())
+            .unwrap();
+        transaction_pool
+            .add_transaction(transaction_b.clone(), &funded_balances())
+            .unwrap();
 
         // pop the values and check that the transactions are included
-        let mut transactions = transaction_pool.pop();
+        let mut transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
         assert_eq!(transactions.len(), 2);
         assert_eq!(transactions[0].amount, transaction_a.amount);
         assert_eq!(transactions[1].amount, transaction_b.amount);
 
         // after the previous pop, the pool should still be empty
-        transactions = transaction_pool.pop();
+        transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
         assert!(transactions.is_empty());
     }
 
-    fn create_mock_transaction(amount: u64) -> Transaction {
-        Transaction {
-            sender: alice(),
-            recipient: bob(),
-            amount: amount,
+    #[test]
+    fn should_reject_unsigned_transaction() {
+        let transaction_pool = create_unlimited_pool();
+
+        let mut transaction = create_mock_transaction(1, 0);
+        transaction.signature = Default::default();
+
+        let err = transaction_pool
+            .add_transaction(transaction, &funded_balances())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<TransactionError>().unwrap(),
+            TransactionError::InvalidSignature
+        );
+        assert!(transaction_pool
+            .pop(&funded_balances(), NO_LIMIT, NO_LIMIT)
+            .is_empty());
+    }
+
+    #[test]
+    fn should_hold_back_transactions_with_future_nonce() {
+        let transaction_pool = create_unlimited_pool();
+        let account_balances = funded_balances();
+
+        // the first nonce of alice is 0, so a transaction with nonce 1 must wait
+        transaction_pool
+            .add_transaction(create_mock_transaction(2, 1), &funded_balances())
+            .unwrap();
+        assert!(transaction_pool
+            .pop(&account_balances, NO_LIMIT, NO_LIMIT)
+            .is_empty());
+
+        // when the gap is filled, both transactions are ready in the order of their nonces
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 0), &funded_balances())
+            .unwrap();
+        let transactions = transaction_pool.pop(&account_balances, NO_LIMIT, NO_LIMIT);
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].nonce, 0);
+        assert_eq!(transactions[1].nonce, 1);
+    }
+
+    #[test]
+    fn should_track_nonces_per_sender() {
+        let transaction_pool = create_unlimited_pool();
+        let account_balances = funded_balances();
+
+        // a gap in the nonces of alice does not affect bob
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 1), &funded_balances())
+            .unwrap();
+        transaction_pool
+            .add_transaction(
+                bob_keypair().create_transaction(alice(), 1, 0),
+                &funded_balances(),
+            )
+            .unwrap();
+
+        let transactions = transaction_pool.pop(&account_balances, NO_LIMIT, NO_LIMIT);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].sender, bob());
+    }
+
+    #[test]
+    fn should_discard_transactions_with_used_nonce() {
+        let transaction_pool = create_unlimited_pool();
+
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 0), &funded_balances())
+            .unwrap();
+
+        // alice sends a different transaction with nonce 0, that is included in a block first
+        let mut account_balances = funded_balances();
+        account_balances
+            .transfer(&alice(), &bob(), 2, 0, 0)
+            .unwrap();
+        assert!(transaction_pool
+            .pop(&account_balances, NO_LIMIT, NO_LIMIT)
+            .is_empty());
+
+        // the transaction is not held back, but removed from the pool
+        assert!(transaction_pool
+            .pop(&funded_balances(), NO_LIMIT, NO_LIMIT)
+            .is_empty());
+    }
+
+    #[test]
+    fn should_pop_highest_fee_rate_first() {
+        let transaction_pool = create_unlimited_pool();
+
+        // all transactions have the same size, so the fee rate only depends on the fee
+        let cheap = alice_keypair().create_transaction_with_fee(bob(), 1, 1, 0);
+        let expensive = bob_keypair().create_transaction_with_fee(alice(), 1, 9, 0);
+        let medium = carol_keypair().create_transaction_with_fee(bob(), 1, 5, 0);
+        for transaction in [cheap, expensive, medium] {
+            transaction_pool
+                .add_transaction(transaction, &funded_balances())
+                .unwrap();
         }
+
+        let transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
+        let fees: Vec<u64> = transactions.iter().map(|tx| tx.fee).collect();
+        assert_eq!(fees, vec![9, 5, 1]);
+    }
+
+    #[test]
+    fn should_respect_nonce_order_over_fee_rate() {
+        let transaction_pool = create_unlimited_pool();
+
+        // the second transaction of alice pays more, but it can only go after the first one
+        transaction_pool
+            .add_transaction(
+                alice_keypair().create_transaction_with_fee(bob(), 1, 1, 0),
+                &funded_balances(),
+            )
+            .unwrap();
+        transaction_pool
+            .add_transaction(
+                alice_keypair().create_transaction_with_fee(bob(), 1, 9, 1),
+                &funded_balances(),
+            )
+            .unwrap();
+        transaction_pool
+            .add_transaction(
+                bob_keypair().create_transaction_with_fee(alice(), 1, 5, 0),
+                &funded_balances(),
+            )
+            .unwrap();
+
+        let transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
+        let fees: Vec<u64> = transactions.iter().map(|tx| tx.fee).collect();
+        assert_eq!(fees, vec![5, 1, 9]);
+    }
+
+    #[test]
+    fn should_pop_only_the_best_transactions_that_fit() {
+        let transaction_pool = create_unlimited_pool();
+        let account_balances = funded_balances();
+
+        let cheap = alice_keypair().create_transaction_with_fee(bob(), 1, 1, 0);
+        let expensive = bob_keypair().create_transaction_with_fee(alice(), 1, 9, 0);
+        let size = cheap.size();
+        transaction_pool
+            .add_transaction(cheap, &funded_balances())
+            .unwrap();
+        transaction_pool
+            .add_transaction(expensive, &funded_balances())
+            .unwrap();
+
+        // only one transaction fits in the limits, so the cheapest one remains in the pool
+        let transactions = transaction_pool.pop(&account_balances, 1, NO_LIMIT);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].fee, 9);
+
+        // the remaining transaction does not fit in less bytes than its size
+        let transactions = transaction_pool.pop(&account_balances, NO_LIMIT, size - 1);
+        assert!(transactions.is_empty());
+
+        let transactions = transaction_pool.pop(&account_balances, NO_LIMIT, size);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].fee, 1);
+    }
+
+    #[test]
+    fn should_evict_lowest_fee_rate_when_full() {
+        let transaction_pool = TransactionPool::new(2, NO_LIMIT);
+
+        transaction_pool
+            .add_transaction(
+                alice_keypair().create_transaction_with_fee(bob(), 1, 5, 0),
+                &funded_balances(),
+            )
+            .unwrap();
+        transaction_pool
+            .add_transaction(
+                bob_keypair().create_transaction_with_fee(alice(), 1, 1, 0),
+                &funded_balances(),
+            )
+            .unwrap();
+
+        // the pool is full, so the transaction with fee 1 is evicted
+        transaction_pool
+            .add_transaction(
+                carol_keypair().create_transaction_with_fee(bob(), 1, 3, 0),
+                &funded_balances(),
+            )
+            .unwrap();
+
+        let transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
+        let fees: Vec<u64> = transactions.iter().map(|tx| tx.fee).collect();
+        assert_eq!(fees, vec![5, 3]);
+    }
+
+    #[test]
+    fn should_reject_lowest_fee_rate_when_full() {
+        let transaction = create_mock_transaction(1, 0);
+        let transaction_pool = TransactionPool::new(NO_LIMIT, transaction.size());
+        transaction_pool
+            .add_transaction(transaction, &funded_balances())
+            .unwrap();
+
+        // the new transaction does not pay more than the one in the pool, so it's rejected
+        let err = transaction_pool
+            .add_transaction(
+                bob_keypair().create_transaction(alice(), 1, 0),
+                &funded_balances(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<TransactionPoolError>().unwrap(),
+            TransactionPoolError::FeeRateTooLow
+        );
+
+        // and the pool still keeps the previous transaction
+        let transactions = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].sender, alice());
+    }
+
+    #[test]
+    fn should_reject_transaction_larger_than_pool() {
+        let transaction = create_mock_transaction(1, 0);
+        let transaction_pool = TransactionPool::new(NO_LIMIT, transaction.size() - 1);
+
+        let err = transaction_pool
+            .add_transaction(transaction, &funded_balances())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<TransactionPoolError>().unwrap(),
+            TransactionPoolError::TransactionTooLarge
+        );
+    }
+
+    // alice, bob and carol have funds to send transactions
+    fn funded_balances() -> AccountBalanceMap {
+        let mut account_balances = AccountBalanceMap::default();
+        account_balances.add_amount(&alice(), 1_000);
+        account_balances.add_amount(&bob(), 1_000);
+        account_balances.add_amount(&carol(), 1_000);
+
+        account_balances
+    }
+
+    #[test]
+    fn should_reject_transaction_from_non_existent_sender() {
+        let transaction_pool = create_unlimited_pool();
+
+        let transaction = create_mock_transaction(1, 0);
+        let err = transaction_pool
+            .add_transaction(transaction, &AccountBalanceMap::default())
+            .unwrap_err();
+        assert_balance_err(err, AccountBalanceMapError::SenderAccountDoesNotExist);
+    }
+
+    #[test]
+    fn should_reject_transaction_with_used_nonce() {
+        let transaction_pool = create_unlimited_pool();
+
+        // alice already sent a transaction with nonce 0
+        let mut account_balances = funded_balances();
+        account_balances
+            .transfer(&alice(), &bob(), 1, 0, 0)
+            .unwrap();
+
+        let err = transaction_pool
+            .add_transaction(create_mock_transaction(1, 0), &account_balances)
+            .unwrap_err();
+        assert_balance_err(err, AccountBalanceMapError::NonceAlreadyUsed);
+    }
+
+    #[test]
+    fn should_reject_duplicate_nonce() {
+        let transaction_pool = create_unlimited_pool();
+
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 0), &funded_balances())
+            .unwrap();
+        let err = transaction_pool
+            .add_transaction(create_mock_transaction(2, 0), &funded_balances())
+            .unwrap_err();
+        assert_pool_err(err, TransactionPoolError::DuplicateNonce);
+    }
+
+    #[test]
+    fn should_reject_zero_amount() {
+        let transaction_pool = create_unlimited_pool();
+
+        let err = transaction_pool
+            .add_transaction(create_mock_transaction(0, 0), &funded_balances())
+            .unwrap_err();
+        assert_pool_err(err, TransactionPoolError::ZeroAmount);
+    }
+
+    #[test]
+    fn should_reject_insufficient_funds_including_pending_spends() {
+        let transaction_pool = create_unlimited_pool();
+
+        // alice has 1000, so each transaction is affordable on its own but not both of them
+        transaction_pool
+            .add_transaction(
+                alice_keypair().create_transaction_with_fee(bob(), 600, 10, 0),
+                &funded_balances(),
+            )
+            .unwrap();
+        let err = transaction_pool
+            .add_transaction(
+                alice_keypair().create_transaction_with_fee(bob(), 390, 1, 1),
+                &funded_balances(),
+            )
+            .unwrap_err();
+        assert_balance_err(err, AccountBalanceMapError::InsufficientFunds);
+
+        // but the exact remaining amount is still affordable
+        transaction_pool
+            .add_transaction(
+                alice_keypair().create_transaction_with_fee(bob(), 390, 0, 1),
+                &funded_balances(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn should_not_pop_transactions_that_are_no_longer_affordable() {
+        let transaction_pool = create_unlimited_pool();
+        transaction_pool
+            .add_transaction(create_mock_transaction(600, 0), &funded_balances())
+            .unwrap();
+
+        // alice spends her funds in a block mined somewhere else, with a different transaction
+        let mut account_balances = funded_balances();
+        account_balances
+            .transfer(&alice(), &bob(), 500, 0, 0)
+            .unwrap();
+        account_balances.add_amount(&alice(), 100);
+        assert!(transaction_pool
+            .pop(&account_balances, NO_LIMIT, NO_LIMIT)
+            .is_empty());
+    }
+
+    #[test]
+    fn should_requeue_transactions_not_included_in_the_blockchain() {
+        let transaction_pool = create_unlimited_pool();
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 0), &funded_balances())
+            .unwrap();
+        transaction_pool
+            .add_transaction(create_mock_transaction(2, 1), &funded_balances())
+            .unwrap();
+        let popped = transaction_pool.pop(&funded_balances(), NO_LIMIT, NO_LIMIT);
+        assert_eq!(popped.len(), 2);
+
+        // the winning block only included the first transaction
+        let mut account_balances = funded_balances();
+        account_balances
+            .transfer(&alice(), &bob(), 1, 0, 0)
+            .unwrap();
+        transaction_pool.requeue(popped, &account_balances);
+
+        // so only the second one is back in the pool
+        let transactions = transaction_pool.pop(&account_balances, NO_LIMIT, NO_LIMIT);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].nonce, 1);
+    }
+
+    #[test]
+    fn should_drop_requeued_transactions_that_are_no_longer_valid() {
+        let transaction_pool = create_unlimited_pool();
+        let transaction = create_mock_transaction(1_000, 0);
+
+        // the sender spent its funds in the winning block with a different transaction
+        let mut account_balances = funded_balances();
+        account_balances
+            .transfer(&alice(), &bob(), 1, 0, 0)
+            .unwrap();
+        let pending = create_mock_transaction(1_000, 1);
+        transaction_pool.requeue(vec![transaction, pending], &account_balances);
+
+        let transactions = transaction_pool.pop(&account_balances, NO_LIMIT, NO_LIMIT);
+        assert!(transactions.is_empty());
+    }
+
+    fn assert_balance_err(err: anyhow::Error, error_type: AccountBalanceMapError) {
+        assert_eq!(
+            err.downcast::<AccountBalanceMapError>().unwrap(),
+            error_type
+        );
+    }
+
+    fn assert_pool_err(err: anyhow::Error, error_type: TransactionPoolError) {
+        assert_eq!(err.downcast::<TransactionPoolError>().unwrap(), error_type);
+    }
+
+    fn create_unlimited_pool() -> TransactionPool {
+        TransactionPool::new(NO_LIMIT, NO_LIMIT)
+    }
+
+    fn create_mock_transaction(amount: u64, nonce: Nonce) -> Transaction {
+        alice_keypair().create_transaction(bob(), amount, nonce)
     }
 }
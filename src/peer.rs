@@ -1,19 +1,49 @@
-use std::panic;
+mod address_book;
+mod gossip;
+mod handshake;
+
+pub use address_book::{AddressBook, KnownPeer, PeerAddress};
+pub use gossip::{Gossip, Relay, RELAY_HEADER};
+pub use handshake::{Handshake, HandshakeError, CAPABILITY_PEERS};
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
 
 use crate::{
     model::{Block, Blockchain},
     util::{
         execution::{sleep_millis, Runnable},
-        Context,
+        metrics::SOURCE_PEER,
+        Context, Metrics,
     },
 };
 use anyhow::Result;
-use isahc::{ReadResponseExt, Request};
+use isahc::{config::Configurable, http::request::Builder, ReadResponseExt, Request};
+use serde::de::DeserializeOwned;
+
+// Maximum amount of blocks requested to a peer at once, it must not exceed the page limit of the API
+const SYNC_PAGE_LIMIT: usize = 100;
+
+// Time limits of the requests to peers, so a peer that does not answer cannot block the node forever
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const PEER_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Every request to a peer must be built with the time limits
+fn create_peer_request(builder: Builder) -> Builder {
+    builder
+        .connect_timeout(PEER_CONNECT_TIMEOUT)
+        .timeout(PEER_REQUEST_TIMEOUT)
+}
 
 pub struct Peer {
-    peer_addresses: Vec<String>,
+    network_id: String,
+    address_book: AddressBook,
     blockchain: Blockchain,
     peer_sync_ms: u64,
+    metrics: Metrics,
+    gossip: Gossip,
 }
 
 impl Runnable for Peer {
@@ -25,29 +55,41 @@ impl Runnable for Peer {
 impl Peer {
     pub fn new(context: &Context) -> Peer {
         Peer {
-            peer_addresses: context.config.peers.clone(),
+            network_id: context.config.network_id.clone(),
+            address_book: context.address_book.clone(),
             blockchain: context.blockchain.clone(),
             peer_sync_ms: context.config.peer_sync_ms,
+            metrics: context.metrics.clone(),
+            gossip: context.gossip.clone(),
         }
     }
 
     pub fn start(&self) -> Result<()> {
-        if self.peer_addresses.is_empty() {
-            info!("No peers configured, exiting peer sync system");
-            return Ok(());
-        }
-
         info!(
             "start peer system with peers: {}",
-            self.peer_addresses.join(", ")
+            self.address_book.get_addresses().join(", ")
         );
 
-        // At regular intervals of time, we try to sync new blocks from our peers
-        let mut last_sent_block_index = self.get_last_block_index();
+        // At regular intervals of time, we try to sync new blocks and peers from our peers
+        // so we catch up with the blocks that were not relayed to us (e.g. while this node was down)
+        // The peers learned in each round are contacted in the next one
+        // Each round starts with a handshake, so peers that become incompatible are not synced anymore
         loop {
-            self.try_receive_new_blocks();
-            self.try_send_new_blocks(last_sent_block_index);
-            last_sent_block_index = self.get_last_block_index();
+            for address in self.address_book.get_addresses() {
+                let peer_handshake = match self.try_handshake(&address) {
+                    Some(peer_handshake) => peer_handshake,
+                    None => continue,
+                };
+
+                self.try_receive_new_blocks(&address);
+                if peer_handshake.has_capability(CAPABILITY_PEERS) {
+                    self.try_exchange_peers(&address);
+                }
+            }
+
+            if let Err(error) = self.address_book.save() {
+                error!("Could not save the known peers: {}", error);
+            }
             sleep_millis(self.peer_sync_ms);
         }
     }
@@ -56,116 +98,227 @@ impl Peer {
         self.blockchain.get_last_block().index as usize
     }
 
-    // Retrieve new blocks from all peers and add them to the blockchain
-    fn try_receive_new_blocks(&self) {
-        for address in self.peer_addresses.iter() {
-            // we don't want to panic if one peer is down or not working properly
-            let result = panic::catch_unwind(|| {
-                let new_blocks = self.get_new_blocks_from_peer(address);
+    // Check that a peer follows the same rules as this node, returning its handshake if so
+    // Incompatible peers are not synced, and no blocks or transactions are relayed to them
+    fn try_handshake(&self, address: &str) -> Option<Handshake> {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.handshake_with_peer(address)));
 
-                if !new_blocks.is_empty() {
-                    self.add_new_blocks(&new_blocks);
+        match result {
+            Ok(Ok(peer_handshake)) => {
+                // only compatible peers get blocks and transactions relayed
+                if self
+                    .address_book
+                    .record_compatible(address, peer_handshake.best_height)
+                {
+                    info!("Connected to peer {}", address);
                 }
-            });
-
-            // if a peer is not working, we simply log it and ignore the error
-            if result.is_err() {
-                error!("Could not sync blocks from peer {}", address);
+                Some(peer_handshake)
+            }
+            Ok(Err(error)) => {
+                warn!("Incompatible peer {}: {}", address, error);
+                self.address_book.record_incompatible(address);
+                None
+            }
+            // if a peer is not working, we cannot sync with it either
+            Err(_) => {
+                self.record_sync_failure(address);
+                None
             }
         }
     }
 
-    // Try to add a bunch of new blocks to our blockchain
-    fn add_new_blocks(&self, new_blocks: &[Block]) {
-        for block in new_blocks.iter() {
-            let result = self.blockchain.add_block(block.clone());
+    // Send the handshake of this node to a peer, which answers with its own if they are compatible
+    // Both sides check the compatibility, so it does not matter which one is more restrictive
+    fn handshake_with_peer(&self, address: &str) -> Result<Handshake, HandshakeError> {
+        let handshake = Handshake::new(&self.network_id, &self.blockchain);
+        let uri = format!("{}/handshake", address);
+        let body = serde_json::to_string(&handshake).unwrap();
 
-            // if a block is invalid, no point in trying to add the next ones
-            if result.is_err() {
-                error!("Could not add peer block {} to the blockchain", block.index);
-                return;
-            }
+        let request = create_peer_request(Request::post(uri))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .unwrap();
+        let mut response = isahc::send(request).unwrap();
 
-            info!("Added new peer block {} to the blockchain", block.index);
+        let raw_body = response.text().unwrap();
+        if response.status().as_u16() != 200 {
+            return Err(HandshakeError::Refused(raw_body));
         }
-    }
 
-    // Retrieve only the new blocks from a peer
-    fn get_new_blocks_from_peer(&self, address: &str) -> Vec<Block> {
-        // we need to know the last block index in our blockchain
-        let our_last_index = self.blockchain.get_last_block().index as usize;
+        let peer_handshake: Handshake = serde_json::from_str(&raw_body).unwrap();
+        handshake.check_compatibility(&peer_handshake)?;
 
-        // we retrieve all the blocks from the peer
-        let peer_blocks = self.get_blocks_from_peer(address);
-        let peer_last_index = peer_blocks.last().unwrap().index as usize;
+        Ok(peer_handshake)
+    }
 
-        // Check if the peer has new blocks
-        if peer_last_index <= our_last_index {
-            return Vec::<Block>::new();
+    // Retrieve new blocks from a peer and add them to the blockchain
+    fn try_receive_new_blocks(&self, address: &str) {
+        // we don't want to panic if one peer is down or not working properly
+        // a panic cannot leave the blockchain or the metrics in an inconsistent state, as they are synchronized
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.receive_new_blocks_from_peer(address);
+        }));
+
+        match result {
+            Ok(_) => self.address_book.record_sync(address, true),
+            Err(_) => self.record_sync_failure(address),
         }
+    }
 
-        // The peer do have new blocks, and we return ONLY the new ones
-        let first_new = our_last_index + 1;
-        let last_new = peer_last_index;
-        let new_blocks_range = first_new..=last_new;
-        peer_blocks.get(new_blocks_range).unwrap().to_vec()
+    // if a peer is not working, we simply log it and ignore the error
+    fn record_sync_failure(&self, address: &str) {
+        error!("Could not sync blocks from peer {}", address);
+        self.metrics
+            .peer_sync_failures
+            .with_label_values(&[address])
+            .inc();
+        self.address_book.record_sync(address, false);
     }
 
-    // Retrieve ALL blocks from a peer
-    fn get_blocks_from_peer(&self, address: &str) -> Vec<Block> {
-        let uri = format!("{}/blocks", address);
-        let mut response = isahc::get(uri).unwrap();
+    // Learn the peers known by a peer, and let it know about this node
+    fn try_exchange_peers(&self, address: &str) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let uri = format!("{}/peers", address);
+            let peers: Vec<KnownPeer> = Peer::get_json_from_peer(&uri);
+            for peer in peers {
+                if self.address_book.add_peer(&peer.address) {
+                    info!("Learned new peer {} from {}", peer.address, address);
+                }
+            }
 
-        // check that the response is sucessful
-        assert_eq!(response.status().as_u16(), 200);
+            self.announce_to_peer(address);
+        }));
 
-        // parse and return the list of blocks from the response body
-        let raw_body = response.text().unwrap();
-        serde_json::from_str(&raw_body).unwrap()
+        if result.is_err() {
+            error!("Could not exchange peers with {}", address);
+        }
     }
 
-    // Try to broadcast all new blocks to peers since last time we broadcasted
-    fn try_send_new_blocks(&self, last_send_block_index: usize) {
-        let new_blocks = self.get_new_blocks_since(last_send_block_index);
+    // Send the address of this node to a peer, so the peer can contact this node and share it with others
+    // Nodes without a public address are not announced, so they only contact their peers
+    fn announce_to_peer(&self, address: &str) {
+        let own_address = PeerAddress {
+            address: self.address_book.get_own_address(),
+        };
+        if own_address.address.is_empty() {
+            return;
+        }
+
+        let uri = format!("{}/peers", address);
+        let body = serde_json::to_string(&own_address).unwrap();
+
+        let request = create_peer_request(Request::post(uri))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .unwrap();
+
+        isahc::send(request).unwrap();
+    }
 
+    // Try to add a bunch of new blocks to our blockchain
+    // Returns false if any of them is invalid
+    fn add_new_blocks(&self, new_blocks: &[Block]) -> bool {
         for block in new_blocks.iter() {
-            for address in self.peer_addresses.iter() {
-                // we don't want to panic if one peer is down or not working properly
-                let result = panic::catch_unwind(|| {
-                    Peer::send_block_to_peer(address, block);
-                });
-
-                if result.is_err() {
-                    error!("Could not send block {} to peer {}", block.index, address);
-                    return;
+            let status = match self.blockchain.add_block(block.clone()) {
+                Ok(status) => status,
+                // if a block is invalid, no point in trying to add the next ones
+                Err(_) => {
+                    error!("Could not add peer block {} to the blockchain", block.index);
+                    return false;
                 }
+            };
 
-                info!("Sended new block {} to peer {}", block.index, address);
+            info!("Added new peer block {} to the blockchain", block.index);
+            if status.is_connected() {
+                self.metrics
+                    .blocks_added
+                    .with_label_values(&[SOURCE_PEER])
+                    .inc();
+                self.gossip.announce_block(block);
             }
         }
+
+        true
     }
 
-    // Return all new blocks added to the blockchain since the one with the indicated index
-    fn get_new_blocks_since(&self, start_index: usize) -> Vec<Block> {
-        let last_block_index = self.get_last_block_index();
-        let new_blocks_range = start_index + 1..=last_block_index;
-        self.blockchain
-            .get_all_blocks()
-            .get(new_blocks_range)
-            .unwrap()
-            .to_vec()
+    // Retrieve only the blocks from a peer that we don't know yet, one page at a time, and add them to our blockchain
+    // They can follow our last block, or belong to a different branch that replaces ours if it has more work
+    fn receive_new_blocks_from_peer(&self, address: &str) {
+        let mut from = match self.find_sync_start(address) {
+            Some(index) => index,
+            None => return,
+        };
+
+        loop {
+            let peer_blocks = self.get_blocks_from_peer(address, from);
+
+            // the blocks of the peer form a chain, so after the first unknown block all of them are new
+            let new_blocks: Vec<Block> = peer_blocks
+                .iter()
+                .skip_while(|block| self.blockchain.contains_block(&block.hash))
+                .cloned()
+                .collect();
+            if !self.add_new_blocks(&new_blocks) {
+                return;
+            }
+
+            // a page that is not full is the last one
+            if peer_blocks.len() < SYNC_PAGE_LIMIT {
+                return;
+            }
+            from += SYNC_PAGE_LIMIT as u64;
+        }
     }
 
-    // Send a block to a peer using the REST API of the peer
-    fn send_block_to_peer(address: &str, block: &Block) {
-        let uri = format!("{}/blocks", address);
-        let body = serde_json::to_string(&block).unwrap();
+    // Returns the index of a block of the peer that we already know, so the new blocks come after it
+    // Returns None if we already know the last block of the peer
+    // The peer may be in a different branch, so we look further back each time until we find a known block
+    fn find_sync_start(&self, address: &str) -> Option<u64> {
+        let peer_last_block = self.get_block_from_peer(address, "latest");
+        if self.blockchain.contains_block(&peer_last_block.hash) {
+            return None;
+        }
 
-        let request = Request::post(uri)
-            .header("Content-Type", "application/json")
-            .body(body)
-            .unwrap();
+        let mut index = peer_last_block
+            .index
+            .min(self.get_last_block_index() as u64);
+        let mut step = 1;
+        while index > 0 {
+            let peer_block = self.get_block_from_peer(address, &index.to_string());
+            if self.blockchain.contains_block(&peer_block.hash) {
+                break;
+            }
 
-        isahc::send(request).unwrap();
+            index = index.saturating_sub(step);
+            step *= 2;
+        }
+
+        Some(index)
+    }
+
+    // Retrieve a single block from a peer, by its index or "latest" for the last one
+    fn get_block_from_peer(&self, address: &str, index: &str) -> Block {
+        let uri = format!("{}/blocks/{}", address, index);
+
+        Peer::get_json_from_peer(&uri)
+    }
+
+    // Retrieve a page of blocks from a peer, starting at the indicated index
+    fn get_blocks_from_peer(&self, address: &str, from: u64) -> Vec<Block> {
+        let uri = format!("{}/blocks?from={}&limit={}", address, from, SYNC_PAGE_LIMIT);
+
+        Peer::get_json_from_peer(&uri)
+    }
+
+    fn get_json_from_peer<T: DeserializeOwned>(uri: &str) -> T {
+        let request = create_peer_request(Request::get(uri)).body(()).unwrap();
+        let mut response = isahc::send(request).unwrap();
+
+        // check that the response is sucessful
+        assert_eq!(response.status().as_u16(), 200);
+
+        // parse and return the value from the response body
+        let raw_body = response.text().unwrap();
+        serde_json::from_str(&raw_body).unwrap()
     }
 }
@@ -0,0 +1,503 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::util::Metrics;
+
+// Peers that never answered are forgotten after this amount of failed attempts in a row
+// Anyone can announce addresses that cannot be reached, and each of them would slow down every sync round
+const MAX_FAILED_ATTEMPTS_NEVER_SEEN: u32 = 3;
+
+// A peer known by the node, along with the last time it answered and the result of the last sync
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownPeer {
+    pub address: String,
+    // times are in milliseconds since the epoch, and they are missing if it never happened
+    pub last_seen_at: Option<i64>,
+    pub last_sync_at: Option<i64>,
+    pub last_sync_succeeded: Option<bool>,
+    // failed attempts to contact the peer since the last time it answered
+    #[serde(default)]
+    pub failed_attempts: u32,
+    // result of the last handshake, and the height of the peer at that moment
+    pub compatible: Option<bool>,
+    pub best_height: Option<u64>,
+}
+
+impl KnownPeer {
+    fn new(address: String) -> KnownPeer {
+        KnownPeer {
+            address,
+            last_seen_at: None,
+            last_sync_at: None,
+            last_sync_succeeded: None,
+            failed_attempts: 0,
+            compatible: None,
+            best_height: None,
+        }
+    }
+}
+
+// Address that a node announces to its peers, so they can connect to it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerAddress {
+    pub address: String,
+}
+
+#[derive(Debug)]
+struct AddressBookState {
+    // the address of this node, which is never added as a peer
+    own_address: String,
+    max_peers: usize,
+    // in the order they were added
+    peers: Vec<KnownPeer>,
+    // the peers from the configuration, which are never replaced
+    seed_addresses: HashSet<String>,
+    file_path: Option<PathBuf>,
+    metrics: Metrics,
+}
+
+// Peers known by the node, from the configuration or learned from other peers, up to a maximum amount
+// When it's full, an incompatible peer or the one seen the longest time ago (or never seen) is replaced by the new one
+// The seed peers from the configuration are never replaced, so the node can always find its way back to the network
+// Multiple threads can read/write concurrently to the peers
+#[derive(Debug, Clone)]
+pub struct AddressBook {
+    state: Arc<Mutex<AddressBookState>>,
+}
+
+impl AddressBook {
+    pub fn new(own_address: &str, max_peers: usize) -> AddressBook {
+        let state = AddressBookState {
+            own_address: AddressBook::normalize(own_address),
+            max_peers,
+            peers: Vec::new(),
+            seed_addresses: HashSet::new(),
+            file_path: None,
+            metrics: Metrics::new(),
+        };
+
+        AddressBook {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    // Removes the metrics of the peers that are forgotten from the indicated metrics, instead of from its own ones
+    // Otherwise, the metrics would keep growing with every peer ever known
+    pub fn with_metrics(self, metrics: Metrics) -> AddressBook {
+        self.state.lock().unwrap().metrics = metrics;
+        self
+    }
+
+    // Keeps the peers in a file, so they are not forgotten when the node restarts
+    // The peers already stored in the file are loaded
+    pub fn with_file(self, file_path: &Path) -> Result<AddressBook> {
+        let stored_peers: Vec<KnownPeer> = match fs::read(file_path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.file_path = Some(file_path.to_path_buf());
+            for peer in stored_peers {
+                state.insert(peer);
+            }
+        }
+
+        Ok(self)
+    }
+
+    // Returns a copy of all the known peers, in the order they were added
+    pub fn get_peers(&self) -> Vec<KnownPeer> {
+        self.state.lock().unwrap().peers.clone()
+    }
+
+    pub fn get_addresses(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .peers
+            .iter()
+            .map(|peer| peer.address.clone())
+            .collect()
+    }
+
+    // Returns the addresses of the peers that completed a handshake and are compatible
+    // The ones that did not complete a handshake yet are left out, as they may not even be reachable
+    pub fn get_compatible_addresses(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .peers
+            .iter()
+            .filter(|peer| peer.compatible == Some(true))
+            .map(|peer| peer.address.clone())
+            .collect()
+    }
+
+    pub fn get_own_address(&self) -> String {
+        self.state.lock().unwrap().own_address.clone()
+    }
+
+    // Adds a peer that was not seen yet, returning false if it's not new or the address is not valid
+    pub fn add_peer(&self, address: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.insert(KnownPeer::new(AddressBook::normalize(address)))
+    }
+
+    // Adds a peer from the configuration, which is kept even if the address book is full of other peers
+    // Returns false if the address is not valid
+    pub fn add_seed_peer(&self, address: &str) -> bool {
+        let address = AddressBook::normalize(address);
+        let mut state = self.state.lock().unwrap();
+        if !AddressBook::is_valid(&address) || address == state.own_address {
+            return false;
+        }
+
+        // it may be already known from the file, otherwise it must be marked before inserting so it's not replaced
+        state.seed_addresses.insert(address.clone());
+        if !state.contains(&address) && !state.insert(KnownPeer::new(address.clone())) {
+            state.seed_addresses.remove(&address);
+            return false;
+        }
+
+        true
+    }
+
+    // Adds a peer that announced itself to this node, returning false if the address is not valid
+    // Anyone can announce any address, so it's not considered seen until this node contacts it
+    pub fn add_announced_peer(&self, address: &str) -> bool {
+        let address = AddressBook::normalize(address);
+        let mut state = self.state.lock().unwrap();
+        if !AddressBook::is_valid(&address) {
+            return false;
+        }
+
+        if !state.contains(&address) {
+            state.insert(KnownPeer::new(address));
+        }
+
+        true
+    }
+
+    // Peers that keep failing without ever answering are forgotten, unless they are seeds
+    pub fn record_sync(&self, address: &str, succeeded: bool) {
+        let now = Utc::now().timestamp_millis();
+        let mut state = self.state.lock().unwrap();
+        state.update(address, |peer| {
+            peer.last_sync_at = Some(now);
+            peer.last_sync_succeeded = Some(succeeded);
+            if succeeded {
+                peer.last_seen_at = Some(now);
+                peer.failed_attempts = 0;
+            } else {
+                peer.failed_attempts += 1;
+            }
+        });
+
+        let position = state.peers.iter().position(|peer| {
+            peer.address == address
+                && peer.last_seen_at.is_none()
+                && peer.failed_attempts >= MAX_FAILED_ATTEMPTS_NEVER_SEEN
+        });
+        if let Some(position) = position {
+            if !state.seed_addresses.contains(address) {
+                state.remove(position, "it was never reachable");
+            }
+        }
+    }
+
+    // Returns true if the peer was not known to be compatible before
+    pub fn record_compatible(&self, address: &str, best_height: u64) -> bool {
+        let now = Utc::now().timestamp_millis();
+        let mut state = self.state.lock().unwrap();
+        let mut newly_compatible = false;
+        state.update(address, |peer| {
+            newly_compatible = peer.compatible != Some(true);
+            peer.last_seen_at = Some(now);
+            peer.failed_attempts = 0;
+            peer.compatible = Some(true);
+            peer.best_height = Some(best_height);
+        });
+
+        newly_compatible
+    }
+
+    pub fn record_incompatible(&self, address: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.update(address, |peer| {
+            peer.compatible = Some(false);
+            peer.best_height = None;
+        });
+    }
+
+    // Writes all the known peers to the file, if there is any
+    // The peers are written in a temporary file and then renamed, so a crash never leaves an incomplete file
+    pub fn save(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let path = match &state.file_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let temp_path = path.with_extension("tmp");
+
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&serde_json::to_vec(&state.peers)?)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+
+        Ok(())
+    }
+
+    // Peers are contacted over HTTP
+    fn is_valid(address: &str) -> bool {
+        (address.starts_with("http://") || address.starts_with("https://"))
+            && !address.contains(char::is_whitespace)
+    }
+
+    // The trailing slashes are removed, so each peer has a single address
+    fn normalize(address: &str) -> String {
+        address.trim().trim_end_matches('/').to_string()
+    }
+}
+
+impl AddressBookState {
+    fn contains(&self, address: &str) -> bool {
+        self.peers.iter().any(|peer| peer.address == address)
+    }
+
+    fn insert(&mut self, peer: KnownPeer) -> bool {
+        if !AddressBook::is_valid(&peer.address)
+            || peer.address == self.own_address
+            || self.contains(&peer.address)
+            || self.max_peers == 0
+        {
+            return false;
+        }
+
+        if self.peers.len() >= self.max_peers {
+            // incompatible peers go first, then never seen peers, and the first added one if they were seen at the same time
+            let oldest_position = self
+                .peers
+                .iter()
+                .enumerate()
+                .filter(|(_, peer)| !self.seed_addresses.contains(&peer.address))
+                .min_by_key(|(position, peer)| {
+                    (peer.compatible != Some(false), peer.last_seen_at, *position)
+                })
+                .map(|(position, _)| position);
+
+            // there is no room if all the peers are seeds
+            let oldest_position = match oldest_position {
+                Some(position) => position,
+                None => return false,
+            };
+            self.remove(oldest_position, "to make room");
+        }
+
+        self.peers.push(peer);
+        true
+    }
+
+    fn remove(&mut self, position: usize, reason: &str) {
+        let removed_peer = self.peers.remove(position);
+        info!("Forgetting peer {} {}", removed_peer.address, reason);
+
+        // the peer may have never failed, so it's fine if it has no metrics
+        let _ = self
+            .metrics
+            .peer_sync_failures
+            .remove_label_values(&[&removed_peer.address]);
+    }
+
+    fn update(&mut self, address: &str, update: impl FnOnce(&mut KnownPeer)) {
+        if let Some(peer) = self.peers.iter_mut().find(|peer| peer.address == address) {
+            update(peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_util::create_temp_dir;
+
+    const OWN_ADDRESS: &str = "http://localhost:8000";
+
+    #[test]
+    fn should_add_new_valid_peers() {
+        let address_book = AddressBook::new(OWN_ADDRESS, 10);
+
+        assert!(address_book.add_peer("http://localhost:8001"));
+        assert!(address_book.add_peer("https://example.com/"));
+
+        // known, own and invalid addresses are not added
+        assert!(!address_book.add_peer("http://localhost:8001/"));
+        assert!(!address_book.add_peer(OWN_ADDRESS));
+        assert!(!address_book.add_peer("localhost:8002"));
+        assert!(!address_book.add_peer("http://local host:8002"));
+
+        assert_eq!(
+            address_book.get_addresses(),
+            vec!["http://localhost:8001", "https://example.com"]
+        );
+    }
+
+    #[test]
+    fn should_record_when_peers_were_seen() {
+        let address_book = AddressBook::new(OWN_ADDRESS, 10);
+        address_book.add_peer("http://localhost:8001");
+        address_book.add_peer("http://localhost:8002");
+
+        address_book.add_peer("http://localhost:8003");
+
+        address_book.record_sync("http://localhost:8001", true);
+        address_book.record_sync("http://localhost:8002", false);
+        address_book.record_compatible("http://localhost:8003", 5);
+        assert!(address_book.add_announced_peer("http://localhost:8004"));
+        assert!(!address_book.add_announced_peer("localhost:8005"));
+
+        let peers = address_book.get_peers();
+        assert_eq!(peers.len(), 4);
+        assert!(peers[0].last_seen_at.is_some());
+        assert_eq!(peers[0].last_sync_succeeded, Some(true));
+        assert!(peers[1].last_seen_at.is_none());
+        assert_eq!(peers[1].last_sync_succeeded, Some(false));
+        assert!(peers[2].last_seen_at.is_some());
+        assert!(peers[2].last_sync_at.is_none());
+
+        // a peer that only announced itself was not contacted yet
+        assert!(peers[3].last_seen_at.is_none());
+    }
+
+    #[test]
+    fn should_replace_the_least_recently_seen_peer_when_full() {
+        let address_book = AddressBook::new(OWN_ADDRESS, 2);
+        address_book.add_peer("http://localhost:8001");
+        address_book.add_peer("http://localhost:8002");
+        address_book.record_sync("http://localhost:8001", true);
+
+        // the peer that was never seen is replaced
+        assert!(address_book.add_peer("http://localhost:8003"));
+        assert_eq!(
+            address_book.get_addresses(),
+            vec!["http://localhost:8001", "http://localhost:8003"]
+        );
+    }
+
+    #[test]
+    fn should_remove_metrics_of_replaced_peers() {
+        let metrics = Metrics::new();
+        let address_book = AddressBook::new(OWN_ADDRESS, 1).with_metrics(metrics.clone());
+        address_book.add_peer("http://localhost:8001");
+        metrics
+            .peer_sync_failures
+            .with_label_values(&["http://localhost:8001"])
+            .inc();
+        assert!(metrics.encode().contains("http://localhost:8001"));
+
+        // the replaced peer does not leave its metrics behind
+        address_book.add_peer("http://localhost:8002");
+        assert!(!metrics.encode().contains("http://localhost:8001"));
+    }
+
+    #[test]
+    fn should_forget_peers_that_were_never_reachable() {
+        let address_book = AddressBook::new(OWN_ADDRESS, 10);
+        address_book.add_seed_peer("http://localhost:8001");
+        address_book.add_announced_peer("http://localhost:8002");
+        address_book.add_announced_peer("http://localhost:8003");
+        address_book.record_compatible("http://localhost:8003", 5);
+
+        for _ in 0..MAX_FAILED_ATTEMPTS_NEVER_SEEN {
+            for address in address_book.get_addresses() {
+                address_book.record_sync(&address, false);
+            }
+        }
+
+        // the seed and the peer that answered before are kept, as they may come back
+        assert_eq!(
+            address_book.get_addresses(),
+            vec!["http://localhost:8001", "http://localhost:8003"]
+        );
+        assert_eq!(
+            address_book.get_peers()[1].failed_attempts,
+            MAX_FAILED_ATTEMPTS_NEVER_SEEN
+        );
+    }
+
+    #[test]
+    fn should_never_replace_seed_peers() {
+        let address_book = AddressBook::new(OWN_ADDRESS, 2);
+        assert!(address_book.add_seed_peer("http://localhost:8001"));
+        address_book.add_peer("http://localhost:8002");
+        address_book.record_sync("http://localhost:8002", true);
+
+        // the seed peer was never seen, but the other peer is replaced instead
+        assert!(address_book.add_announced_peer("http://localhost:8003"));
+        assert_eq!(
+            address_book.get_addresses(),
+            vec!["http://localhost:8001", "http://localhost:8003"]
+        );
+
+        // when all the peers are seeds, there is no room for new ones
+        let address_book = AddressBook::new(OWN_ADDRESS, 1);
+        address_book.add_seed_peer("http://localhost:8001");
+        assert!(!address_book.add_peer("http://localhost:8002"));
+        assert_eq!(address_book.get_addresses(), vec!["http://localhost:8001"]);
+    }
+
+    #[test]
+    fn should_exclude_incompatible_peers() {
+        let address_book = AddressBook::new(OWN_ADDRESS, 3);
+        address_book.add_peer("http://localhost:8001");
+        address_book.add_peer("http://localhost:8002");
+        address_book.add_peer("http://localhost:8003");
+        address_book.record_compatible("http://localhost:8001", 5);
+        address_book.record_incompatible("http://localhost:8002");
+
+        // only the peers that completed a handshake are compatible
+        assert_eq!(
+            address_book.get_compatible_addresses(),
+            vec!["http://localhost:8001"]
+        );
+        assert_eq!(address_book.get_peers()[0].best_height, Some(5));
+
+        // incompatible peers are replaced first, even if they were never seen
+        address_book.add_peer("http://localhost:8004");
+        assert_eq!(
+            address_book.get_addresses(),
+            vec![
+                "http://localhost:8001",
+                "http://localhost:8003",
+                "http://localhost:8004"
+            ]
+        );
+    }
+
+    #[test]
+    fn should_keep_peers_after_reopening() {
+        let path = create_temp_dir("address_book").join("peers.json");
+        let address_book = AddressBook::new(OWN_ADDRESS, 10).with_file(&path).unwrap();
+        address_book.add_peer("http://localhost:8001");
+        address_book.add_peer("http://localhost:8002");
+        address_book.record_sync("http://localhost:8002", true);
+        address_book.save().unwrap();
+
+        let reopened_address_book = AddressBook::new(OWN_ADDRESS, 10).with_file(&path).unwrap();
+        let peers = reopened_address_book.get_peers();
+        assert_eq!(
+            reopened_address_book.get_addresses(),
+            vec!["http://localhost:8001", "http://localhost:8002"]
+        );
+        assert!(peers[1].last_seen_at.is_some());
+    }
+}
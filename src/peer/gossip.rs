@@ -0,0 +1,291 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use super::{create_peer_request, AddressBook};
+use crate::{
+    model::{Block, BlockHash, Transaction},
+    util::{execution::Runnable, Context},
+};
+use anyhow::Result;
+use isahc::Request;
+
+// Upper limit of hashes remembered of each kind, the oldest ones are forgotten first
+const MAX_SEEN_HASHES: usize = 10_000;
+
+// Upper limit of announcements waiting to be relayed, the oldest ones are dropped first if the peers are too slow
+const MAX_PENDING_ANNOUNCEMENTS: usize = 1_000;
+
+// Upper limit of announcements waiting to be sent to each peer, the new ones are dropped if the peer is too slow
+const MAX_PENDING_ANNOUNCEMENTS_PER_PEER: usize = 1_000;
+
+// Header of the requests made by the relay, so the peers can tell them apart from the ones of other API users
+pub const RELAY_HEADER: &str = "X-Relayed-By-Peer";
+
+// A new block or transaction accepted by the node, to be relayed to the peers
+#[derive(Debug, Clone)]
+pub enum Announcement {
+    Block(Block),
+    Transaction(Transaction),
+}
+
+// Hashes of the most recently announced items, so each of them is relayed only once
+// Otherwise, in a mesh of peers, the same items would be relayed back and forth forever
+#[derive(Debug, Default)]
+struct SeenSet {
+    hashes: HashSet<BlockHash>,
+    // insertion order of the hashes, to forget the oldest ones first
+    order: VecDeque<BlockHash>,
+}
+
+impl SeenSet {
+    // Returns false if the hash was already seen
+    fn insert(&mut self, hash: BlockHash) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+
+        self.order.push_back(hash);
+        if self.order.len() > MAX_SEEN_HASHES {
+            if let Some(oldest_hash) = self.order.pop_front() {
+                self.hashes.remove(&oldest_hash);
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+struct GossipState {
+    seen_blocks: SeenSet,
+    seen_transactions: SeenSet,
+    pending: VecDeque<Announcement>,
+}
+
+// Collects the new blocks and transactions accepted by any part of the node, to relay them to the peers
+// Multiple threads can announce concurrently, while the relay waits for new announcements
+#[derive(Debug, Clone, Default)]
+pub struct Gossip {
+    state: Arc<Mutex<GossipState>>,
+    announced: Arc<Condvar>,
+}
+
+impl Gossip {
+    // Queues a block to be relayed, unless it was already announced before
+    pub fn announce_block(&self, block: &Block) {
+        let mut state = self.state.lock().unwrap();
+        if state.seen_blocks.insert(block.hash) {
+            self.push(&mut state, Announcement::Block(block.clone()));
+        }
+    }
+
+    // Queues a transaction to be relayed, unless it was already announced before
+    pub fn announce_transaction(&self, transaction: &Transaction) {
+        let mut state = self.state.lock().unwrap();
+        if state.seen_transactions.insert(transaction.hash()) {
+            self.push(&mut state, Announcement::Transaction(transaction.clone()));
+        }
+    }
+
+    // Blocks the thread until there is any announcement, returning all of them in the order they were made
+    pub fn wait_for_announcements(&self) -> Vec<Announcement> {
+        let mut state = self.state.lock().unwrap();
+        while state.pending.is_empty() {
+            state = self.announced.wait(state).unwrap();
+        }
+
+        state.pending.drain(..).collect()
+    }
+
+    fn push(&self, state: &mut GossipState, announcement: Announcement) {
+        state.pending.push_back(announcement);
+        if state.pending.len() > MAX_PENDING_ANNOUNCEMENTS {
+            state.pending.pop_front();
+        }
+
+        self.announced.notify_one();
+    }
+}
+
+// Sends the announcements of the node to all its peers as soon as they are made
+pub struct Relay {
+    address_book: AddressBook,
+    gossip: Gossip,
+}
+
+impl Runnable for Relay {
+    fn run(&self) -> Result<()> {
+        self.start()
+    }
+}
+
+impl Relay {
+    pub fn new(context: &Context) -> Relay {
+        Relay {
+            address_book: context.address_book.clone(),
+            gossip: context.gossip.clone(),
+        }
+    }
+
+    // The announcements are sent to the peers known at that moment, so new peers are included as they are learned
+    // Only the peers that completed a handshake are included, so unknown or unreachable addresses cannot slow it down
+    // Each peer has its own worker, so a slow peer does not delay the announcements to the other ones
+    pub fn start(&self) -> Result<()> {
+        let mut workers: HashMap<String, SyncSender<Announcement>> = HashMap::new();
+        loop {
+            let announcements = self.gossip.wait_for_announcements();
+            let peer_addresses = self.address_book.get_compatible_addresses();
+
+            // dropping the queue of a peer that is not compatible anymore stops its worker
+            workers.retain(|address, _| peer_addresses.contains(address));
+
+            for address in peer_addresses {
+                let queue = workers
+                    .entry(address.clone())
+                    .or_insert_with(|| Relay::start_worker(address.clone()));
+
+                for announcement in announcements.iter() {
+                    // the announcements are dropped if the peer cannot keep up, it will sync them later anyway
+                    if let Err(TrySendError::Full(_)) = queue.try_send(announcement.clone()) {
+                        warn!("Too many announcements pending for peer {}", address);
+                    }
+                }
+            }
+        }
+    }
+
+    // Start a thread that sends the announcements of its queue to a peer, in the same order they were made
+    fn start_worker(address: String) -> SyncSender<Announcement> {
+        let (queue, announcements) = mpsc::sync_channel(MAX_PENDING_ANNOUNCEMENTS_PER_PEER);
+        thread::spawn(move || {
+            for announcement in announcements {
+                Relay::send_to_peer(&address, &announcement);
+            }
+        });
+
+        queue
+    }
+
+    // Send a block or transaction to a peer using the REST API of the peer
+    // The peer will relay it in turn to its own peers, if it did not know it yet
+    fn send_to_peer(address: &str, announcement: &Announcement) {
+        let (uri, body, description) = match announcement {
+            Announcement::Block(block) => (
+                format!("{}/blocks", address),
+                serde_json::to_string(block).unwrap(),
+                format!("block {}", block.index),
+            ),
+            Announcement::Transaction(transaction) => (
+                format!("{}/transactions", address),
+                serde_json::to_string(transaction).unwrap(),
+                "transaction".to_string(),
+            ),
+        };
+
+        let result = create_peer_request(Request::post(uri))
+            .header("Content-Type", "application/json")
+            .header(RELAY_HEADER, "true")
+            .body(body)
+            .map_err(isahc::Error::from)
+            .and_then(isahc::send);
+
+        // we don't want to stop relaying if one peer is down or not working properly
+        // a peer rejecting an item it already knows is not an error, so we only check that it was sent
+        match result {
+            Ok(_) => info!("Relayed {} to peer {}", description, address),
+            Err(error) => error!(
+                "Could not relay {} to peer {}: {}",
+                description, address, error
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_util::{alice_keypair, bob};
+
+    #[test]
+    fn should_relay_new_blocks_and_transactions_in_order() {
+        let gossip = Gossip::default();
+        let block = create_block(1);
+        let transaction = alice_keypair().create_transaction(bob(), 10, 0);
+
+        gossip.announce_block(&block);
+        gossip.announce_transaction(&transaction);
+
+        let announcements = gossip.wait_for_announcements();
+        assert_eq!(
+            get_hashes(&announcements),
+            vec![block.hash, transaction.hash()]
+        );
+    }
+
+    #[test]
+    fn should_relay_each_item_only_once() {
+        let gossip = Gossip::default();
+        let block = create_block(1);
+        let transaction = alice_keypair().create_transaction(bob(), 10, 0);
+
+        gossip.announce_block(&block);
+        gossip.announce_transaction(&transaction);
+        gossip.wait_for_announcements();
+
+        // announcing them again, for example when they come back from another peer, does nothing
+        gossip.announce_block(&block);
+        gossip.announce_transaction(&transaction);
+        let new_block = create_block(2);
+        gossip.announce_block(&new_block);
+
+        let announcements = gossip.wait_for_announcements();
+        assert_eq!(get_hashes(&announcements), vec![new_block.hash]);
+    }
+
+    #[test]
+    fn should_forget_the_oldest_hashes() {
+        let mut seen_set = SeenSet::default();
+        for value in 0..=MAX_SEEN_HASHES {
+            assert!(seen_set.insert(BlockHash::from(value)));
+        }
+
+        // the first hash was forgotten, but the last one is still remembered
+        assert!(seen_set.insert(BlockHash::from(0)));
+        assert!(!seen_set.insert(BlockHash::from(MAX_SEEN_HASHES)));
+    }
+
+    #[test]
+    fn should_drop_the_oldest_announcements_when_too_many_are_pending() {
+        let gossip = Gossip::default();
+        for index in 0..=MAX_PENDING_ANNOUNCEMENTS as u64 {
+            gossip.announce_block(&create_block(index));
+        }
+
+        let announcements = gossip.wait_for_announcements();
+        assert_eq!(announcements.len(), MAX_PENDING_ANNOUNCEMENTS);
+        match &announcements[0] {
+            Announcement::Block(block) => assert_eq!(block.index, 1),
+            Announcement::Transaction(_) => panic!("expected a block"),
+        }
+    }
+
+    fn create_block(index: u64) -> Block {
+        Block::new(index, 0, 0, BlockHash::default(), Vec::new())
+    }
+
+    fn get_hashes(announcements: &[Announcement]) -> Vec<BlockHash> {
+        announcements
+            .iter()
+            .map(|announcement| match announcement {
+                Announcement::Block(block) => block.hash,
+                Announcement::Transaction(transaction) => transaction.hash(),
+            })
+            .collect()
+    }
+}
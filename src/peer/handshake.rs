@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::model::{BlockHash, Blockchain};
+
+// Version of the requests between peers, it changes when they are not understood by previous versions
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Requests between peers supported by this node
+pub const CAPABILITY_SYNC: &str = "sync";
+pub const CAPABILITY_GOSSIP: &str = "gossip";
+pub const CAPABILITY_PEERS: &str = "peers";
+
+#[derive(Error, PartialEq, Debug)]
+pub enum HandshakeError {
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedProtocolVersion(u32),
+
+    #[error("Different network {0}")]
+    DifferentNetwork(String),
+
+    #[error("Different genesis block")]
+    DifferentGenesisBlock,
+
+    #[error("Different initial difficulty {0}")]
+    DifferentDifficulty(u32),
+
+    #[error("Different difficulty adjustment interval {0}")]
+    DifferentDifficultyAdjustmentInterval(u64),
+
+    #[error("Different target block time {0} ms")]
+    DifferentTargetBlockTime(u64),
+
+    #[error("Refused by the peer: {0}")]
+    Refused(String),
+}
+
+// Information that nodes exchange before syncing, to check that they follow the same rules
+// Otherwise, they would keep sending each other blocks that are always rejected
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub network_id: String,
+    pub genesis_hash: BlockHash,
+    // the difficulty of the first blocks, the following ones are calculated from it
+    pub difficulty: u32,
+    // how the difficulty of the following blocks is adjusted
+    pub difficulty_adjustment_interval: u64,
+    pub target_block_time_ms: u64,
+    pub best_height: u64,
+    pub capabilities: Vec<String>,
+}
+
+impl Handshake {
+    pub fn new(network_id: &str, blockchain: &Blockchain) -> Handshake {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            network_id: network_id.to_string(),
+            genesis_hash: blockchain.get_block_by_index(0).unwrap().hash,
+            difficulty: blockchain.difficulty,
+            difficulty_adjustment_interval: blockchain.difficulty_adjustment_interval,
+            target_block_time_ms: blockchain.target_block_time_ms,
+            best_height: blockchain.get_chain_info().height,
+            capabilities: [CAPABILITY_SYNC, CAPABILITY_GOSSIP, CAPABILITY_PEERS]
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect(),
+        }
+    }
+
+    // Checks that the blocks of a peer would be valid for this node, and the other way around
+    pub fn check_compatibility(&self, peer: &Handshake) -> Result<(), HandshakeError> {
+        if peer.protocol_version != self.protocol_version {
+            return Err(HandshakeError::UnsupportedProtocolVersion(
+                peer.protocol_version,
+            ));
+        }
+
+        if peer.network_id != self.network_id {
+            return Err(HandshakeError::DifferentNetwork(peer.network_id.clone()));
+        }
+
+        if peer.genesis_hash != self.genesis_hash {
+            return Err(HandshakeError::DifferentGenesisBlock);
+        }
+
+        if peer.difficulty != self.difficulty {
+            return Err(HandshakeError::DifferentDifficulty(peer.difficulty));
+        }
+
+        if peer.difficulty_adjustment_interval != self.difficulty_adjustment_interval {
+            return Err(HandshakeError::DifferentDifficultyAdjustmentInterval(
+                peer.difficulty_adjustment_interval,
+            ));
+        }
+
+        if peer.target_block_time_ms != self.target_block_time_ms {
+            return Err(HandshakeError::DifferentTargetBlockTime(
+                peer.target_block_time_ms,
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|value| value == capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORK_ID: &str = "test";
+
+    #[test]
+    fn should_accept_peers_with_the_same_rules() {
+        let handshake = create_handshake();
+
+        // the height of the peer does not matter, as syncing will bring both nodes to the same height
+        let mut peer_handshake = create_handshake();
+        peer_handshake.best_height = 10;
+        peer_handshake.capabilities = Vec::new();
+
+        assert_eq!(handshake.check_compatibility(&peer_handshake), Ok(()));
+    }
+
+    #[test]
+    fn should_refuse_peers_with_different_rules() {
+        let handshake = create_handshake();
+
+        let mut peer_handshake = create_handshake();
+        peer_handshake.protocol_version = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            handshake.check_compatibility(&peer_handshake),
+            Err(HandshakeError::UnsupportedProtocolVersion(
+                PROTOCOL_VERSION + 1
+            ))
+        );
+
+        let mut peer_handshake = create_handshake();
+        peer_handshake.network_id = "other".to_string();
+        assert_eq!(
+            handshake.check_compatibility(&peer_handshake),
+            Err(HandshakeError::DifferentNetwork("other".to_string()))
+        );
+
+        let mut peer_handshake = create_handshake();
+        peer_handshake.genesis_hash = BlockHash::from(1);
+        assert_eq!(
+            handshake.check_compatibility(&peer_handshake),
+            Err(HandshakeError::DifferentGenesisBlock)
+        );
+
+        let mut peer_handshake = create_handshake();
+        peer_handshake.difficulty += 1;
+        assert_eq!(
+            handshake.check_compatibility(&peer_handshake),
+            Err(HandshakeError::DifferentDifficulty(
+                handshake.difficulty + 1
+            ))
+        );
+
+        let mut peer_handshake = create_handshake();
+        peer_handshake.difficulty_adjustment_interval = 20;
+        assert_eq!(
+            handshake.check_compatibility(&peer_handshake),
+            Err(HandshakeError::DifferentDifficultyAdjustmentInterval(20))
+        );
+
+        let mut peer_handshake = create_handshake();
+        peer_handshake.target_block_time_ms = 5_000;
+        assert_eq!(
+            handshake.check_compatibility(&peer_handshake),
+            Err(HandshakeError::DifferentTargetBlockTime(5_000))
+        );
+    }
+
+    #[test]
+    fn should_report_the_state_of_the_node() {
+        let handshake = create_handshake();
+
+        assert_eq!(handshake.network_id, NETWORK_ID);
+        assert_eq!(handshake.best_height, 0);
+        assert!(handshake.has_capability(CAPABILITY_PEERS));
+        assert!(!handshake.has_capability("unknown"));
+    }
+
+    fn create_handshake() -> Handshake {
+        let blockchain = Blockchain::new(1).with_difficulty_adjustment(10, 60_000);
+        Handshake::new(NETWORK_ID, &blockchain)
+    }
+}
@@ -2,6 +2,7 @@ mod config;
 mod context;
 pub mod execution;
 mod logger;
+pub mod metrics;
 pub mod termination;
 
 // Explicitly controlling which individual identifiers we export
@@ -9,3 +10,4 @@ pub mod termination;
 pub use config::Config;
 pub use context::Context;
 pub use logger::initialize_logger;
+pub use metrics::Metrics;
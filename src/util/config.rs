@@ -13,17 +13,37 @@ type StringVec = Vec<String>;
 pub struct Config {
     // Networking settings
     pub port: u16,
+    pub public_address: String,
+    pub admin_token: String,
 
     // Peer settings
+    pub network_id: String,
     pub peers: StringVec,
     pub peer_sync_ms: u64,
+    pub max_peers: usize,
+
+    // Blockchain settings
+    pub data_dir: String,
+    pub snapshot_interval: u64,
+    pub max_orphan_blocks: usize,
+    pub orphan_expiry_ms: u64,
+    pub max_fork_depth: u64,
+
+    // Transaction pool settings
+    pub max_pool_transactions: usize,
+    pub max_pool_bytes: usize,
 
     // Miner settings
     pub max_blocks: u64,
     pub max_nonce: u64,
+    pub miner_threads: usize,
     pub difficulty: u32,
+    pub difficulty_adjustment_interval: u64,
+    pub target_block_time_ms: u64,
     pub tx_waiting_ms: u64,
     pub miner_address: Address,
+    pub max_block_transactions: usize,
+    pub max_block_bytes: usize,
 }
 
 // The implementation reads the values from environment variables
@@ -36,17 +56,41 @@ impl Config {
         Config {
             // Networking settings
             port: Config::read_envvar::<u16>("PORT", 8000),
+            // empty by default, so nodes are not announced to others unless they are reachable
+            public_address: Config::read_envvar::<String>("PUBLIC_ADDRESS", String::new()),
+            admin_token: Config::read_envvar::<String>("ADMIN_TOKEN", String::new()),
 
             // Peer settings
+            network_id: Config::read_envvar::<String>("NETWORK_ID", "main".to_string()),
             peers: Config::read_vec_envvar("PEERS", ",", StringVec::default()),
             peer_sync_ms: Config::read_envvar::<u64>("PEER_SYNC_MS", 10000),
+            max_peers: Config::read_envvar::<usize>("MAX_PEERS", 100),
+
+            // Blockchain settings
+            data_dir: Config::read_envvar::<String>("DATA_DIR", "data".to_string()),
+            snapshot_interval: Config::read_envvar::<u64>("SNAPSHOT_INTERVAL", 1000),
+            max_orphan_blocks: Config::read_envvar::<usize>("MAX_ORPHAN_BLOCKS", 100),
+            orphan_expiry_ms: Config::read_envvar::<u64>("ORPHAN_EXPIRY_MS", 600_000),
+            max_fork_depth: Config::read_envvar::<u64>("MAX_FORK_DEPTH", 100),
+
+            // Transaction pool settings
+            max_pool_transactions: Config::read_envvar::<usize>("MAX_POOL_TRANSACTIONS", 10_000),
+            max_pool_bytes: Config::read_envvar::<usize>("MAX_POOL_BYTES", 10_000_000),
 
             // Miner settings
             max_blocks: Config::read_envvar::<u64>("MAX_BLOCKS", 0), // unlimited blocks
             max_nonce: Config::read_envvar::<u64>("MAX_NONCE", 1_000_000),
+            miner_threads: Config::read_envvar::<usize>("MINER_THREADS", 1),
             difficulty: Config::read_envvar::<u32>("DIFFICULTY", 10),
+            difficulty_adjustment_interval: Config::read_envvar::<u64>(
+                "DIFFICULTY_ADJUSTMENT_INTERVAL",
+                10,
+            ),
+            target_block_time_ms: Config::read_envvar::<u64>("TARGET_BLOCK_TIME_MS", 10000),
             tx_waiting_ms: Config::read_envvar::<u64>("TRANSACTION_WAITING_MS", 10000),
             miner_address: Config::read_envvar::<Address>("MINER_ADDRESS", Address::default()),
+            max_block_transactions: Config::read_envvar::<usize>("MAX_BLOCK_TRANSACTIONS", 1_000),
+            max_block_bytes: Config::read_envvar::<usize>("MAX_BLOCK_BYTES", 1_000_000),
         }
     }
 
@@ -82,7 +126,7 @@ mod tests {
         env::set_var(var_name, real_value.to_string());
 
         // read the present var, should NOT return the default value but the real one
-        let default_value = 8000 as u16;
+        let default_value = 8000_u16;
         let value = Config::read_envvar::<u16>(var_name, default_value);
 
         assert_eq!(value, real_value);
@@ -95,7 +139,7 @@ mod tests {
     fn read_present_vec_envvar() {
         let var_name = "PRESENT_VEC_ENVVAR";
         let value = "FOO,BAR";
-        env::set_var(var_name, value.to_string());
+        env::set_var(var_name, value);
 
         // read the present var, should NOT return the default value but the real one
         let default_value = StringVec::default();
@@ -116,7 +160,7 @@ mod tests {
         env::remove_var(var_name);
 
         // read the non present var, should return the default value
-        let default_value = 8000 as u16;
+        let default_value = 8000_u16;
         let value = Config::read_envvar::<u16>(var_name, default_value);
         assert_eq!(value, default_value);
 
@@ -132,7 +176,7 @@ mod tests {
         let var_name = "INVALID=VAR=NAME";
 
         // read the invalid var, should return the default value
-        let default_value = 8000 as u16;
+        let default_value = 8000_u16;
         let value = Config::read_envvar::<u16>(var_name, default_value);
         assert_eq!(value, default_value);
 
@@ -143,7 +187,7 @@ mod tests {
     }
 
     // All credit for this function to https://stackoverflow.com/a/58175659
-    fn do_vecs_match<T: PartialEq>(a: &Vec<T>, b: &Vec<T>) -> bool {
+    fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
         let matching = a.iter().zip(b.iter()).filter(|&(a, b)| a == b).count();
         matching == a.len() && matching == b.len()
     }
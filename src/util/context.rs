@@ -1,8 +1,16 @@
-use super::Config;
-use crate::model::{Blockchain, TransactionPool};
+use super::{Config, Metrics};
+use crate::{
+    miner::MinerStatus,
+    model::{Blockchain, TransactionPool},
+    peer::{AddressBook, Gossip},
+};
 
 pub struct Context {
     pub config: Config,
     pub blockchain: Blockchain,
     pub pool: TransactionPool,
+    pub miner_status: MinerStatus,
+    pub address_book: AddressBook,
+    pub metrics: Metrics,
+    pub gossip: Gossip,
 }
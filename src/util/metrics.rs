@@ -0,0 +1,159 @@
+use std::fmt;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+// Where a block added to the blockchain comes from, used as label of the metrics
+pub const SOURCE_MINED: &str = "mined";
+pub const SOURCE_API: &str = "api";
+pub const SOURCE_PEER: &str = "peer";
+
+// Counters and gauges of the activity of the node, exported in the Prometheus text format
+// Cloning only clones the pointers to the metrics, so all the clones update the same values
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub blocks_added: IntCounterVec,
+    pub blocks_rejected: IntCounterVec,
+    pub chain_height: IntGauge,
+    pub mempool_transactions: IntGauge,
+    pub mempool_bytes: IntGauge,
+    pub hash_attempts: IntCounter,
+    pub peer_sync_failures: IntCounterVec,
+    pub api_request_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let blocks_added = IntCounterVec::new(
+            Opts::new(
+                "blockchain_blocks_added_total",
+                "Blocks added to the blockchain, by where they come from",
+            ),
+            &["source"],
+        )
+        .unwrap();
+        let blocks_rejected = IntCounterVec::new(
+            Opts::new(
+                "blockchain_blocks_rejected_total",
+                "Blocks rejected by the blockchain, by the reason of the rejection",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let chain_height = IntGauge::new(
+            "blockchain_height",
+            "Index of the last block of the main chain",
+        )
+        .unwrap();
+        let mempool_transactions =
+            IntGauge::new("mempool_transactions", "Transactions waiting in the pool").unwrap();
+        let mempool_bytes = IntGauge::new(
+            "mempool_bytes",
+            "Bytes of the transactions waiting in the pool",
+        )
+        .unwrap();
+        let hash_attempts =
+            IntCounter::new("miner_hashes_total", "Block hashes calculated while mining").unwrap();
+        let peer_sync_failures = IntCounterVec::new(
+            Opts::new(
+                "peer_sync_failures_total",
+                "Failed attempts to receive blocks from each peer",
+            ),
+            &["peer"],
+        )
+        .unwrap();
+        let api_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "api_request_duration_seconds",
+                "Time to respond the requests to the REST API, by route",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(blocks_added.clone())).unwrap();
+        registry
+            .register(Box::new(blocks_rejected.clone()))
+            .unwrap();
+        registry.register(Box::new(chain_height.clone())).unwrap();
+        registry
+            .register(Box::new(mempool_transactions.clone()))
+            .unwrap();
+        registry.register(Box::new(mempool_bytes.clone())).unwrap();
+        registry.register(Box::new(hash_attempts.clone())).unwrap();
+        registry
+            .register(Box::new(peer_sync_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(api_request_duration.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            blocks_added,
+            blocks_rejected,
+            chain_height,
+            mempool_transactions,
+            mempool_bytes,
+            hash_attempts,
+            peer_sync_failures,
+            api_request_duration,
+        }
+    }
+
+    // Returns the current value of all the metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+// The metrics are not relevant when debugging the structs that hold them
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_metrics_in_text_format() {
+        let metrics = Metrics::new();
+        metrics
+            .blocks_added
+            .with_label_values(&[SOURCE_MINED])
+            .inc();
+        metrics.hash_attempts.inc_by(10);
+        metrics.mempool_transactions.set(3);
+
+        let text = metrics.encode();
+        assert!(text.contains("# TYPE blockchain_blocks_added_total counter"));
+        assert!(text.contains("blockchain_blocks_added_total{source=\"mined\"} 1"));
+        assert!(text.contains("miner_hashes_total 10"));
+        assert!(text.contains("mempool_transactions 3"));
+    }
+
+    #[test]
+    fn should_share_values_between_clones() {
+        let metrics = Metrics::new();
+        let cloned_metrics = metrics.clone();
+        cloned_metrics
+            .peer_sync_failures
+            .with_label_values(&["http://localhost:8001"])
+            .inc();
+
+        let text = metrics.encode();
+        assert!(text.contains("peer_sync_failures_total{peer=\"http://localhost:8001\"} 1"));
+    }
+}
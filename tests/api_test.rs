@@ -1,9 +1,11 @@
 mod common;
 
+use isahc::ReadResponseExt;
 use serial_test::serial;
 
 use crate::common::{
-    Api, Block, BlockHash, ServerBuilder, Transaction, ALICE, BLOCK_SUBSIDY, BOB, MINER_ADDRESS,
+    create_coinbase, get_next_timestamp, miner_keypair, Api, Block, BlockHash, ServerBuilder,
+    TransactionProof, ALICE, BLOCK_SUBSIDY, BOB,
 };
 
 #[test]
@@ -26,21 +28,137 @@ fn test_should_get_a_valid_genesis_block() {
     assert!(genesis_block.transactions.is_empty());
 }
 
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_get_blocks_by_range_index_and_hash() {
+    let node = ServerBuilder::new().start();
+    for _ in 0..5 {
+        node.add_valid_block();
+    }
+    let blocks = node.get_blocks();
+    assert_eq!(blocks.len(), 6);
+
+    // a range of blocks, which may be cut at the end of the chain
+    assert_eq!(node.get_blocks_in_range(2, 3), blocks[2..5].to_vec());
+    assert_eq!(node.get_blocks_in_range(4, 10), blocks[4..].to_vec());
+    assert!(node.get_blocks_in_range(10, 10).is_empty());
+
+    // a single block by index, by hash or the latest one
+    let parse_block = |mut res: isahc::Response<isahc::Body>| -> Block {
+        assert_eq!(res.status().as_u16(), 200);
+        serde_json::from_str(&res.text().unwrap()).unwrap()
+    };
+    assert_eq!(parse_block(node.get_block("3")), blocks[3]);
+    let hash = serde_json::to_value(blocks[2].hash).unwrap();
+    let path = format!("hash/{}", hash.as_str().unwrap());
+    assert_eq!(parse_block(node.get_block(&path)), blocks[2]);
+    assert_eq!(parse_block(node.get_block("latest")), blocks[5]);
+
+    // unknown blocks are not found
+    assert_eq!(node.get_block("6").status().as_u16(), 404);
+    let path = format!(
+        "hash/{}",
+        serde_json::to_value(BlockHash::default())
+            .unwrap()
+            .as_str()
+            .unwrap()
+    );
+    assert_eq!(node.get_block(&path).status().as_u16(), 404);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_report_node_status() {
+    let mut node = ServerBuilder::new().start();
+    node.add_valid_block();
+
+    let status = node.get_status();
+    assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(status["chain"]["height"], 1);
+    assert_eq!(
+        status["chain"]["tip_hash"],
+        serde_json::to_value(node.get_last_block().hash).unwrap()
+    );
+    assert_eq!(status["chain"]["difficulty"], 0);
+    assert_eq!(status["mempool"]["transactions"], 0);
+    assert!(status["peers"].as_array().unwrap().is_empty());
+    assert_eq!(status["miner"]["running"], true);
+    assert_eq!(status["miner"]["blocks_mined"], 0);
+
+    // a transaction waits in the pool until it's mined
+    let transaction = miner_keypair().create_transaction(BOB, 10, 1);
+    node.add_transaction(&transaction);
+    let status = node.get_status();
+    assert_eq!(status["mempool"]["transactions"], 1);
+    assert!(status["mempool"]["bytes"].as_u64().unwrap() > 0);
+
+    let transaction = miner_keypair().create_transaction(BOB, 10, 0);
+    node.add_transaction(&transaction);
+    node.wait_for_mining();
+    let status = node.get_status();
+    assert_eq!(status["chain"]["height"], 2);
+    assert_eq!(status["mempool"]["transactions"], 0);
+    assert_eq!(status["miner"]["blocks_mined"], 1);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_export_metrics() {
+    let mut node = ServerBuilder::new().start();
+    node.add_valid_block();
+
+    // a block on top of the last one, but with the same index
+    let last_block = node.get_last_block();
+    let mut invalid_block = last_block.clone();
+    invalid_block.previous_hash = last_block.hash;
+    let res = node.add_block(&invalid_block);
+    assert_eq!(res.status().as_u16(), 400);
+
+    // a block with an unknown previous block is kept as an orphan, but it's not added to the blockchain yet
+    let mut orphan_block = last_block.clone();
+    orphan_block.index += 2;
+    orphan_block.previous_hash = BlockHash::from(1);
+    let res = node.add_block(&orphan_block);
+    assert_eq!(res.status().as_u16(), 200);
+
+    // a transaction that waits in the pool, and another one that gets mined
+    node.add_transaction(&miner_keypair().create_transaction(BOB, 10, 2));
+    node.add_transaction(&miner_keypair().create_transaction(BOB, 10, 0));
+    node.wait_for_mining();
+
+    let metrics = node.get_metrics();
+    let expected_lines = [
+        "blockchain_blocks_added_total{source=\"api\"} 1",
+        "blockchain_blocks_added_total{source=\"mined\"} 1",
+        "blockchain_blocks_rejected_total{reason=\"InvalidIndex\"} 1",
+        "blockchain_height 2",
+        "mempool_transactions 1",
+    ];
+    for line in expected_lines {
+        assert!(metrics.contains(line), "missing {} in {}", line, metrics);
+    }
+    assert!(metrics.contains("miner_hashes_total"));
+    assert!(metrics.contains(
+        "api_request_duration_seconds_count{method=\"POST\",route=\"/blocks\",status=\"400\"} 1"
+    ));
+}
+
 #[test]
 #[serial]
 #[cfg(unix)]
 fn test_should_let_add_transactions() {
     let mut node = ServerBuilder::new().start();
-    let genesis_block = node.get_last_block();
+
+    // the sender must be the mining address,
+    // so we fund it with the coinbase reward of a new block
+    node.add_valid_block();
+    let funding_block = node.get_last_block();
 
     // create and add a new transaction to the pool
-    // the sender must the mining address,
-    // as it should have funds from the coinbase reward of the genesis block
-    let transaction = Transaction {
-        sender: MINER_ADDRESS.to_string(),
-        recipient: BOB.to_string(),
-        amount: 10 as u64,
-    };
+    let transaction = miner_keypair().create_transaction(BOB, 10, 0);
     let res = node.add_transaction(&transaction);
     assert_eq!(res.status().as_u16(), 200);
 
@@ -49,12 +167,12 @@ fn test_should_let_add_transactions() {
 
     // check that a new bock was added...
     let blocks = node.get_blocks();
-    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks.len(), 3);
     let mined_block = blocks.last().unwrap();
 
     // ...and is valid
-    assert_eq!(mined_block.index, 1);
-    assert_eq!(mined_block.previous_hash, genesis_block.hash);
+    assert_eq!(mined_block.index, 2);
+    assert_eq!(mined_block.previous_hash, funding_block.hash);
 
     // ...and contains the transaction that we added (plus the coinbase)
     assert_eq!(mined_block.transactions.len(), 2);
@@ -62,26 +180,177 @@ fn test_should_let_add_transactions() {
     assert_eq!(*mined_transaction, transaction);
 }
 
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_find_transactions_by_hash() {
+    let mut node = ServerBuilder::new().start();
+    node.add_valid_block();
+
+    // the hash of the new transaction is returned
+    let transaction = miner_keypair().create_transaction(BOB, 10, 0);
+    let mut res = node.add_transaction(&transaction);
+    assert_eq!(res.status().as_u16(), 200);
+    let body: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+    let hash: BlockHash = serde_json::from_value(body["hash"].clone()).unwrap();
+
+    // once mined, the transaction is found in the last block
+    node.wait_for_mining();
+    let mined_block = node.get_last_block();
+    let mut res = node.get_transaction(&hash);
+    assert_eq!(res.status().as_u16(), 200);
+    let details: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+    assert_eq!(details["status"], "confirmed");
+    assert_eq!(details["block_index"], mined_block.index);
+    assert_eq!(
+        details["block_hash"],
+        serde_json::to_value(mined_block.hash).unwrap()
+    );
+    assert_eq!(details["confirmations"], 1);
+    assert_eq!(
+        details["transaction"],
+        serde_json::to_value(&transaction).unwrap()
+    );
+
+    // unknown transactions are not found
+    let res = node.get_transaction(&BlockHash::default());
+    assert_eq!(res.status().as_u16(), 404);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_list_and_remove_pending_transactions() {
+    let node = ServerBuilder::new().start();
+    node.add_valid_block();
+
+    // a transaction with a future nonce stays in the pool
+    let transaction = miner_keypair().create_transaction_with_fee(BOB, 10, 3, 1);
+    let mut res = node.add_transaction(&transaction);
+    let body: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+    let hash: BlockHash = serde_json::from_value(body["hash"].clone()).unwrap();
+
+    let pending = node.get_pending_transactions();
+    let pending = pending.as_array().unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0]["hash"], body["hash"]);
+    assert_eq!(pending[0]["fee"], 3);
+    assert!(pending[0]["received_at"].as_i64().unwrap() > 0);
+    assert_eq!(
+        pending[0]["transaction"],
+        serde_json::to_value(&transaction).unwrap()
+    );
+
+    // only admins can remove pending transactions
+    let res = node.remove_pending_transaction(&hash, "invalid_token");
+    assert_eq!(res.status().as_u16(), 401);
+    assert_eq!(node.get_pending_transactions().as_array().unwrap().len(), 1);
+
+    let res = node.remove_pending_transaction(&hash, &node.config.admin_token);
+    assert_eq!(res.status().as_u16(), 200);
+    assert!(node
+        .get_pending_transactions()
+        .as_array()
+        .unwrap()
+        .is_empty());
+    assert_eq!(node.get_transaction(&hash).status().as_u16(), 404);
+
+    // it cannot be removed twice
+    let res = node.remove_pending_transaction(&hash, &node.config.admin_token);
+    assert_eq!(res.status().as_u16(), 404);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_get_accounts_and_their_transactions() {
+    let mut node = ServerBuilder::new().start();
+    node.add_valid_block();
+    let miner_address = miner_keypair().address();
+
+    let account = node.get_account(&miner_address);
+    assert_eq!(account["balance"], BLOCK_SUBSIDY);
+    assert_eq!(account["nonce"], 0);
+    assert_eq!(account["pending_balance"], BLOCK_SUBSIDY);
+
+    // a transaction with a future nonce stays in the pool, but it's already in the pending balances
+    let held_back = miner_keypair().create_transaction(BOB, 20, 1);
+    assert_eq!(node.add_transaction(&held_back).status().as_u16(), 200);
+    let account = node.get_account(&miner_address);
+    assert_eq!(account["balance"], BLOCK_SUBSIDY);
+    assert_eq!(account["pending_balance"], BLOCK_SUBSIDY - 20);
+    let account = node.get_account(BOB);
+    assert_eq!(account["balance"], 0);
+    assert_eq!(account["pending_balance"], 20);
+
+    // once the missing nonce arrives, both transactions are mined
+    let transaction = miner_keypair().create_transaction(BOB, 10, 0);
+    assert_eq!(node.add_transaction(&transaction).status().as_u16(), 200);
+    node.wait_for_mining();
+    let account = node.get_account(BOB);
+    assert_eq!(account["balance"], 30);
+    assert_eq!(account["nonce"], 0);
+    assert_eq!(account["pending_balance"], 30);
+
+    // the history is returned from the newest to the oldest transaction
+    let page = node.get_address_transactions(BOB, 0, 10);
+    assert_eq!(page["total"], 2);
+    let transactions = page["transactions"].as_array().unwrap();
+    assert_eq!(transactions[0]["transaction"]["nonce"], 1);
+    assert_eq!(transactions[1]["transaction"]["nonce"], 0);
+    assert_eq!(transactions[1]["status"], "confirmed");
+
+    // and it can be paginated, the miner also received the coinbases of both blocks
+    let page = node.get_address_transactions(&miner_address, 1, 2);
+    assert_eq!(page["total"], 4);
+    let transactions = page["transactions"].as_array().unwrap();
+    assert_eq!(transactions.len(), 2);
+    assert_eq!(
+        transactions[0]["transaction"],
+        serde_json::to_value(&transaction).unwrap()
+    );
+    assert_eq!(transactions[1]["block_index"], 2);
+    assert_eq!(transactions[1]["transaction"]["amount"], BLOCK_SUBSIDY);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_pay_transaction_fees_to_miner() {
+    let mut node = ServerBuilder::new().start();
+    node.add_valid_block();
+
+    let transaction = miner_keypair().create_transaction_with_fee(BOB, 10, 5, 0);
+    let res = node.add_transaction(&transaction);
+    assert_eq!(res.status().as_u16(), 200);
+
+    node.wait_for_mining();
+
+    // the coinbase of the mined block includes the fee of the transaction
+    let mined_block = node.get_last_block();
+    let coinbase = mined_block.transactions.first().unwrap();
+    assert_eq!(coinbase.amount, BLOCK_SUBSIDY + transaction.fee);
+}
+
 #[test]
 #[serial]
 #[cfg(unix)]
 fn test_should_let_add_valid_block() {
     let node = ServerBuilder::new().start();
     let genesis_block = node.get_last_block();
-    let coinbase = Transaction {
-        sender: ALICE.to_string(),
-        recipient: ALICE.to_string(),
-        amount: BLOCK_SUBSIDY,
-    };
+    let coinbase = create_coinbase(1, ALICE, BLOCK_SUBSIDY);
 
     let valid_block = Block {
         // there is the genesis block already, so the next index is 1
         index: 1,
-        timestamp: 0,
+        timestamp: get_next_timestamp(&genesis_block),
+        difficulty: 0,
         nonce: 0,
         // the previous hash is checked
         previous_hash: genesis_block.hash,
-        // the api automatically recalculates the hash...
+        // the api automatically recalculates the merkle root and the hash...
+        merkle_root: BlockHash::default(),
+        // ...so no need to add a valid one here
         // ...so no need to add a valid one here
         hash: BlockHash::default(),
         // must include the coinbase transaction
@@ -101,11 +370,99 @@ fn test_should_not_let_add_invalid_block() {
     let invalid_block = Block {
         index: 0, // not valid index, the genesis block already has index 0
         timestamp: 0,
+        difficulty: 0,
         nonce: 0,
         previous_hash: BlockHash::default(), // also not valid
+        merkle_root: BlockHash::default(),
         hash: BlockHash::default(),
         transactions: [].to_vec(),
     };
     let res = node.add_block(&invalid_block);
     assert_eq!(res.status().as_u16(), 400);
 }
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_keep_blocks_after_restart() {
+    let mut node = ServerBuilder::new().start();
+    node.add_valid_block();
+    let blocks = node.get_blocks();
+
+    node.restart();
+
+    // the blocks are loaded from disk...
+    assert_eq!(node.get_blocks(), blocks);
+
+    // ...and so are the funds of the miner, so it can still send them
+    let transaction = miner_keypair().create_transaction(BOB, 10, 0);
+    let res = node.add_transaction(&transaction);
+    assert_eq!(res.status().as_u16(), 200);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_prove_transactions_in_blocks() {
+    let node = ServerBuilder::new().start();
+    node.add_valid_block();
+    let block = node.get_last_block();
+
+    // get the proof that the coinbase is included in the block
+    let mut res = node.get_transaction_proof(&block.hash, 0);
+    assert_eq!(res.status().as_u16(), 200);
+    let proof: TransactionProof = serde_json::from_str(&res.text().unwrap()).unwrap();
+    assert_eq!(proof.block_hash, block.hash);
+    assert_eq!(proof.merkle_root, block.merkle_root);
+
+    // the proof is valid...
+    assert!(node.verify_transaction_proof(&proof));
+
+    // ...but not for a different transaction
+    let mut invalid_proof = proof.clone();
+    invalid_proof.transaction_hash = BlockHash::default();
+    assert!(!node.verify_transaction_proof(&invalid_proof));
+
+    // ...nor for a tree with more leaves than transactions in the block
+    let mut invalid_proof = proof.clone();
+    invalid_proof.proof.leaf_count = 2;
+    assert!(!node.verify_transaction_proof(&invalid_proof));
+
+    // there is no proof for transactions that are not in the block
+    let res = node.get_transaction_proof(&block.hash, 1);
+    assert_eq!(res.status().as_u16(), 404);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_not_let_add_unsigned_transactions() {
+    let node = ServerBuilder::new().start();
+
+    // try to spend the funds of the miner without its keys
+    let mut transaction = miner_keypair().create_transaction(BOB, 10, 0);
+    transaction.signature = "0".repeat(128);
+
+    let res = node.add_transaction(&transaction);
+    assert_eq!(res.status().as_u16(), 400);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_not_let_add_transactions_without_funds() {
+    let node = ServerBuilder::new().start();
+
+    // the miner address has not received any funds yet
+    let transaction = miner_keypair().create_transaction(BOB, 10, 0);
+    let mut res = node.add_transaction(&transaction);
+    assert_eq!(res.status().as_u16(), 400);
+    assert_eq!(res.text().unwrap(), "Sender account does not exist");
+
+    // after receiving a reward, it still cannot spend more than its balance
+    node.add_valid_block();
+    let transaction = miner_keypair().create_transaction(BOB, BLOCK_SUBSIDY + 1, 0);
+    let mut res = node.add_transaction(&transaction);
+    assert_eq!(res.status().as_u16(), 400);
+    assert_eq!(res.text().unwrap(), "Insufficient funds");
+}
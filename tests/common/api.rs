@@ -1,3 +1,4 @@
+use chrono::Utc;
 use ethereum_types::U256;
 use isahc::{Body, ReadResponseExt, Request, Response};
 use serde::{Deserialize, Serialize};
@@ -10,8 +11,10 @@ pub type BlockHash = U256;
 pub struct Block {
     pub index: u64,
     pub timestamp: i64,
+    pub difficulty: u32,
     pub nonce: u64,
     pub previous_hash: BlockHash,
+    pub merkle_root: BlockHash,
     pub hash: BlockHash,
     pub transactions: Vec<Transaction>,
 }
@@ -21,6 +24,31 @@ pub struct Transaction {
     pub sender: String,
     pub recipient: String,
     pub amount: u64,
+    pub fee: u64,
+    pub nonce: u64,
+    pub public_key: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MerkleSibling {
+    pub hash: BlockHash,
+    pub position: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub leaf_count: usize,
+    pub siblings: Vec<MerkleSibling>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransactionProof {
+    pub block_hash: BlockHash,
+    pub merkle_root: BlockHash,
+    pub transaction_hash: BlockHash,
+    pub proof: MerkleProof,
 }
 
 #[allow(dead_code)]
@@ -32,12 +60,53 @@ pub const BOB: &str = "51df097c03c0a6e64e54a6fce90cb6968adebd85955917ed438e3d3c0
 #[allow(dead_code)]
 pub const BLOCK_SUBSIDY: u64 = 100;
 
+// Coinbase transactions are not signed, so the keys are filled with zeroes
+#[allow(dead_code)]
+pub fn create_coinbase(index: u64, recipient: &str, amount: u64) -> Transaction {
+    Transaction {
+        sender: ALICE.to_string(),
+        recipient: recipient.to_string(),
+        amount,
+        fee: 0,
+        // the nodes require the index of the block in the upper bits of the coinbase nonce
+        nonce: index << 32,
+        public_key: "0".repeat(64),
+        signature: "0".repeat(128),
+    }
+}
+
+// The nodes only accept blocks more recent than the previous ones, and not too far in the future
+#[allow(dead_code)]
+pub fn get_next_timestamp(previous_block: &Block) -> i64 {
+    Utc::now()
+        .timestamp_millis()
+        .max(previous_block.timestamp + 1)
+}
+
+#[allow(dead_code)]
 pub trait Api {
     fn get_blocks(&self) -> Vec<Block>;
+    fn get_blocks_in_range(&self, from: u64, limit: usize) -> Vec<Block>;
+    fn get_block(&self, path: &str) -> Response<Body>;
     fn get_last_block(&self) -> Block;
     fn add_block(&self, block: &Block) -> Response<Body>;
     fn add_valid_block(&self) -> Response<Body>;
     fn add_transaction(&self, transaction: &Transaction) -> Response<Body>;
+    fn get_transaction(&self, hash: &BlockHash) -> Response<Body>;
+    fn get_transaction_proof(&self, block_hash: &BlockHash, index: usize) -> Response<Body>;
+    fn get_status(&self) -> serde_json::Value;
+    fn get_metrics(&self) -> String;
+    fn get_peer_addresses(&self) -> Vec<String>;
+    fn get_pending_transactions(&self) -> serde_json::Value;
+    fn remove_pending_transaction(&self, hash: &BlockHash, admin_token: &str) -> Response<Body>;
+    fn get_account(&self, address: &str) -> serde_json::Value;
+    fn get_address_transactions(
+        &self,
+        address: &str,
+        offset: usize,
+        limit: usize,
+    ) -> serde_json::Value;
+    fn verify_transaction_proof(&self, proof: &TransactionProof) -> bool;
 }
 
 impl Api for Server {
@@ -56,25 +125,47 @@ impl Api for Server {
         blocks
     }
 
+    fn get_blocks_in_range(&self, from: u64, limit: usize) -> Vec<Block> {
+        let uri = format!(
+            "{}/blocks?from={}&limit={}",
+            get_base_url(self),
+            from,
+            limit
+        );
+
+        serde_json::from_value(get_json(uri)).unwrap()
+    }
+
+    // The path after "/blocks/", to get a block by its index, its hash or the latest one
+    fn get_block(&self, path: &str) -> Response<Body> {
+        let uri = format!("{}/blocks/{}", get_base_url(self), path);
+
+        isahc::get(uri).unwrap()
+    }
+
     fn get_last_block(&self) -> Block {
         self.get_blocks().last().unwrap().to_owned()
     }
 
     fn add_valid_block(&self) -> Response<Body> {
         let last_block = self.get_last_block();
-        let coinbase = Transaction {
-            sender: ALICE.to_string(),
-            recipient: ALICE.to_string(),
-            amount: BLOCK_SUBSIDY,
-        };
+        // the reward goes to the miner address, so it can send funds in the tests
+        let coinbase = create_coinbase(
+            last_block.index + 1,
+            &self.config.miner_address,
+            BLOCK_SUBSIDY,
+        );
         let valid_block = Block {
             index: last_block.index + 1,
-            timestamp: 0,
+            timestamp: get_next_timestamp(&last_block),
+            // the nodes start with the configured difficulty
+            difficulty: self.config.difficulty,
             nonce: 0,
             // the previous hash is checked
             previous_hash: last_block.hash,
-            // the api automatically recalculates the hash...
-            // ...so no need to add a valid one here
+            // the api automatically recalculates the merkle root and the hash...
+            // ...so no need to add valid ones here
+            merkle_root: BlockHash::default(),
             hash: BlockHash::default(),
             transactions: vec![coinbase],
         };
@@ -96,12 +187,123 @@ impl Api for Server {
 
         post_request(uri, body)
     }
+
+    fn get_transaction(&self, hash: &BlockHash) -> Response<Body> {
+        let uri = format!("{}/transactions/{}", get_base_url(self), format_hash(hash));
+
+        isahc::get(uri).unwrap()
+    }
+
+    fn get_transaction_proof(&self, block_hash: &BlockHash, index: usize) -> Response<Body> {
+        let uri = format!(
+            "{}/blocks/{}/transactions/{}/proof",
+            get_base_url(self),
+            format_hash(block_hash),
+            index
+        );
+
+        isahc::get(uri).unwrap()
+    }
+
+    fn get_status(&self) -> serde_json::Value {
+        let uri = format!("{}/status", get_base_url(self));
+
+        get_json(uri)
+    }
+
+    fn get_metrics(&self) -> String {
+        let uri = format!("{}/metrics", get_base_url(self));
+        let mut response = isahc::get(uri).unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+
+        response.text().unwrap()
+    }
+
+    fn get_peer_addresses(&self) -> Vec<String> {
+        let uri = format!("{}/peers", get_base_url(self));
+        let peers = get_json(uri);
+
+        peers
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|peer| peer["address"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn get_pending_transactions(&self) -> serde_json::Value {
+        let uri = format!("{}/transactions/pending", get_base_url(self));
+
+        get_json(uri)
+    }
+
+    fn remove_pending_transaction(&self, hash: &BlockHash, admin_token: &str) -> Response<Body> {
+        let uri = format!(
+            "{}/admin/transactions/{}",
+            get_base_url(self),
+            format_hash(hash)
+        );
+        let request = Request::delete(uri)
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .body(())
+            .unwrap();
+
+        isahc::send(request).unwrap()
+    }
+
+    fn get_account(&self, address: &str) -> serde_json::Value {
+        let uri = format!("{}/accounts/{}", get_base_url(self), address);
+
+        get_json(uri)
+    }
+
+    fn get_address_transactions(
+        &self,
+        address: &str,
+        offset: usize,
+        limit: usize,
+    ) -> serde_json::Value {
+        let uri = format!(
+            "{}/accounts/{}/transactions?offset={}&limit={}",
+            get_base_url(self),
+            address,
+            offset,
+            limit
+        );
+
+        get_json(uri)
+    }
+
+    fn verify_transaction_proof(&self, proof: &TransactionProof) -> bool {
+        let uri = format!("{}/proofs/verify", get_base_url(self));
+        let body = serde_json::to_string(&proof).unwrap();
+        let mut response = post_request(uri, body);
+        assert_eq!(response.status().as_u16(), 200);
+
+        let verification: serde_json::Value =
+            serde_json::from_str(&response.text().unwrap()).unwrap();
+        verification["valid"].as_bool().unwrap()
+    }
+}
+
+// The hashes in URLs are represented in the same way as in JSON
+fn format_hash(hash: &BlockHash) -> String {
+    let value = serde_json::to_value(hash).unwrap();
+    value.as_str().unwrap().to_string()
 }
 
 fn get_base_url(server: &Server) -> String {
     format!("http://localhost:{}", server.config.port)
 }
 
+// Sends a GET request that must be successful, returning the JSON of the response
+fn get_json(uri: String) -> serde_json::Value {
+    let mut response = isahc::get(uri).unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    serde_json::from_str(&response.text().unwrap()).unwrap()
+}
+
 fn post_request(uri: String, body: String) -> Response<Body> {
     let request = Request::post(uri)
         .header("Content-Type", "application/json")
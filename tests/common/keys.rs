@@ -0,0 +1,83 @@
+use crypto::{digest::Digest, ed25519, sha2::Sha256};
+
+use super::api::Transaction;
+
+// Seed of the keys owning the miner address, so tests can spend the mining rewards
+const MINER_SEED: [u8; 32] = [42; 32];
+
+// Holds the keys of an account, to sign transactions like a wallet would do
+pub struct KeyPair {
+    secret_key: [u8; 64],
+    public_key: [u8; 32],
+}
+
+#[allow(dead_code)]
+impl KeyPair {
+    pub fn from_seed(seed: &[u8; 32]) -> KeyPair {
+        let (secret_key, public_key) = ed25519::keypair(seed);
+
+        KeyPair {
+            secret_key,
+            public_key,
+        }
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.public_key)
+    }
+
+    // The address is the SHA-256 hash of the public key
+    pub fn address(&self) -> String {
+        let mut byte_hash = [0; 32];
+        let mut hasher = Sha256::new();
+        hasher.input(&self.public_key);
+        hasher.result(&mut byte_hash);
+
+        hex::encode(byte_hash)
+    }
+
+    // Creates a transfer from this account without fee, signed with its secret key
+    pub fn create_transaction(&self, recipient: &str, amount: u64, nonce: u64) -> Transaction {
+        self.create_transaction_with_fee(recipient, amount, 0, nonce)
+    }
+
+    // Creates a transfer from this account, signed with its secret key
+    pub fn create_transaction_with_fee(
+        &self,
+        recipient: &str,
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+    ) -> Transaction {
+        let mut transaction = Transaction {
+            sender: self.address(),
+            recipient: recipient.to_string(),
+            amount,
+            fee,
+            nonce,
+            public_key: self.public_key(),
+            signature: String::new(),
+        };
+        let signature = ed25519::signature(&signing_data(&transaction), &self.secret_key);
+        transaction.signature = hex::encode(signature);
+
+        transaction
+    }
+}
+
+pub fn miner_keypair() -> KeyPair {
+    KeyPair::from_seed(&MINER_SEED)
+}
+
+// Mirrors the canonical binary encoding that the node uses to verify signatures
+fn signing_data(transaction: &Transaction) -> Vec<u8> {
+    // version of the encoding
+    let mut data = vec![1];
+    data.extend(hex::decode(&transaction.sender).unwrap());
+    data.extend(hex::decode(&transaction.recipient).unwrap());
+    data.extend_from_slice(&transaction.amount.to_be_bytes());
+    data.extend_from_slice(&transaction.fee.to_be_bytes());
+    data.extend_from_slice(&transaction.nonce.to_be_bytes());
+
+    data
+}
@@ -1,5 +1,8 @@
 mod api;
+mod keys;
 mod server;
 
 pub use api::*;
+#[allow(unused_imports)]
+pub use keys::*;
 pub use server::*;
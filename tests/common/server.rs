@@ -1,5 +1,6 @@
 use std::{
     convert::TryInto,
+    env, fs,
     io::{BufRead, BufReader},
     process::{Child, Command, Stdio},
     sync::{Arc, Mutex},
@@ -13,17 +14,22 @@ use nix::{
     unistd::Pid,
 };
 
-pub const MINER_ADDRESS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+use super::keys::miner_keypair;
 
+#[allow(dead_code)]
 pub struct Config {
     pub port: u16,
+    pub network_id: String,
     pub peers: Vec<String>,
     pub peer_sync_ms: u64,
     pub max_blocks: u64,
     pub max_nonce: u64,
     pub difficulty: u32,
+    pub difficulty_adjustment_interval: u64,
     pub tx_waiting_ms: u64,
     pub miner_address: String,
+    pub data_dir: String,
+    pub admin_token: String,
 }
 
 pub struct ServerBuilder {
@@ -36,16 +42,22 @@ impl ServerBuilder {
         // set the default values
         let config = Config {
             port: 8000,
+            network_id: "test".to_string(),
             // not to high to avoid waiting too much, not too shot to spam it
             peer_sync_ms: 10,
             // no difficulty to minimize the mining time
             difficulty: 0,
+            // the blocks added in the tests come much faster than the target time, so the difficulty must not change
+            difficulty_adjustment_interval: 0,
             // not to high to avoid waiting, not too shot to spam it
             tx_waiting_ms: 10,
             peers: Vec::<String>::new(),
             max_blocks: 0, // unlimited blocks
             max_nonce: 0,  // unlimited nonce
-            miner_address: MINER_ADDRESS.to_string(),
+            miner_address: miner_keypair().address(),
+            // each server gets its own directory when started, as it depends on the port
+            data_dir: String::new(),
+            admin_token: "test_admin_token".to_string(),
         };
 
         ServerBuilder { config }
@@ -56,18 +68,39 @@ impl ServerBuilder {
         self
     }
 
+    pub fn difficulty_adjustment_interval(mut self, interval: u64) -> ServerBuilder {
+        self.config.difficulty_adjustment_interval = interval;
+        self
+    }
+
     pub fn port(mut self, port: u16) -> ServerBuilder {
         self.config.port = port;
         self
     }
 
+    pub fn network_id(mut self, network_id: &str) -> ServerBuilder {
+        self.config.network_id = network_id.to_string();
+        self
+    }
+
+    pub fn peer_sync_ms(mut self, peer_sync_ms: u64) -> ServerBuilder {
+        self.config.peer_sync_ms = peer_sync_ms;
+        self
+    }
+
     pub fn peer(mut self, port: u64) -> ServerBuilder {
         let address = format!("http://localhost:{}", port);
         self.config.peers.push(address);
         self
     }
 
-    pub fn start(self) -> Server {
+    pub fn start(mut self) -> Server {
+        let data_dir = env::temp_dir().join(format!("rust_blockchain_test_{}", self.config.port));
+        self.config.data_dir = data_dir.to_str().unwrap().to_string();
+
+        // the server must not load blocks from previous tests
+        let _ = fs::remove_dir_all(&self.config.data_dir);
+
         Server::new(self.config)
     }
 }
@@ -99,15 +132,35 @@ impl Server {
         server
     }
 
+    // stop the server and start it again with the same configuration, keeping its data directory
+    pub fn restart(&mut self) {
+        self.stop();
+
+        self.process = Server::start_process(&self.config);
+        self.output = Server::start_stdout_reading(&mut self.process);
+        self.wait_for_log_message("actix-web-service");
+    }
+
     // start the blockchain application in the background
     fn start_process(config: &Config) -> Child {
         Command::new(cargo_bin("rust_blockchain"))
             .env("PORT", config.port.to_string())
+            .env(
+                "PUBLIC_ADDRESS",
+                format!("http://localhost:{}", config.port),
+            )
+            .env("NETWORK_ID", config.network_id.clone())
             .env("PEERS", config.peers.join(","))
             .env("DIFFICULTY", config.difficulty.to_string())
+            .env(
+                "DIFFICULTY_ADJUSTMENT_INTERVAL",
+                config.difficulty_adjustment_interval.to_string(),
+            )
             .env("TRANSACTION_WAITING_MS", config.tx_waiting_ms.to_string())
             .env("PEER_SYNC_MS", config.peer_sync_ms.to_string())
             .env("MINER_ADDRESS", config.miner_address.clone())
+            .env("DATA_DIR", config.data_dir.clone())
+            .env("ADMIN_TOKEN", config.admin_token.clone())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
@@ -148,11 +201,41 @@ impl Server {
         self.wait_for_log_message("Added new peer block");
     }
 
+    // block the execution until we sync the block with the indicated index
+    pub fn wait_for_peer_block(&mut self, index: u64) {
+        self.wait_for_log_message(&format!("Added new peer block {} ", index));
+    }
+
+    // block the execution until we learn a new peer from another one
+    pub fn wait_for_new_peer(&mut self) {
+        self.wait_for_log_message("Learned new peer");
+    }
+
+    // block the execution until a peer completes a handshake for the first time
+    pub fn wait_for_peer_connection(&mut self) {
+        self.wait_for_log_message("Connected to peer");
+    }
+
+    // block the execution until a peer is refused for following different rules
+    pub fn wait_for_incompatible_peer(&mut self) {
+        self.wait_for_log_message("Incompatible peer");
+    }
+
+    // block the execution until a branch with more work replaces the last blocks of the main chain
+    pub fn wait_for_chain_reorganization(&mut self) {
+        self.wait_for_log_message("chain reorganization");
+    }
+
     // block the execution until we receive a new block via api
     pub fn wait_to_receive_block_in_api(&mut self) {
         self.wait_for_log_message("Received new block");
     }
 
+    // block the execution until we receive a new transaction via api
+    pub fn wait_to_receive_transaction_in_api(&mut self) {
+        self.wait_for_log_message("Received new transaction");
+    }
+
     // block the execution until a message is contained in the process output
     // or until a max time has passed
     fn wait_for_log_message(&mut self, message: &str) {
@@ -227,5 +310,6 @@ impl Server {
 impl Drop for Server {
     fn drop(&mut self) {
         self.stop();
+        let _ = fs::remove_dir_all(&self.config.data_dir);
     }
 }
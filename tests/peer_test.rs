@@ -1,6 +1,10 @@
 mod common;
 
-use crate::common::{Api, ServerBuilder};
+use crate::common::{
+    create_coinbase, get_next_timestamp, miner_keypair, Api, Block, BlockHash, ServerBuilder,
+    BLOCK_SUBSIDY, BOB,
+};
+use isahc::ReadResponseExt;
 use serial_test::serial;
 
 #[test]
@@ -31,6 +35,22 @@ fn test_should_receive_new_valid_blocks() {
     assert_eq!(last_follower_block, last_leader_block);
 }
 
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_receive_blocks_in_multiple_pages() {
+    // the leader has more blocks than the follower can request at once
+    let leader_node = ServerBuilder::new().port(8000).start();
+    for _ in 0..150 {
+        leader_node.add_valid_block();
+    }
+
+    // the follower eventually requests all of them
+    let mut follower_node = ServerBuilder::new().port(8001).peer(8000).start();
+    follower_node.wait_for_peer_block(150);
+    assert_eq!(follower_node.get_blocks(), leader_node.get_blocks());
+}
+
 #[test]
 #[serial]
 #[cfg(unix)]
@@ -49,11 +69,56 @@ fn test_should_not_receive_new_invalid_blocks() {
     // we create a new valid block in the leader node
     leader_node.add_valid_block();
 
-    // the follower node should eventually ask and receive the new block
-    follower_node.wait_for_peer_sync();
+    // the follower node refuses the leader in the handshake, as the difficulty does not match
+    follower_node.wait_for_incompatible_peer();
 
-    // but the block should not be added as the difficulty will not match
+    // so the block is never added
     assert_eq!(follower_node.get_blocks().len(), 1);
+    assert_eq!(follower_node.get_status()["peers"][0]["compatible"], false);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_refuse_peers_of_other_networks() {
+    let leader_node = ServerBuilder::new().port(8000).start();
+    let mut follower_node = ServerBuilder::new()
+        .network_id("other")
+        .port(8001)
+        .peer(8000)
+        .start();
+
+    leader_node.add_valid_block();
+
+    // the follower does not sync with the leader, as they are in different networks
+    follower_node.wait_for_incompatible_peer();
+    assert_eq!(follower_node.get_blocks().len(), 1);
+    let peers = follower_node.get_status()["peers"].clone();
+    assert_eq!(peers[0]["address"], "http://localhost:8000");
+    assert_eq!(peers[0]["compatible"], false);
+
+    // and it does not announce itself to the leader either
+    assert!(leader_node.get_peer_addresses().is_empty());
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_refuse_peers_that_adjust_the_difficulty_differently() {
+    let leader_node = ServerBuilder::new().port(8000).start();
+    let mut follower_node = ServerBuilder::new()
+        .difficulty_adjustment_interval(10)
+        .port(8001)
+        .peer(8000)
+        .start();
+
+    leader_node.add_valid_block();
+
+    // the blocks of the leader could require a different difficulty for the follower, so they do not sync
+    follower_node.wait_for_incompatible_peer();
+    assert_eq!(follower_node.get_blocks().len(), 1);
+    let peers = follower_node.get_status()["peers"].clone();
+    assert_eq!(peers[0]["compatible"], false);
 }
 
 #[test]
@@ -79,6 +144,22 @@ fn test_should_ignore_unavailable_peers() {
 
     // even if one of the peers does not exist, it ignores the error and adds blocks from available peers
     assert_eq!(follower_node.get_blocks().len(), 2);
+
+    // the result of the last sync with each peer is reported
+    let peers = follower_node.get_status()["peers"].clone();
+    assert_eq!(peers[0]["address"], "http://localhost:9000");
+    assert_eq!(peers[0]["last_sync_succeeded"], false);
+    assert_eq!(peers[1]["address"], "http://localhost:8000");
+    assert_eq!(peers[1]["last_sync_succeeded"], true);
+    assert!(peers[1]["last_sync_at"].as_i64().unwrap() > 0);
+
+    // and the failures are counted for each peer
+    let metrics = follower_node.get_metrics();
+    assert!(metrics.contains("peer_sync_failures_total{peer=\"http://localhost:9000\"}"));
+    assert!(!metrics.contains("peer_sync_failures_total{peer=\"http://localhost:8000\"}"));
+    // the block comes from the leader, no matter if the follower pulled it or the leader relayed it
+    assert!(metrics.contains("blockchain_blocks_added_total{source=\"peer\"} 1"));
+    assert!(!metrics.contains("blockchain_blocks_added_total{source=\"api\"}"));
 }
 
 #[test]
@@ -108,3 +189,124 @@ fn test_should_send_new_blocks() {
     let last_follower_block = leader_node.get_last_block();
     assert_eq!(last_follower_block, last_leader_block);
 }
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_relay_new_blocks_through_peers() {
+    // the nodes form a chain, and they take too long to request blocks from their peers
+    // so a new block can only reach the last node if each node relays it to the next one
+    // blocks are only relayed to peers after a handshake, so each node starts after its peer
+    let mut last_node = ServerBuilder::new().port(8002).peer_sync_ms(60_000).start();
+    let mut second_node = ServerBuilder::new()
+        .port(8001)
+        .peer(8002)
+        .peer_sync_ms(60_000)
+        .start();
+    second_node.wait_for_peer_connection();
+    let mut first_node = ServerBuilder::new()
+        .port(8000)
+        .peer(8001)
+        .peer_sync_ms(60_000)
+        .start();
+    first_node.wait_for_peer_connection();
+
+    first_node.add_valid_block();
+
+    second_node.wait_to_receive_block_in_api();
+    last_node.wait_to_receive_block_in_api();
+    assert_eq!(second_node.get_blocks(), first_node.get_blocks());
+    assert_eq!(last_node.get_blocks(), first_node.get_blocks());
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_relay_new_transactions_to_peers() {
+    // transactions are only relayed to peers after a handshake, so the follower starts first
+    let mut follower_node = ServerBuilder::new().port(8001).peer_sync_ms(60_000).start();
+    let mut leader_node = ServerBuilder::new()
+        .port(8000)
+        .peer(8001)
+        .peer_sync_ms(60_000)
+        .start();
+    leader_node.wait_for_peer_connection();
+
+    // the funds of the transaction are relayed before the transaction itself
+    leader_node.add_valid_block();
+    follower_node.wait_to_receive_block_in_api();
+
+    // so the follower accepts the transaction, even if it was sent to the leader
+    let transaction = miner_keypair().create_transaction(BOB, 10, 0);
+    let mut res = leader_node.add_transaction(&transaction);
+    assert_eq!(res.status().as_u16(), 200);
+    let body: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+    let hash: BlockHash = serde_json::from_value(body["hash"].clone()).unwrap();
+
+    // it may be already mined by the follower, but it must be known anyway
+    follower_node.wait_to_receive_transaction_in_api();
+    let res = follower_node.get_transaction(&hash);
+    assert_eq!(res.status().as_u16(), 200);
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_discover_peers_from_a_seed_node() {
+    let seed_node = ServerBuilder::new().port(8000).start();
+
+    // the first node announces itself to the seed node
+    let first_node = ServerBuilder::new().port(8001).peer(8000).start();
+
+    // so the second node learns about the first one from the seed node, and they can talk to each other
+    let mut second_node = ServerBuilder::new().port(8002).peer(8000).start();
+    second_node.wait_for_new_peer();
+    assert_eq!(
+        second_node.get_peer_addresses(),
+        vec!["http://localhost:8000", "http://localhost:8001"]
+    );
+    assert!(seed_node
+        .get_peer_addresses()
+        .contains(&"http://localhost:8002".to_string()));
+
+    // the learned peers are not forgotten after a restart, even if no other node knows about them anymore
+    drop(seed_node);
+    drop(first_node);
+    second_node.restart();
+    assert_eq!(
+        second_node.get_peer_addresses(),
+        vec!["http://localhost:8000", "http://localhost:8001"]
+    );
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn test_should_switch_to_peer_chain_with_more_work() {
+    let leader_node = ServerBuilder::new().port(8000).start();
+    let mut follower_node = ServerBuilder::new().port(8001).peer(8000).start();
+
+    // the follower adds its own block, different from the ones of the leader
+    let genesis_block = follower_node.get_last_block();
+    let coinbase = create_coinbase(1, &follower_node.config.miner_address, BLOCK_SUBSIDY);
+    let own_block = Block {
+        index: 1,
+        timestamp: get_next_timestamp(&genesis_block),
+        difficulty: 0,
+        nonce: 0,
+        previous_hash: genesis_block.hash,
+        merkle_root: BlockHash::default(),
+        hash: BlockHash::default(),
+        transactions: vec![coinbase],
+    };
+    let res = follower_node.add_block(&own_block);
+    assert_eq!(res.status().as_u16(), 200);
+
+    // but the leader creates a longer branch
+    leader_node.add_valid_block();
+    leader_node.add_valid_block();
+
+    // so the follower replaces its own block with the ones from the leader
+    follower_node.wait_for_chain_reorganization();
+    assert_eq!(follower_node.get_blocks(), leader_node.get_blocks());
+}